@@ -20,3 +20,39 @@ pub struct AccountBalance {
     pub tag: String,
     pub unlocked_balance: u64,
 }
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SubAccountBalance {
+    pub account_index: u32,
+    pub address_index: u32,
+    pub address: String,
+    pub balance: u64,
+    pub unlocked_balance: u64,
+    pub label: String,
+    pub num_unspent_outputs: u32,
+    pub blocks_to_unlock: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AccountTag {
+    pub tag: String,
+    pub label: String,
+    pub accounts: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Receivers {
+    pub transparent: Option<String>,
+    pub sapling: Option<String>,
+    pub orchard: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddressInfo {
+    pub address: String,
+    pub address_index: u32,
+    pub label: String,
+    pub diversifier_index: u64,
+    pub receivers: Receivers,
+    pub used: bool,
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use rocket::serde::{Deserialize, Serialize};
+use zcash_keys::{
+    address::{Address, UnifiedAddress},
+    encoding::AddressCodec,
+};
+use zcash_primitives::legacy::TransparentAddress;
+use zcash_protocol::consensus::{MainNetwork, NetworkType, Parameters, TestNetwork};
+use zip32::DiversifierIndex;
+
+use crate::{
+    account::Receivers,
+    keys::ViewingKey,
+    network::{Network, REGTEST},
+    scan::{make_decoders, Decode},
+};
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct AddressValidation {
+    pub valid: bool,
+    pub nettype: String,
+    pub r#type: String,
+    pub receivers: Receivers,
+    pub owned: bool,
+    pub diversifier_index: Option<u64>,
+}
+
+/// Parse an address of any type and check if its receivers belong
+/// to our viewing key, whether or not we issued it. A UA is ours only if all its
+/// receivers are the ones of our address at the same diversifier index.
+/// Transparent addresses cannot be checked without their address index and
+/// are never `owned` here, see `Db::get_address_index`.
+pub fn validate_address(network: &Network, vk: &ViewingKey, address: &str) -> AddressValidation {
+    let Some((decoded, network_type)) = decode_any_network(network, address) else {
+        return AddressValidation::default();
+    };
+    let nettype = match network_type {
+        NetworkType::Main => "mainnet",
+        NetworkType::Test => "testnet",
+        NetworkType::Regtest => "regtest",
+    };
+    let r#type = match decoded {
+        Address::Sapling(_) => "sapling",
+        Address::Transparent(_) => "transparent",
+        Address::Unified(_) => "unified",
+        Address::Tex(_) => "tex",
+    };
+    let mut validation = AddressValidation {
+        valid: network_type == network.network_type(),
+        nettype: nettype.to_string(),
+        r#type: r#type.to_string(),
+        ..AddressValidation::default()
+    };
+    // Addresses from another network cannot be ours
+    if !validation.valid {
+        return validation;
+    }
+
+    let (sapling, orchard, transparent) = match decoded {
+        Address::Sapling(pa) => (Some(pa), None, None),
+        Address::Transparent(ta) => (None, None, Some(ta)),
+        Address::Tex(pkh) => (None, None, Some(TransparentAddress::PublicKeyHash(pkh))),
+        Address::Unified(ua) => (
+            ua.sapling().copied(),
+            ua.orchard().copied(),
+            ua.transparent().copied(),
+        ),
+    };
+    validation.receivers = Receivers {
+        transparent: transparent.map(|ta| ta.encode(network)),
+        sapling: sapling.map(|pa| pa.encode(network)),
+        orchard: orchard.map(|address| {
+            let ua = UnifiedAddress::from_receivers(Some(address), None, None).unwrap();
+            ua.encode(network)
+        }),
+    };
+
+    let decoders = make_decoders(0, vk, &HashMap::new());
+    let mut indexes = vec![];
+    if let Some(pa) = sapling {
+        indexes.push(
+            decoders
+                .sapling
+                .as_ref()
+                .and_then(|sap_dec| sap_dec.decrypt_diversifier(&pa).ok().flatten()),
+        );
+    }
+    if let Some(address) = orchard {
+        indexes.push(
+            decoders
+                .orchard
+                .as_ref()
+                .and_then(|orc_dec| orc_dec.decrypt_diversifier(&address).ok().flatten()),
+        );
+    }
+    // A UA that pairs one of our receivers with someone else's would
+    // have the payer fund the other one
+    let di = match indexes.split_first() {
+        Some((first, rest)) => first.filter(|di| rest.iter().all(|other| *other == Some(*di))),
+        None => None,
+    };
+    let di = match (di, transparent) {
+        (Some(di), Some(ta)) => {
+            // The transparent receiver must be the one we derive at this index
+            let ours = vk
+                .find_address(di)
+                .ok()
+                .filter(|(_, found)| *found == DiversifierIndex::from(di))
+                .and_then(|(ua, _)| ua.transparent().copied());
+            (ours == Some(ta)).then_some(di)
+        }
+        (di, _) => di,
+    };
+    validation.diversifier_index = di;
+    validation.owned = di.is_some();
+
+    validation
+}
+
+/// Receivers of one of our addresses as (pool, receiver address), the way
+/// they are stored in the `receivers` table. Orchard receivers are
+/// encoded as Orchard only UA. Addresses from older versions may be
+/// plain Sapling addresses
+pub fn address_receivers(network: &Network, address: &str) -> Result<Vec<(u8, String)>> {
+    let receivers = match Address::decode(network, address) {
+        Some(Address::Sapling(pa)) => vec![(1, pa.encode(network))],
+        Some(Address::Unified(ua)) => {
+            let mut receivers = vec![];
+            if let Some(ta) = ua.transparent() {
+                receivers.push((0, ta.encode(network)));
+            }
+            if let Some(pa) = ua.sapling() {
+                receivers.push((1, pa.encode(network)));
+            }
+            if let Some(address) = ua.orchard() {
+                let ua = UnifiedAddress::from_receivers(Some(*address), None, None).unwrap();
+                receivers.push((2, ua.encode(network)));
+            }
+            receivers
+        }
+        _ => bail!("Invalid wallet address {address}"),
+    };
+    Ok(receivers)
+}
+
+fn decode_any_network(network: &Network, address: &str) -> Option<(Address, NetworkType)> {
+    Address::decode(network, address)
+        .map(|a| (a, network.network_type()))
+        .or_else(|| Address::decode(&MainNetwork, address).map(|a| (a, NetworkType::Main)))
+        .or_else(|| Address::decode(&TestNetwork, address).map(|a| (a, NetworkType::Test)))
+        .or_else(|| Address::decode(&REGTEST, address).map(|a| (a, NetworkType::Regtest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGTEST_FVK: &str = "uviewregtest10lkfv9ck80w7hc50x02fkzwl004glax8gtyg6n3edgy5ld34xvutln5zwlezpmtadv9v2jge0damef7egg8tk93xncq73k0fdzpfrecpzmres8ucz82m8h9ephp53vasten7xrf95h9egdhyg2fqu2qz3hgyy0k6tny6d28m5duuzk72ma0nfr2y5cxqwjscspsdm5qkaafc9edtpzapmfxgzcdkqr60atx32g6q8fxhhh9n0hueslvzy04xyx5353nmmxx2k7uxwdv6t9y626f0d03lgufgkct3gkyxp4u24xdz9l5jsa5ne8cw9s5cjqernqj7xqmwzuc7lad6c7ayqk2ry3e66qea5pmq32a9v4spfswmtsvklljmd0fc4pk8f32g7snzxyrlmnkguch3execr9kqx02a6dc2ryuzvrg8vrrfxjkve6tpyk4vfz9j2zkuws9g5e06wm744yzsye3w74qwjrn5t2rzqfn6zmr8fgkjea8c";
+
+    #[test]
+    fn test_validate_address() {
+        let vk = ViewingKey::decode(&Network::Regtest, REGTEST_FVK).unwrap();
+
+        let v = validate_address(&Network::Regtest, &vk, "uregtest1se78asch326c8czsa2wyzzfuytrvlezzjw42rest6nkqu3dzuvf4ua3lxjzf8gc5ygwca5sjdsqnpzcs087hdpgz4msfazfwfsjtr0lrln7dg0729rzp7y2acm2wrjyr5qjc8mj7x03dqh4a6frku9ue8gv3z54xgxev3dg895hepwej");
+        assert!(v.valid);
+        assert!(v.owned);
+        assert_eq!(v.nettype, "regtest");
+        assert_eq!(v.r#type, "unified");
+        assert!(v.receivers.sapling.is_some());
+        assert!(v.receivers.orchard.is_some());
+
+        let v = validate_address(&Network::Regtest, &vk, "zregtestsapling1qag0mpkwcratr9zweyk973dzukaln3svpl0v8fpydajq8aq8ghsq0ah3my0qc2admygg6xt4snh");
+        assert!(v.valid);
+        assert!(v.owned);
+        assert_eq!(v.r#type, "sapling");
+
+        let v = validate_address(&Network::Regtest, &vk, "not an address");
+        assert!(!v.valid);
+        assert!(!v.owned);
+    }
+
+    #[test]
+    fn test_validate_mixed_address() -> Result<()> {
+        let network = Network::Regtest;
+        let vk = ViewingKey::decode(&network, REGTEST_FVK)?;
+        let (ua, di) = vk.find_address(0)?;
+        let (other, _) = vk.find_address(u64::try_from(di)? + 1)?;
+
+        let v = validate_address(&network, &vk, &ua.encode(&network));
+        assert!(v.owned);
+        assert_eq!(v.diversifier_index, Some(u64::try_from(di)?));
+
+        // Our Sapling receiver with the Orchard receiver of another address
+        let mixed = UnifiedAddress::from_receivers(
+            other.orchard().copied(),
+            ua.sapling().copied(),
+            ua.transparent().copied(),
+        )
+        .unwrap();
+        let v = validate_address(&network, &vk, &mixed.encode(&network));
+        assert!(v.valid);
+        assert!(!v.owned);
+        assert_eq!(v.diversifier_index, None);
+
+        if let Some(ta) = other.transparent() {
+            let mixed = UnifiedAddress::from_receivers(
+                ua.orchard().copied(),
+                ua.sapling().copied(),
+                Some(*ta),
+            )
+            .unwrap();
+            let v = validate_address(&network, &vk, &mixed.encode(&network));
+            assert!(!v.owned);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use md5::{Digest, Md5};
+use rand::RngCore;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Debug;
+use serde::Deserialize;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+const REALM: &str = "zcash-walletd";
+const NONCE_VALIDITY_SECS: u64 = 300;
+// Digest requests remembered to refuse their replays
+const MAX_SEEN_NONCES: usize = 100_000;
+
+/// Whether the REST API needs API keys. Without any key,
+/// `"auth": "disabled"` must be set on purpose
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    #[default]
+    Required,
+    Disabled,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    ReadOnly,
+    Invoice,
+    Admin,
+}
+
+/// An API key. The key is used as a bearer token, or as the
+/// password of `user` with HTTP Digest authentication
+#[derive(Deserialize, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    pub scope: Scope,
+    pub user: Option<String>,
+    /// Wallets the key can access, all of them if empty
+    #[serde(default)]
+    pub wallets: Vec<u32>,
+}
+
+// Keep the keys out of the logs
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("scope", &self.scope)
+            .field("user", &self.user)
+            .field("wallets", &self.wallets)
+            .finish()
+    }
+}
+
+pub struct ApiAuth {
+    keys: Vec<ApiKey>,
+    disabled: bool,
+    digest: bool,
+    nonce_secret: [u8; 32],
+    /// (nonce, nc) of the Digest requests accepted, with the time of the nonce
+    seen_nonces: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl ApiAuth {
+    pub fn new(keys: &[ApiKey], digest: bool, mode: AuthMode) -> Result<Self> {
+        let mut nonce_secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut nonce_secret);
+        let disabled = mode == AuthMode::Disabled;
+        if disabled {
+            warn!("Authentication is disabled. Anyone can use the REST API");
+        } else if keys.is_empty() {
+            bail!("No API key configured. Add api_keys, or set \"auth\": \"disabled\"");
+        }
+        Ok(ApiAuth {
+            keys: keys.to_vec(),
+            disabled,
+            digest,
+            nonce_secret,
+            seen_nonces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// True if the REST API is open to everyone
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Returns the key of the request, if any
+    pub fn authorize(
+        &self,
+        method: &str,
+        uri: &str,
+        authorization: Option<&str>,
+    ) -> Option<&ApiKey> {
+        let authorization = authorization?;
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            let token = token.trim().as_bytes();
+            return self
+                .keys
+                .iter()
+                .find(|k| bool::from(token.ct_eq(k.key.as_bytes())));
+        }
+        if let Some(params) = authorization.strip_prefix("Digest ") {
+            if !self.digest {
+                return None;
+            }
+            return self.authorize_digest(method, uri, &parse_digest_params(params));
+        }
+        None
+    }
+
+    fn authorize_digest(
+        &self,
+        method: &str,
+        uri: &str,
+        params: &HashMap<String, String>,
+    ) -> Option<&ApiKey> {
+        let username = params.get("username")?;
+        let nonce = params.get("nonce")?;
+        let response = params.get("response")?;
+        if params.get("uri")? != uri || params.get("realm")? != REALM || !self.check_nonce(nonce) {
+            return None;
+        }
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.user.as_deref() == Some(username.as_str()))?;
+
+        let ha1 = md5_hex(&format!("{username}:{REALM}:{}", key.key));
+        let ha2 = md5_hex(&format!("{method}:{uri}"));
+        let (expected, nc) = match params.get("qop").map(|qop| qop.as_str()) {
+            Some("auth") => {
+                let nc = params.get("nc")?;
+                let cnonce = params.get("cnonce")?;
+                let expected = md5_hex(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+                (expected, nc.as_str())
+            }
+            // Without qop, a nonce can only be used once
+            None => (md5_hex(&format!("{ha1}:{nonce}:{ha2}")), ""),
+            Some(_) => return None,
+        };
+        if !bool::from(expected.as_bytes().ct_eq(response.as_bytes())) {
+            return None;
+        }
+        self.check_replay(nonce, nc).then_some(key)
+    }
+
+    /// Remember a valid Digest request, false if it was already seen
+    fn check_replay(&self, nonce: &str, nc: &str) -> bool {
+        let mut seen = self.seen_nonces.lock().unwrap();
+        if seen.len() >= MAX_SEEN_NONCES {
+            let now = now();
+            seen.retain(|_, timestamp| now.saturating_sub(*timestamp) <= NONCE_VALIDITY_SECS);
+            if seen.len() >= MAX_SEEN_NONCES {
+                warn!("Too many Digest requests, refusing them until their nonces expire");
+                return false;
+            }
+        }
+        let Some(timestamp) = nonce_timestamp(nonce) else {
+            return false;
+        };
+        seen.insert((nonce.to_string(), nc.to_string()), timestamp)
+            .is_none()
+    }
+
+    /// The `WWW-Authenticate` header sent back with 401 responses
+    pub fn challenge(&self) -> Header<'static> {
+        let value = if self.digest {
+            format!(
+                "Digest realm=\"{REALM}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"",
+                self.new_nonce(now())
+            )
+        } else {
+            format!("Bearer realm=\"{REALM}\"")
+        };
+        Header::new("WWW-Authenticate", value)
+    }
+
+    // Nonces are stateless: a timestamp signed with our secret
+    fn new_nonce(&self, timestamp: u64) -> String {
+        format!("{timestamp:x}.{}", self.nonce_signature(timestamp))
+    }
+
+    fn nonce_signature(&self, timestamp: u64) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.nonce_secret);
+        hasher.update(timestamp.to_le_bytes());
+        hex::encode(&hasher.finalize()[..16])
+    }
+
+    fn check_nonce(&self, nonce: &str) -> bool {
+        let Some((_, signature)) = nonce.split_once('.') else {
+            return false;
+        };
+        let Some(timestamp) = nonce_timestamp(nonce) else {
+            return false;
+        };
+        let fresh = now().saturating_sub(timestamp) <= NONCE_VALIDITY_SECS;
+        fresh
+            && bool::from(
+                signature
+                    .as_bytes()
+                    .ct_eq(self.nonce_signature(timestamp).as_bytes()),
+            )
+    }
+}
+
+fn nonce_timestamp(nonce: &str) -> Option<u64> {
+    let (timestamp, _) = nonce.split_once('.')?;
+    u64::from_str_radix(timestamp, 16).ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn md5_hex(s: &str) -> String {
+    hex::encode(Md5::digest(s.as_bytes()))
+}
+
+fn parse_digest_params(params: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let Some((name, tail)) = rest.split_once('=') else {
+            break;
+        };
+        let tail = tail.trim_start();
+        let (value, tail) = if let Some(quoted) = tail.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some((value, tail)) => (value, tail),
+                None => break,
+            }
+        } else {
+            tail.split_once(',').unwrap_or((tail, ""))
+        };
+        map.insert(name.trim().to_lowercase(), value.trim().to_string());
+        rest = tail.trim_start_matches([',', ' ']);
+    }
+    map
+}
+
+pub trait RequiredScope: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+pub struct ReadOnlyScope;
+pub struct InvoiceScope;
+pub struct AdminScope;
+
+impl RequiredScope for ReadOnlyScope {
+    const SCOPE: Scope = Scope::ReadOnly;
+}
+
+impl RequiredScope for InvoiceScope {
+    const SCOPE: Scope = Scope::Invoice;
+}
+
+impl RequiredScope for AdminScope {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+/// Request guard that checks the caller has at least the scope `S`.
+/// Every route takes one.
+pub struct Authorized<S: RequiredScope> {
+    /// Wallets of the key, all of them if empty
+    wallets: Vec<u32>,
+    scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> Authorized<S> {
+    fn new(wallets: Vec<u32>) -> Self {
+        Authorized {
+            wallets,
+            scope: PhantomData,
+        }
+    }
+
+    /// Fails with `Forbidden` if the key is restricted to other wallets
+    pub fn check_wallet(&self, wallet: u32) -> Result<()> {
+        if !self.wallets.is_empty() && !self.wallets.contains(&wallet) {
+            bail!(Forbidden(format!(
+                "The API key has no access to wallet {wallet}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fails with `Forbidden` unless the key has access to every wallet,
+    /// for the requests that are not about a single wallet
+    pub fn check_all_wallets(&self) -> Result<()> {
+        if !self.wallets.is_empty() {
+            bail!(Forbidden(format!(
+                "The API key is restricted to wallets {:?}",
+                self.wallets
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the key has access to the wallet
+    pub fn has_wallet(&self, wallet: u32) -> bool {
+        self.check_wallet(wallet).is_ok()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, S: RequiredScope> FromRequest<'r> for Authorized<S> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        let Some(auth) = req.rocket().state::<ApiAuth>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        if auth.disabled {
+            return Outcome::Success(Authorized::new(vec![]));
+        }
+        let key = auth.authorize(
+            req.method().as_str(),
+            &req.uri().to_string(),
+            req.headers().get_one("Authorization"),
+        );
+        match key {
+            Some(key) if key.scope >= S::SCOPE => {
+                Outcome::Success(Authorized::new(key.wallets.clone()))
+            }
+            Some(_) => Outcome::Error((Status::Forbidden, ())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The API key has no access to the wallets of the request
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct Forbidden(String);
+
+/// Error of the routes: 403 when the API key has no access to the
+/// wallets of the request, 500 otherwise
+#[derive(Responder)]
+pub enum ApiError {
+    #[response(status = 403)]
+    Forbidden(String),
+    Internal(Debug<anyhow::Error>),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        match e.into().downcast::<Forbidden>() {
+            Ok(Forbidden(message)) => ApiError::Forbidden(message),
+            Err(e) => ApiError::Internal(Debug(e)),
+        }
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 401)]
+pub struct AuthChallenge {
+    message: &'static str,
+    challenge: Header<'static>,
+}
+
+#[catch(401)]
+pub fn unauthorized(req: &Request) -> AuthChallenge {
+    let challenge = match req.rocket().state::<ApiAuth>() {
+        Some(auth) => auth.challenge(),
+        None => Header::new("WWW-Authenticate", format!("Bearer realm=\"{REALM}\"")),
+    };
+    AuthChallenge {
+        message: "Unauthorized",
+        challenge,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> ApiAuth {
+        ApiAuth::new(
+            &[
+                ApiKey {
+                    key: "reader".to_string(),
+                    scope: Scope::ReadOnly,
+                    user: None,
+                    wallets: vec![],
+                },
+                ApiKey {
+                    key: "secret".to_string(),
+                    scope: Scope::Invoice,
+                    user: Some("btcpay".to_string()),
+                    wallets: vec![1],
+                },
+            ],
+            true,
+            AuthMode::Required,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bearer() {
+        let auth = auth();
+        assert_eq!(
+            auth.authorize("POST", "/get_height", Some("Bearer reader"))
+                .map(|k| k.scope),
+            Some(Scope::ReadOnly)
+        );
+        assert_eq!(
+            auth.authorize("POST", "/create_address", Some("Bearer secret"))
+                .map(|k| k.scope),
+            Some(Scope::Invoice)
+        );
+        assert!(auth
+            .authorize("POST", "/get_height", Some("Bearer wrong"))
+            .is_none());
+        assert!(auth.authorize("POST", "/get_height", None).is_none());
+    }
+
+    #[test]
+    fn test_digest() {
+        let auth = auth();
+        let nonce = auth.new_nonce(now());
+        let ha1 = md5_hex(&format!("btcpay:{REALM}:secret"));
+        let ha2 = md5_hex("POST:/create_address");
+        let response = md5_hex(&format!("{ha1}:{nonce}:00000001:abcdef:auth:{ha2}"));
+        let header = format!(
+            "Digest username=\"btcpay\", realm=\"{REALM}\", nonce=\"{nonce}\", uri=\"/create_address\", \
+            algorithm=MD5, qop=auth, nc=00000001, cnonce=\"abcdef\", response=\"{response}\""
+        );
+        assert_eq!(
+            auth.authorize("POST", "/create_address", Some(header.as_str()))
+                .map(|k| k.scope),
+            Some(Scope::Invoice)
+        );
+        // Replayed on another route
+        assert!(auth
+            .authorize("POST", "/reorg", Some(header.as_str()))
+            .is_none());
+        // Replayed on the same route
+        assert!(auth
+            .authorize("POST", "/create_address", Some(header.as_str()))
+            .is_none());
+        let response = md5_hex(&format!("{ha1}:{nonce}:00000002:abcdef:auth:{ha2}"));
+        let next = format!(
+            "Digest username=\"btcpay\", realm=\"{REALM}\", nonce=\"{nonce}\", uri=\"/create_address\", \
+            algorithm=MD5, qop=auth, nc=00000002, cnonce=\"abcdef\", response=\"{response}\""
+        );
+        assert!(auth
+            .authorize("POST", "/create_address", Some(next.as_str()))
+            .is_some());
+
+        let stale_nonce = auth.new_nonce(now() - 2 * NONCE_VALIDITY_SECS);
+        let header = header.replace(&nonce, &stale_nonce);
+        assert!(auth
+            .authorize("POST", "/create_address", Some(header.as_str()))
+            .is_none());
+    }
+
+    #[test]
+    fn test_auth_mode() {
+        assert!(ApiAuth::new(&[], false, AuthMode::Required).is_err());
+        let auth = ApiAuth::new(&[], false, AuthMode::Disabled).unwrap();
+        assert!(auth.is_disabled());
+        assert!(!self::auth().is_disabled());
+    }
+
+    #[test]
+    fn test_wallets() {
+        let all: Authorized<ReadOnlyScope> = Authorized::new(vec![]);
+        assert!(all.check_wallet(0).is_ok());
+        assert!(all.check_wallet(2).is_ok());
+        assert!(all.check_all_wallets().is_ok());
+
+        let merchant: Authorized<ReadOnlyScope> = Authorized::new(vec![1, 3]);
+        assert!(merchant.check_wallet(1).is_ok());
+        assert!(merchant.check_wallet(3).is_ok());
+        assert!(merchant.check_wallet(0).is_err());
+        assert!(merchant.check_wallet(2).is_err());
+        assert!(merchant.check_all_wallets().is_err());
+
+        // Refused with 403, and the other errors with 500
+        let forbidden = merchant.check_all_wallets().unwrap_err();
+        assert!(matches!(ApiError::from(forbidden), ApiError::Forbidden(_)));
+        let other = anyhow::anyhow!("Unknown wallet 2");
+        assert!(matches!(ApiError::from(other), ApiError::Internal(_)));
+    }
+}
@@ -1,73 +1,78 @@
-use crate::account::{Account, AccountBalance, SubAccount};
-use crate::lwd_rpc::BlockId;
+use crate::account::{
+    Account, AccountBalance, AccountTag, AddressInfo, SubAccount, SubAccountBalance,
+};
+use crate::address::address_receivers;
+use crate::keys::ViewingKey;
+use crate::memo::parse_memo;
+use crate::metrics::db_timer;
 use crate::network::Network;
-use crate::scan::ScanEvent;
-use crate::transaction::{SubAddress, Transfer};
+use crate::payment_id::PaymentIdParser;
+use crate::scan::{get_block_hash, ScanEvent, WalletTx};
+use crate::storage::{SnapshotInfo, Storage, WalletKey, WalletMetadata, SCHEMA_VERSION};
+use crate::transaction::{MemoEncoding, SubAddress, Transfer, TransferFilter};
 use crate::{notify_tx, Client, Hash};
 use anyhow::Result;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
-use sqlx::{Acquire, Row, SqliteConnection, SqlitePool};
-use std::collections::HashMap;
-use tokio::sync::Mutex;
-use tonic::Request;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, MutexGuard, Notify};
 use zcash_keys::address::UnifiedAddress;
 use zcash_keys::encoding::AddressCodec;
-use zcash_keys::keys::{UnifiedAddressRequest, UnifiedFullViewingKey};
+use zcash_primitives::legacy::TransparentAddress;
 use zcash_protocol::consensus::{NetworkUpgrade, Parameters};
 
+#[derive(Clone)]
 pub struct Db {
     network: Network,
-    pool: SqlitePool,
-    ufvk: UnifiedFullViewingKey,
+    store: Arc<dyn Storage>,
+    // Viewing keys by wallet id
+    wallets: Arc<RwLock<BTreeMap<u32, ViewingKey>>>,
     notify_tx_url: String,
-    address_creation_lock: Mutex<()>,
+    payment_id: Option<PaymentIdParser>,
+    address_creation_lock: Arc<Mutex<()>>,
+    scan_lock: Arc<Mutex<()>>,
+    // Wallets with a running catch-up scan
+    catch_ups: Arc<std::sync::Mutex<HashSet<u32>>>,
+    memo_lock: Arc<Mutex<()>>,
+    memo_queued: Arc<Notify>,
 }
 
+// A transaction is given up after this many failed fetches of its memos
+const MEMO_MAX_ATTEMPTS: u32 = 10;
+// Delay before the first retry, in seconds. It doubles at every failure
+const MEMO_RETRY_DELAY: u64 = 30;
+const MEMO_MAX_RETRY_DELAY: u64 = 3600;
+
 impl Db {
-    pub async fn new(
+    pub fn new(
         network: Network,
-        db_path: &str,
-        ufvk: &UnifiedFullViewingKey,
+        store: Arc<dyn Storage>,
+        vk: &ViewingKey,
         notify_tx_url: &str,
-    ) -> Result<Self> {
-        let options = SqliteConnectOptions::new()
-            .filename(db_path)
-            .create_if_missing(true);
-        let pool = SqlitePool::connect_with(options).await?;
-        Ok(Db {
+        payment_id: Option<PaymentIdParser>,
+    ) -> Self {
+        Db {
             network,
-            pool,
-            ufvk: ufvk.clone(),
+            store,
+            wallets: Arc::new(RwLock::new(BTreeMap::from([(0, vk.clone())]))),
             notify_tx_url: notify_tx_url.to_string(),
-            address_creation_lock: Mutex::new(()),
-        })
-    }
-
-    async fn cleanup_stale_data(connection: &mut SqliteConnection) -> Result<()> {
-        sqlx::query("DELETE FROM received_notes WHERE height >=
-            (SELECT MAX(height) FROM blocks)")
-        .execute(connection)
-        .await?;
-        Ok(())
+            payment_id,
+            address_creation_lock: Arc::new(Mutex::new(())),
+            scan_lock: Arc::new(Mutex::new(())),
+            catch_ups: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            memo_lock: Arc::new(Mutex::new(())),
+            memo_queued: Arc::new(Notify::new()),
+        }
     }
 
-    pub async fn new_account(&self, name: &str) -> Result<Account> {
+    pub async fn new_account(&self, wallet: u32, name: &str) -> Result<Account> {
         let _guard = self.address_creation_lock.lock().await;
-        let mut connection = self.pool.acquire().await?;
-        let (id_account,): (Option<u32>,) = sqlx::query_as("SELECT MAX(account) FROM addresses")
-            .fetch_one(&mut *connection)
-            .await?;
+        let id_account = self.store.max_account(wallet).await?;
         let id_account = id_account.map(|id| id + 1).unwrap_or(0);
-        let (diversifier_index, address) = self.next_diversifier(&mut connection).await?;
-        self.store_receivers(
-            &mut connection,
-            name,
-            id_account,
-            0,
-            diversifier_index,
-            &address,
-        )
-        .await?;
+        let (diversifier_index, address) = self.next_diversifier(wallet).await?;
+        self.store_receivers(wallet, name, id_account, 0, diversifier_index, &address)
+            .await?;
 
         let account = Account {
             account_index: id_account,
@@ -76,18 +81,22 @@ impl Db {
         Ok(account)
     }
 
-    pub async fn new_sub_account(&self, id_account: u32, name: &str) -> Result<SubAccount> {
+    pub async fn new_sub_account(
+        &self,
+        wallet: u32,
+        id_account: u32,
+        name: &str,
+    ) -> Result<SubAccount> {
         let _guard = self.address_creation_lock.lock().await;
-        let mut connection = self.pool.acquire().await?;
-        let (id_sub_account,): (u32,) =
-            sqlx::query_as("SELECT MAX(sub_account) FROM addresses WHERE account = ?1")
-                .bind(id_account)
-                .fetch_one(&mut *connection)
-                .await?;
+        let id_sub_account = self
+            .store
+            .max_sub_account(wallet, id_account)
+            .await?
+            .ok_or(anyhow::anyhow!("Unknown account index {id_account}"))?;
         let id_sub_account = id_sub_account + 1;
-        let (diversifier_index, address) = self.next_diversifier(&mut connection).await?;
+        let (diversifier_index, address) = self.next_diversifier(wallet).await?;
         self.store_receivers(
-            &mut connection,
+            wallet,
             name,
             id_account,
             id_sub_account,
@@ -106,522 +115,367 @@ impl Db {
 
     async fn store_receivers(
         &self,
-        connection: &mut SqliteConnection,
+        wallet: u32,
         name: &str,
         id_account: u32,
         id_sub_account: u32,
         diversifier_index: u64,
         address: &str,
     ) -> Result<()> {
-        let r = sqlx::query("INSERT INTO addresses(label, account, sub_account, address, diversifier_index) VALUES (?1,?2,?3,?4,?5)")
-            .bind(name)
-            .bind(id_account)
-            .bind(id_sub_account)
-            .bind(address)
-            .bind(diversifier_index as i64)
-            .execute(&mut *connection)
-            .await?;
-        let id_address = r.last_insert_rowid() as u32;
-
-        let ua = UnifiedAddress::decode(&self.network, address).unwrap();
-        if let Some(address) = ua.sapling() {
-            sqlx::query(
-                "INSERT INTO receivers(pool, id_address, receiver_address)
-                VALUES (1, ?1, ?2)",
-            )
-            .bind(id_address)
-            .bind(address.encode(&self.network))
-            .execute(&mut *connection)
-            .await?;
-        }
-        if let Some(address) = ua.orchard() {
-            let ua = UnifiedAddress::from_receivers(Some(*address), None, None).unwrap();
-            sqlx::query(
-                "INSERT INTO receivers(pool, id_address, receiver_address)
-                VALUES (2, ?1, ?2)",
+        let receivers = address_receivers(&self.network, address)?;
+        self.store
+            .store_address(
+                wallet,
+                name,
+                id_account,
+                id_sub_account,
+                diversifier_index,
+                address,
+                &receivers,
             )
-            .bind(id_address)
-            .bind(ua.encode(&self.network))
-            .execute(&mut *connection)
-            .await?;
+            .await
+    }
+
+    pub async fn get_addresses(
+        &self,
+        wallet: u32,
+        id_account: u32,
+        sub_accounts: &[u32],
+    ) -> Result<Vec<AddressInfo>> {
+        let _timer = db_timer("get_addresses");
+        self.store
+            .get_addresses(wallet, id_account, sub_accounts)
+            .await
+    }
+
+    /// Resolve an address back to its account and sub account. The address can be
+    /// one of our UA or any of their receivers, alone or in another UA
+    pub async fn get_address_index(
+        &self,
+        wallet: u32,
+        address: &str,
+    ) -> Result<Option<SubAddress>> {
+        let _timer = db_timer("get_address_index");
+        let address = zcash_keys::address::Address::decode(&self.network, address)
+            .ok_or(anyhow::anyhow!("Invalid address {address}"))?;
+        let mut candidates = vec![];
+        match address {
+            zcash_keys::address::Address::Sapling(pa) => {
+                candidates.push(pa.encode(&self.network));
+            }
+            zcash_keys::address::Address::Transparent(ta) => {
+                candidates.push(ta.encode(&self.network));
+            }
+            zcash_keys::address::Address::Tex(pkh) => {
+                candidates.push(TransparentAddress::PublicKeyHash(pkh).encode(&self.network));
+            }
+            zcash_keys::address::Address::Unified(ua) => {
+                candidates.push(ua.encode(&self.network));
+                if let Some(address) = ua.orchard() {
+                    let ua = UnifiedAddress::from_receivers(Some(*address), None, None).unwrap();
+                    candidates.push(ua.encode(&self.network));
+                }
+                if let Some(address) = ua.sapling() {
+                    candidates.push(address.encode(&self.network));
+                }
+                if let Some(address) = ua.transparent() {
+                    candidates.push(address.encode(&self.network));
+                }
+            }
         }
 
-        Ok(())
+        for candidate in candidates {
+            let index = self.store.find_address(wallet, &candidate).await?;
+            if index.is_some() {
+                return Ok(index);
+            }
+        }
+        Ok(None)
     }
 
     pub async fn get_accounts(
         &self,
+        wallet: u32,
         height: u32,
         confirmations: u32,
+        tag: Option<&str>,
     ) -> Result<Vec<AccountBalance>> {
-        let mut connection = self.pool.acquire().await?;
+        let _timer = db_timer("get_accounts");
         let confirmed_height = height - confirmations + 1;
-        let sub_accounts = sqlx::query(
-            "WITH base AS (SELECT account, address FROM addresses WHERE sub_account = 0), \
-                balances AS (SELECT account, SUM(value) AS total from received_notes WHERE spent IS NULL GROUP BY account), \
-                unlocked_balances AS (SELECT account, SUM(value) AS unlocked from received_notes WHERE spent IS NULL AND height <= ?1 GROUP BY account) \
-                SELECT a.account, a.label, b.total, COALESCE(u.unlocked, 0) AS unlocked, base.address as base_address \
-                FROM addresses a JOIN balances b ON a.account = b.account LEFT JOIN unlocked_balances u ON u.account = a.account JOIN base ON base.account = a.account GROUP BY a.account")
-            .bind(confirmed_height)
-            .map(|row: SqliteRow| {
-                let id_account: u32 = row.get(0);
-                let label: String = row.get(1);
-                let balance: u64 = row.get(2);
-                let unlocked: u64 = row.get(3);
-                let base_address: String = row.get(4);
-                AccountBalance {
-                    account_index: id_account,
-                    label,
-                    balance,
-                    unlocked_balance: unlocked,
-                    base_address,
-                    tag: "".to_string(),
-                }
-            })
-            .fetch_all(&mut *connection)
-            .await?;
+        self.store.get_accounts(wallet, confirmed_height, tag).await
+    }
+
+    /// Balances of the sub accounts of an account. An empty list of
+    /// sub accounts selects all of them
+    pub async fn get_subaddress_balances(
+        &self,
+        wallet: u32,
+        height: u32,
+        account_index: u32,
+        sub_accounts: &[u32],
+        confirmations: u32,
+    ) -> Result<Vec<SubAccountBalance>> {
+        let _timer = db_timer("get_subaddress_balances");
+        self.store
+            .get_subaddress_balances(wallet, height, account_index, sub_accounts, confirmations)
+            .await
+    }
+
+    pub async fn set_address_label(
+        &self,
+        wallet: u32,
+        id_account: u32,
+        id_sub_account: u32,
+        label: &str,
+    ) -> Result<()> {
+        self.store
+            .set_address_label(wallet, id_account, id_sub_account, label)
+            .await
+    }
+
+    pub async fn tag_accounts(&self, wallet: u32, tag: &str, accounts: &[u32]) -> Result<()> {
+        self.store.tag_accounts(wallet, tag, accounts).await
+    }
 
-        Ok(sub_accounts)
+    pub async fn untag_accounts(&self, wallet: u32, accounts: &[u32]) -> Result<()> {
+        self.store.untag_accounts(wallet, accounts).await
+    }
+
+    pub async fn set_tag_description(
+        &self,
+        wallet: u32,
+        tag: &str,
+        description: &str,
+    ) -> Result<()> {
+        self.store
+            .set_tag_description(wallet, tag, description)
+            .await
+    }
+
+    pub async fn get_account_tags(&self, wallet: u32) -> Result<Vec<AccountTag>> {
+        let rows = self.store.get_tagged_accounts(wallet).await?;
+
+        let mut tags: Vec<AccountTag> = vec![];
+        for (tag, description, account) in rows {
+            match tags.last_mut() {
+                Some(last) if last.tag == tag => last.accounts.push(account),
+                _ => tags.push(AccountTag {
+                    tag,
+                    label: description,
+                    accounts: vec![account],
+                }),
+            }
+        }
+        Ok(tags)
     }
 
     pub async fn get_synced_height(&self) -> Result<u32> {
-        let mut connection = self.pool.acquire().await?;
-        let height = sqlx::query("SELECT MAX(height) FROM blocks")
-            .map(|row: SqliteRow| {
-                let h: Option<u32> = row.get(0);
-                h.unwrap_or_else(|| {
-                    u32::from(
-                        self.network
-                            .activation_height(NetworkUpgrade::Sapling)
-                            .unwrap(),
-                    )
-                })
-            })
-            .fetch_one(&mut *connection)
-            .await?;
+        let _timer = db_timer("get_synced_height");
+        let height = self.store.get_synced_height().await?.unwrap_or_else(|| {
+            u32::from(
+                self.network
+                    .activation_height(NetworkUpgrade::Sapling)
+                    .unwrap(),
+            )
+        });
         Ok(height)
     }
 
+    pub async fn get_block_time(&self, height: u32) -> Result<Option<u32>> {
+        self.store.get_block_time(height).await
+    }
+
+    pub async fn check_writable(&self) -> Result<()> {
+        self.store.check_writable().await
+    }
+
     pub async fn get_block_hash(&self, height: u32) -> Result<Option<[u8; 32]>> {
-        let mut connection = self.pool.acquire().await?;
-
-        let hash = sqlx::query("SELECT hash FROM blocks WHERE height = ?1")
-            .bind(height)
-            .map(|row: SqliteRow| {
-                let hash_vec: Vec<u8> = row.get(0);
-                let mut hash = [0u8; 32];
-                hash.copy_from_slice(&hash_vec);
-                hash
-            })
-            .fetch_optional(&mut *connection)
-            .await?;
-        Ok(hash)
+        self.store.get_block_hash(height).await
     }
 
-    fn row_to_transfer(
-        row: SqliteRow,
+    pub async fn get_transfers(
+        &self,
         latest_height: u32,
-        account_index: u32,
+        filter: &TransferFilter,
         confirmations: u32,
-    ) -> Transfer {
-        let address: String = row.get(0);
-        let value: u64 = row.get(1);
-        let sub_account: u32 = row.get(2);
-        let mut txid: Vec<u8> = row.get(3);
-        txid.reverse();
-        let memo: String = row.get(4);
-        let height: u32 = row.get(5);
-        Transfer {
-            address,
-            amount: value,
-            confirmations: latest_height - height + 1,
-            height,
-            fee: 0,
-            note: memo,
-            payment_id: "".to_string(),
-            subaddr_index: SubAddress {
-                major: account_index,
-                minor: sub_account,
-            },
-            suggested_confirmations_threshold: confirmations,
-            timestamp: 0, // TODO: Check if needed
-            txid: hex::encode(txid),
-            r#type: "in".to_string(),
-            unlock_time: 0,
-        }
+        memo_encoding: MemoEncoding,
+    ) -> Result<Vec<Transfer>> {
+        let _timer = db_timer("get_transfers");
+        let transfers = self.store.get_transfers(filter).await?;
+        Ok(transfers
+            .into_iter()
+            .map(|t| t.into_transfer(latest_height, confirmations, memo_encoding))
+            .collect())
     }
 
-    pub async fn get_transfers(
+    /// Outgoing transfers are reported per spending transaction and sub account,
+    /// with the total value of the notes spent. We don't know the recipients
+    /// since this is a view only wallet
+    pub async fn get_outgoing_transfers(
         &self,
         latest_height: u32,
-        account_index: u32,
-        sub_accounts: &[u32],
+        filter: &TransferFilter,
         confirmations: u32,
+        memo_encoding: MemoEncoding,
     ) -> Result<Vec<Transfer>> {
-        let mut connection = self.pool.acquire().await?;
-
-        let transfers = sqlx::query(
-            "SELECT address, n.value, sub_account, txid, memo, n.height \
-            FROM received_notes n JOIN transactions t ON n.id_tx = t.id_tx WHERE \
-            account = ?1 ORDER BY n.height",
-        )
-        .bind(account_index)
-        .map(|row| Self::row_to_transfer(row, latest_height, account_index, confirmations))
-        .fetch_all(&mut *connection)
-        .await?;
-
-        let transfers = transfers
+        let _timer = db_timer("get_outgoing_transfers");
+        let transfers = self.store.get_outgoing_transfers(filter).await?;
+        Ok(transfers
             .into_iter()
-            .filter(|transfer| sub_accounts.contains(&transfer.subaddr_index.minor))
-            .collect::<Vec<_>>();
-        Ok(transfers)
+            .map(|t| t.into_transfer(latest_height, confirmations, memo_encoding))
+            .collect())
     }
 
     pub async fn get_transfers_by_txid(
         &self,
+        wallet: u32,
+        account: Option<u32>,
         latest_height: u32,
         txid: &str,
-        account_index: u32,
         confirmations: u32,
+        memo_encoding: MemoEncoding,
     ) -> Result<Vec<Transfer>> {
-        let mut connection = self.pool.acquire().await?;
-
+        let _timer = db_timer("get_transfers_by_txid");
         let mut txid = hex::decode(txid)?;
         txid.reverse();
-        let transfers = sqlx::query(
-            "SELECT a.address, n.value, n.sub_account, txid, memo, n.height
-            FROM received_notes n
-			JOIN transactions t ON n.id_tx = t.id_tx
-			JOIN receivers r ON n.address = r.receiver_address
-			JOIN addresses a ON a.id_address = r.id_address
-            WHERE txid = ?1
-			ORDER BY n.height",
-        )
-        .bind(txid)
-        .map(|row| Self::row_to_transfer(row, latest_height, account_index, confirmations))
-        .fetch_all(&mut *connection)
-        .await?;
-        Ok(transfers)
+        let transfers = self
+            .store
+            .get_transfers_by_txid(wallet, account, &txid)
+            .await?;
+        Ok(transfers
+            .into_iter()
+            .map(|t| {
+                let mut transfer = t.into_transfer(latest_height, confirmations, memo_encoding);
+                transfer.memo_fields = parse_memo(&self.network, &transfer.note);
+                transfer
+            })
+            .collect())
     }
 
     pub async fn truncate_height(&self, height: u32) -> Result<()> {
-        let mut connection = self.pool.acquire().await?;
-
-        sqlx::query("DELETE FROM transactions WHERE height >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-        sqlx::query("DELETE FROM received_notes WHERE height >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-        sqlx::query("DELETE FROM blocks WHERE height >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-        sqlx::query("UPDATE received_notes SET spent = NULL WHERE spent >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-
-        Ok(())
+        let _timer = db_timer("truncate_height");
+        self.store.truncate_height(height).await
     }
 
     pub async fn fetch_block_hash(&self, client: &mut Client, height: u32) -> Result<()> {
-        let mut connection = self.pool.acquire().await?;
-        if sqlx::query("SELECT 1 FROM blocks WHERE height = ?1")
-            .bind(height)
-            .fetch_optional(&mut *connection)
-            .await?
-            .is_none()
-        {
-            let b = client
-                .get_block(Request::new(BlockId {
-                    height: height as u64,
-                    hash: vec![],
-                }))
-                .await?
-                .into_inner();
-            let hash: Hash = b.hash.try_into().unwrap();
-            sqlx::query(
-                "INSERT INTO blocks(hash, height)
-            VALUES (?1, ?2)",
-            )
-            .bind(hash.as_slice())
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
+        if self.store.get_block_hash(height).await?.is_none() {
+            let (hash, time) = get_block_hash(client, height).await?;
+            self.store.store_block(height, &hash, Some(time)).await?;
         }
         Ok(())
     }
 
-    pub async fn get_nfs(&self) -> Result<HashMap<[u8; 32], u64>> {
-        let mut connection = self.pool.acquire().await?;
-
-        let nfs = sqlx::query("SELECT nf, value FROM received_notes WHERE spent = 0")
-            .map(|row: SqliteRow| {
-                let nf: Vec<u8> = row.get(0);
-                let value: u64 = row.get(1);
-                let nf: Hash = nf.try_into().unwrap();
-                (nf, value)
-            })
-            .fetch_all(&mut *connection)
-            .await?;
-
-        let mut nf_map = HashMap::new();
-        for (nf, value) in nfs {
-            nf_map.insert(nf, value);
-        }
-        Ok(nf_map)
+    pub async fn get_nfs(&self, wallet: u32) -> Result<HashMap<[u8; 32], u64>> {
+        let _timer = db_timer("get_nfs");
+        self.store.get_nfs(wallet).await
     }
 
-    async fn next_diversifier(&self, connection: &mut SqliteConnection) -> Result<(u64, String)> {
-        let di = sqlx::query("SELECT MAX(diversifier_index) FROM addresses")
-            .map(|r: SqliteRow| r.get::<Option<u64>, _>(0))
-            .fetch_one(&mut *connection)
+    async fn next_diversifier(&self, wallet: u32) -> Result<(u64, String)> {
+        let di = self
+            .store
+            .max_diversifier_index(wallet)
             .await?
             .map(|di| di + 1)
             .unwrap_or_default();
-        let (ua, ndi) = self
-            .ufvk
-            .find_address(di.into(), UnifiedAddressRequest::AllAvailableKeys)?;
+        self.find_address(&self.viewing_key(wallet)?, di)
+    }
+
+    /// First valid diversifier index from `di` and its address
+    fn find_address(&self, vk: &ViewingKey, di: u64) -> Result<(u64, String)> {
+        let (ua, ndi) = vk.find_address(di)?;
         let ua = ua.encode(&self.network);
         let ndi: u64 = ndi.try_into().unwrap();
         Ok((ndi, ua))
     }
 
     pub async fn create(&self) -> Result<bool> {
-        let mut connection = self.pool.acquire().await?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS blocks (
-            height INTEGER PRIMARY KEY,
-            hash BLOB NOT NULL)",
-        )
-        .execute(&mut *connection)
-        .await?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS addresses (
-            id_address INTEGER PRIMARY KEY,
-            label TEXT NOT NULL,
-            account INTEGER NOT NULL,
-            sub_account INTEGER NOT NULL,
-            address TEXT NOT NULL,
-            diversifier_index INTEGER NOT NULL)",
-        )
-        .execute(&mut *connection)
-        .await?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS receivers (
-            id_receiver INTEGER PRIMARY KEY,
-            pool INTEGER NOT NULL,
-            id_address INTEGER NOT NULL,
-            receiver_address TEXT NOT NULL)",
-        )
-        .execute(&mut *connection)
-        .await?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS transactions (
-            id_tx INTEGER PRIMARY KEY,
-            txid BLOB NOT NULL UNIQUE,
-            height INTEGER NOT NULL,
-            value INTEGER NOT NULL)",
-        )
-        .execute(&mut *connection)
-        .await?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS received_notes (
-            id_note INTEGER PRIMARY KEY,
-            address TEXT NOT NULL,
-            account INTEGER,
-            sub_account INTEGER,
-            id_tx INTEGER NOT NULL,
-            position INTEGER NOT NULL,
-            height INTEGER NOT NULL,
-            diversifier BLOB NOT NULL,
-            value INTEGER NOT NULL,
-            rcm BLOB NOT NULL,
-            nf BLOB NOT NULL UNIQUE,
-            rho BLOB,
-            memo TEXT,
-            spent INTEGER,
-            CONSTRAINT tx_output UNIQUE (position))",
-        )
-        .execute(&mut *connection)
-        .await?;
-
-        Self::cleanup_stale_data(&mut connection).await?;
+        self.store.create(&self.network).await
+    }
 
-        if sqlx::query("SELECT 1 FROM pragma_table_info('received_notes') WHERE name = 'rho'")
-            .fetch_optional(&mut *connection)
-            .await?
-            .is_none()
-        {
-            panic!("Old database schema. This version is not compatible with it.");
+    fn metadata(&self, birth_height: u32) -> WalletMetadata {
+        WalletMetadata {
+            ufvk_fingerprint: self.ufvk_fingerprint(),
+            network: self.network.name().to_string(),
+            birth_height,
         }
+    }
 
-        let r = sqlx::query("SELECT 1 FROM addresses")
-            .map(|r: SqliteRow| r.get::<u32, _>(0))
-            .fetch_optional(&mut *connection)
-            .await?;
-
-        Ok(r.is_some())
-    }
-
-    pub async fn store_events(&self, events: &[ScanEvent]) -> Result<()> {
-        let mut connection = self.pool.acquire().await?;
-        let mut db_transaction = connection.begin().await?;
-        let db_tx = db_transaction.acquire().await?;
-        let mut notify_txids = vec![];
-
-        for event in events {
-            match event {
-                ScanEvent::Received(received_note) => {
-                    let (id_tx, is_new) = self
-                        .create_tx_if_not_exists(
-                            received_note.height,
-                            received_note.txid.as_slice(),
-                            db_tx,
-                        )
-                        .await?;
-                    if is_new {
-                        notify_txids.push(received_note.txid);
-                    }
-
-                    let (account, sub_account) = match sqlx::query(
-                        "SELECT a.account, a.sub_account FROM addresses a
-                        JOIN receivers r ON a.id_address = r.id_address
-                        WHERE r.receiver_address = ?1",
-                    )
-                    .bind(&received_note.address)
-                    .map(|r: SqliteRow| {
-                        let account: u32 = r.get(0);
-                        let sub_account: u32 = r.get(1);
-                        (account, sub_account)
-                    })
-                    .fetch_optional(&mut *db_tx)
-                    .await?
-                    {
-                        Some(x) => x,
-                        None => {
-                            let account = sqlx::query("SELECT MAX(account) FROM addresses")
-                                .map(|r: SqliteRow| {
-                                    let account: Option<u32> = r.get(0);
-                                    account.unwrap_or_default()
-                                })
-                                .fetch_one(&mut *db_tx)
-                                .await?;
-                            let sub_account = sqlx::query(
-                                "SELECT MAX(sub_account) FROM addresses WHERE account = ?1",
-                            )
-                            .bind(account)
-                            .map(|r: SqliteRow| {
-                                let sub_account: Option<u32> = r.get(0);
-                                sub_account.map(|x| x + 1).unwrap_or_default()
-                            })
-                            .fetch_optional(&mut *db_tx)
-                            .await?
-                            .unwrap_or_default();
-
-                            let r = sqlx::query(
-                                "INSERT INTO addresses
-                            (label, account, sub_account, address, diversifier_index)
-                            VALUES ('', ?1, ?2, ?3, ?4)",
-                            )
-                            .bind(account)
-                            .bind(sub_account)
-                            .bind(&received_note.address)
-                            .bind(received_note.diversifier_index.unwrap_or_default() as u32)
-                            .execute(&mut *db_tx)
-                            .await?;
-                            let id_address = r.last_insert_rowid() as u32;
-
-                            sqlx::query(
-                                "INSERT INTO receivers(pool, id_address, receiver_address)
-                                VALUES (?1, ?2, ?3)",
-                            )
-                            .bind(received_note.pool)
-                            .bind(id_address)
-                            .bind(&received_note.address)
-                            .execute(&mut *db_tx)
-                            .await?;
-
-                            (account, sub_account)
-                        }
-                    };
-
-                    sqlx::query(
-                        "INSERT INTO received_notes
-                        (address, account, sub_account, id_tx, position, height,
-                        diversifier, value, rcm, nf, rho, memo, spent)
-                        VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,'',0)",
-                    )
-                    .bind(&received_note.address)
-                    .bind(account)
-                    .bind(sub_account)
-                    .bind(id_tx)
-                    .bind(received_note.position)
-                    .bind(received_note.height)
-                    .bind(received_note.diversifier.as_slice())
-                    .bind(received_note.value as i64)
-                    .bind(received_note.rcm.as_slice())
-                    .bind(received_note.nf.as_slice())
-                    .bind(received_note.rho.map(|r| r.to_vec()))
-                    .execute(&mut *db_tx)
-                    .await?;
-                    sqlx::query("UPDATE transactions SET value = value + ?2 WHERE txid = ?1")
-                        .bind(received_note.txid.as_slice())
-                        .bind(received_note.value as i64)
-                        .execute(&mut *db_tx)
-                        .await?;
+    /// Bind the database to our viewing key, network and birth height the first
+    /// time, and refuse to use it with different ones afterwards
+    pub async fn check_metadata(&self, birth_height: u32) -> Result<()> {
+        let metadata = self.metadata(birth_height);
+        match self.store.get_metadata().await? {
+            Some(stored) => {
+                if stored.network != metadata.network {
+                    anyhow::bail!(
+                        "The database was created for the {} network, not {}",
+                        stored.network,
+                        metadata.network
+                    );
                 }
-                ScanEvent::Spent(spent_note) => {
-                    let (_, is_new) = self.create_tx_if_not_exists(
-                        spent_note.height,
-                        spent_note.txid.as_slice(),
-                        db_tx,
-                    )
-                    .await?;
-                    if is_new {
-                        notify_txids.push(spent_note.txid);
-                    }
-                    sqlx::query("UPDATE received_notes SET spent = TRUE WHERE nf = ?1")
-                        .bind(spent_note.nf.as_slice())
-                        .execute(&mut *db_tx)
-                        .await?;
-                    sqlx::query("UPDATE transactions SET value = value - ?2 WHERE txid = ?1")
-                        .bind(spent_note.txid.as_slice())
-                        .bind(spent_note.value as i64)
-                        .execute(&mut *db_tx)
-                        .await?;
+                if stored.ufvk_fingerprint != metadata.ufvk_fingerprint {
+                    anyhow::bail!(
+                        "The database was created for another viewing key. \
+                        Use --rotate-key to delete its data and switch to this key"
+                    );
+                }
+                if stored.birth_height != metadata.birth_height {
+                    anyhow::bail!(
+                        "The database was created with birth height {}, not {}. \
+                        Use --rotate-key to rescan from the new birth height",
+                        stored.birth_height,
+                        metadata.birth_height
+                    );
                 }
-                ScanEvent::Memo(memo_note) => {
-                    sqlx::query("UPDATE received_notes SET memo = ?2 WHERE nf = ?1")
-                        .bind(memo_note.nf.as_slice())
-                        .bind(&memo_note.memo)
-                        .execute(&mut *db_tx)
-                        .await?;
+            }
+            None => {
+                // Databases created before the metadata was stored:
+                // the address of the first account must come from our key
+                let addresses = self.store.get_addresses(0, 0, &[0]).await?;
+                if let Some(address) = addresses.first() {
+                    let (_, ua) =
+                        self.find_address(&self.viewing_key(0)?, address.diversifier_index)?;
+                    if ua != address.address {
+                        anyhow::bail!(
+                            "The database was created for another viewing key. \
+                            Use --rotate-key to delete its data and switch to this key"
+                        );
+                    }
                 }
-                ScanEvent::Block(height, hash) => {
-                    sqlx::query(
-                        "INSERT INTO blocks(height, hash)
-                        VALUES (?1, ?2)",
-                    )
-                    .bind(*height)
-                    .bind(hash.as_slice())
-                    .execute(&mut *db_tx)
-                    .await?;
+                self.store.set_metadata(&metadata).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the addresses and notes of the previous viewing key
+    /// and bind the database to ours. The wallet rescans from the birth height
+    pub async fn rotate_key(&self, birth_height: u32) -> Result<()> {
+        let _guard = self.lock_scan().await;
+        self.store.reset(&self.metadata(birth_height)).await?;
+        warn!(
+            "The database is now bound to the viewing key {}",
+            self.ufvk_fingerprint()
+        );
+        Ok(())
+    }
+
+    pub async fn store_events(&self, events: &mut [ScanEvent]) -> Result<()> {
+        let _timer = db_timer("store_events");
+        if let Some(parser) = &self.payment_id {
+            for event in events.iter_mut() {
+                if let ScanEvent::Memo(memo_note) = event {
+                    memo_note.payment_id = parser.parse(&memo_note.memo);
                 }
             }
         }
-        db_transaction.commit().await?;
+        let notify_txids = self.store.store_events(events).await?;
+        if events.iter().any(|e| matches!(e, ScanEvent::MemoTx(_))) {
+            self.memo_queued.notify_one();
+        }
 
         // Once committed, we can notify our listeners of the new received
-        // txs
+        // txs, and again once their memos are fetched
         for txid in notify_txids {
             notify_tx(&txid, &self.notify_tx_url).await?;
         }
@@ -629,37 +483,306 @@ impl Db {
         Ok(())
     }
 
-    pub async fn create_tx_if_not_exists(
-        &self,
-        height: u32,
-        txid: &[u8],
-        db_tx: &mut SqliteConnection,
-    ) -> Result<(u32, bool)> {
-        // let txid = &received_note.txid;
-        let result = match sqlx::query("SELECT id_tx FROM transactions WHERE txid = ?1")
-            .bind(txid)
-            .map(|r: SqliteRow| r.get::<u32, _>(0))
-            .fetch_optional(&mut *db_tx)
+    pub fn viewing_key(&self, wallet: u32) -> Result<ViewingKey> {
+        let wallets = self.wallets.read().unwrap();
+        let vk = wallets
+            .get(&wallet)
+            .ok_or(anyhow::anyhow!("Unknown wallet {wallet}"))?;
+        Ok(vk.clone())
+    }
+
+    /// Viewing keys of all the wallets, by id
+    pub fn wallets(&self) -> Vec<(u32, ViewingKey)> {
+        let wallets = self.wallets.read().unwrap();
+        wallets.iter().map(|(&id, vk)| (id, vk.clone())).collect()
+    }
+
+    /// Viewing keys of the wallets scanned at the tip, i.e. all of them
+    /// except those that are still catching up
+    pub async fn synced_wallets(&self) -> Result<Vec<(u32, ViewingKey)>> {
+        let catching_up: Vec<u32> = self
+            .store
+            .get_wallets()
             .await?
-        {
-            Some(id_tx) => (id_tx, false),
+            .iter()
+            .filter(|w| w.scan_height.is_some())
+            .map(|w| w.id)
+            .collect();
+        let wallets = self
+            .wallets()
+            .into_iter()
+            .filter(|(id, _)| !catching_up.contains(id))
+            .collect();
+        Ok(wallets)
+    }
+
+    pub async fn get_wallets(&self) -> Result<Vec<WalletKey>> {
+        self.store.get_wallets().await
+    }
+
+    /// Add a wallet, or check that it has the same key as before
+    pub async fn register_wallet(
+        &self,
+        client: &mut Client,
+        id: u32,
+        label: &str,
+        vk: &ViewingKey,
+        birth_height: u32,
+    ) -> Result<()> {
+        let encoded = vk.encode(&self.network);
+        let wallets = self.store.get_wallets().await?;
+        match wallets.iter().find(|w| w.id == id) {
+            Some(wallet) if wallet.vk != encoded => {
+                anyhow::bail!("Wallet {id} was created with another viewing key")
+            }
+            Some(_) => {
+                self.wallets.write().unwrap().insert(id, vk.clone());
+            }
             None => {
-                let r =
-                    sqlx::query("INSERT INTO transactions(txid, height, value) VALUES (?1, ?2, 0)")
-                        .bind(txid)
-                        .bind(height)
-                        .execute(db_tx)
-                        .await?;
-                let id_tx = r.last_insert_rowid();
-
-                (id_tx as u32, true)
+                if let Some(other) = self.find_wallet(&wallets, vk)? {
+                    anyhow::bail!("Wallet {id} has the viewing key of wallet {other}");
+                }
+                self.add_wallet(client, id, label, vk, birth_height).await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Wallet with the same incoming viewing key, whatever the encoding
+    /// of its key: UFVK, UIVK or Sapling extended full viewing key
+    fn find_wallet(&self, wallets: &[WalletKey], vk: &ViewingKey) -> Result<Option<u32>> {
+        let uivk = vk.to_uivk().encode(&self.network);
+        for wallet in wallets.iter() {
+            let other = ViewingKey::decode(&self.network, &wallet.vk)?;
+            if other.to_uivk().encode(&self.network) == uivk {
+                return Ok(Some(wallet.id));
             }
+        }
+        Ok(None)
+    }
+
+    /// Add a viewing key as a new wallet and return its id.
+    /// Scans are paused so that none of them misses the wallet
+    pub async fn import_wallet(
+        &self,
+        client: &mut Client,
+        label: &str,
+        vk: &ViewingKey,
+        birth_height: u32,
+    ) -> Result<u32> {
+        let _guard = self.lock_scan().await;
+        let wallets = self.store.get_wallets().await?;
+        if let Some(id) = self.find_wallet(&wallets, vk)? {
+            anyhow::bail!("This viewing key is already wallet {id}");
+        }
+        let id = wallets.iter().map(|w| w.id + 1).max().unwrap_or(1);
+        self.add_wallet(client, id, label, vk, birth_height).await?;
+        info!("Imported wallet {id} from height {birth_height}");
+        if !vk.tracks_spends() {
+            warn!("Wallet {id} has an incoming viewing key, its spends are not tracked");
+        }
+        Ok(id)
+    }
+
+    /// Store a new wallet with its first account. If its birth height is
+    /// below the synced height, the wallet catches up on its own before
+    /// it is scanned with the others
+    async fn add_wallet(
+        &self,
+        client: &mut Client,
+        id: u32,
+        label: &str,
+        vk: &ViewingKey,
+        birth_height: u32,
+    ) -> Result<()> {
+        let synced_height = self.store.get_synced_height().await?;
+        // Databases created before wallets were added already have
+        // the addresses of wallet 0
+        let has_addresses = self.store.max_account(id).await?.is_some();
+        let scan_height = match synced_height {
+            Some(height) if !has_addresses && height > birth_height => Some(birth_height),
+            _ => None,
+        };
+        self.store
+            .store_wallet(&WalletKey {
+                id,
+                label: label.to_string(),
+                vk: vk.encode(&self.network),
+                birth_height,
+                scan_height,
+            })
+            .await?;
+        self.wallets.write().unwrap().insert(id, vk.clone());
+        if synced_height.is_none() {
+            self.fetch_block_hash(client, birth_height).await?;
+        }
+        if !has_addresses {
+            self.new_account(id, "").await?;
+        }
+        Ok(())
+    }
+
+    /// Delete a wallet and its data. Wallet 0 has the viewing key
+    /// of the config and cannot be removed
+    pub async fn remove_wallet(&self, id: u32) -> Result<()> {
+        if id == 0 {
+            anyhow::bail!("Wallet 0 cannot be removed");
+        }
+        self.viewing_key(id)?;
+        let _guard = self.lock_scan().await;
+        self.store.delete_wallet(id).await?;
+        self.wallets.write().unwrap().remove(&id);
+        info!("Removed wallet {id}");
+        Ok(())
+    }
+
+    /// Mark the end of the catch-up scan of a wallet. From now on,
+    /// it is scanned with the others
+    pub async fn set_wallet_synced(&self, id: u32) -> Result<()> {
+        self.store.set_wallet_scan_height(id, None).await
+    }
+
+    /// Claim the catch-up scan of a wallet, false if it is already running
+    pub fn start_catch_up(&self, id: u32) -> bool {
+        self.catch_ups.lock().unwrap().insert(id)
+    }
+
+    pub fn end_catch_up(&self, id: u32) {
+        self.catch_ups.lock().unwrap().remove(&id);
+    }
+
+    /// Set the wallets to wallet 0 and the ones stored in the database,
+    /// e.g. after a restore drops the wallets imported since the snapshot
+    pub async fn load_wallets(&self) -> Result<()> {
+        let mut wallets = BTreeMap::from([(0, self.viewing_key(0)?)]);
+        for wallet in self.store.get_wallets().await? {
+            let vk = ViewingKey::decode(&self.network, &wallet.vk)
+                .map_err(|e| anyhow::anyhow!("{e} for wallet {}", wallet.id))?;
+            wallets.entry(wallet.id).or_insert(vk);
+        }
+        *self.wallets.write().unwrap() = wallets;
+        Ok(())
+    }
+
+    /// Identifies the viewing key of wallet 0 without revealing it
+    pub fn ufvk_fingerprint(&self) -> String {
+        fingerprint(&self.viewing_key(0).unwrap().encode(&self.network))
+    }
+
+    /// Fingerprints of the viewing keys of all the wallets
+    pub fn wallet_fingerprints(&self) -> BTreeMap<u32, String> {
+        self.wallets
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, vk)| (*id, fingerprint(&vk.encode(&self.network))))
+            .collect()
+    }
+
+    /// Write a snapshot of the database to `path`. Scans are paused
+    /// so that the synced height matches the content of the snapshot
+    pub async fn backup(&self, path: &str) -> Result<SnapshotInfo> {
+        let _guard = self.lock_scan().await;
+        let snapshot = SnapshotInfo {
+            schema_version: SCHEMA_VERSION,
+            synced_height: self.get_synced_height().await?,
+            ufvk_fingerprint: self.ufvk_fingerprint(),
+            created_at: unix_time(),
+            wallet_fingerprints: self.wallet_fingerprints(),
         };
+        self.store.backup(path, &snapshot).await?;
+        info!(
+            "Backup written to {path} at height {}",
+            snapshot.synced_height
+        );
+        Ok(snapshot)
+    }
 
-        Ok(result)
+    /// Replace the database with a snapshot taken with the same viewing keys.
+    /// The next scan catches up from the height of the snapshot
+    pub async fn restore(&self, path: &str) -> Result<SnapshotInfo> {
+        let snapshot = self.store.read_snapshot_info(path).await?;
+        if snapshot.ufvk_fingerprint != self.ufvk_fingerprint() {
+            anyhow::bail!("The backup {path} belongs to another viewing key");
+        }
+        // Every wallet of the backup must be one of ours, with the same key
+        let fingerprints = self.wallet_fingerprints();
+        for (id, fingerprint) in snapshot.wallet_fingerprints.iter() {
+            match fingerprints.get(id) {
+                Some(ours) if ours == fingerprint => {}
+                Some(_) => {
+                    anyhow::bail!("Wallet {id} of the backup {path} has another viewing key")
+                }
+                None => anyhow::bail!(
+                    "The backup {path} has wallet {id}, which is not served here. Import it first"
+                ),
+            }
+        }
+        if snapshot.schema_version > SCHEMA_VERSION {
+            anyhow::bail!(
+                "The backup {path} has schema version {}, more recent than this version of zcash-walletd",
+                snapshot.schema_version
+            );
+        }
+        // Neither the scans nor the memo fetches may write to the old database
+        let _guard = self.lock_scan().await;
+        let _memo_guard = self.lock_memos().await;
+        self.store.restore(path).await?;
+        // Migrate backups made by older versions
+        self.create().await?;
+        self.load_wallets().await?;
+        info!("Restored {path} at height {}", snapshot.synced_height);
+        Ok(snapshot)
+    }
+
+    /// Scans must not overlap, whether they come from the monitor task or the API
+    pub async fn lock_scan(&self) -> MutexGuard<'_, ()> {
+        self.scan_lock.lock().await
+    }
+
+    /// Transactions of the memo queue that are due, with their number of failed fetches
+    pub async fn get_memo_queue(&self, limit: u32) -> Result<Vec<(WalletTx, u32)>> {
+        let _timer = db_timer("get_memo_queue");
+        self.store.get_memo_queue(unix_time(), limit).await
+    }
+
+    /// Retry the fetch of the memos of a transaction later, or give up
+    /// after `MEMO_MAX_ATTEMPTS` failures
+    pub async fn retry_memo_tx(&self, txid: &Hash, attempts: u32) -> Result<()> {
+        let next_attempt = (attempts < MEMO_MAX_ATTEMPTS).then(|| {
+            let delay = MEMO_RETRY_DELAY << attempts.saturating_sub(1).min(16);
+            unix_time() + delay.min(MEMO_MAX_RETRY_DELAY)
+        });
+        if next_attempt.is_none() {
+            let mut txid = *txid;
+            txid.reverse();
+            warn!(
+                "Giving up the memos of {} after {attempts} attempts",
+                hex::encode(txid)
+            );
+        }
+        self.store.retry_memo_tx(txid, attempts, next_attempt).await
+    }
+
+    /// Resolves when transactions are added to the memo queue
+    pub async fn memo_queued(&self) {
+        self.memo_queued.notified().await
     }
 
-    pub fn ufvk(&self) -> &UnifiedFullViewingKey {
-        &self.ufvk
+    /// Memo fetches must not overlap either, so that a transaction is fetched once
+    pub async fn lock_memos(&self) -> MutexGuard<'_, ()> {
+        self.memo_lock.lock().await
     }
 }
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Identifies a viewing key without revealing it
+pub fn fingerprint(vk: &str) -> String {
+    hex::encode(&Sha256::digest(vk.as_bytes())[..16])
+}
@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, bail, Result};
+use rocket::serde::Deserialize;
+use sapling_crypto::{CommitmentTree, Node};
+use zcash_primitives::block::BlockHeader;
+use zcash_protocol::consensus::{BlockHeight, NetworkUpgrade, Parameters};
+
+use crate::{lwd_rpc::CompactBlock, network::Network};
+
+/// How much the blocks of lightwalletd are checked against their
+/// full headers. A compact block only has them if the server sends them.
+///
+/// The headers prove the work of the blocks, not their transactions:
+/// only the Sapling outputs of the blocks before Heartwood are bound to
+/// their header. Later outputs and every Orchard action come from the
+/// server as they are, so a malicious server can still invent payments
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderValidation {
+    /// Refuse the blocks without a full header
+    #[default]
+    Always,
+    /// Check the full headers that the server sends, and trust
+    /// the blocks that have none
+    WhenPresent,
+}
+
+// Version, previous block, merkle root, final sapling root, time and bits:
+// the input of the Equihash solution, followed by its nonce
+const EQUIHASH_INPUT_LEN: usize = 108;
+const NONCE_LEN: usize = 32;
+
+// Difficulty adjustment: the target of a block is the mean target of
+// the 17 blocks before, scaled by the time they took between the median
+// times of the 11 blocks that end the window and that precede it
+const AVERAGING_WINDOW: usize = 17;
+const MEDIAN_TIME_SPAN: usize = 11;
+const DAMPING_FACTOR: i64 = 4;
+// In percent of the timespan of the window
+const MAX_ADJUST_UP: i64 = 16;
+const MAX_ADJUST_DOWN: i64 = 32;
+
+/// Number of headers before a block needed to compute its target
+pub const DIFFICULTY_WINDOW: usize = AVERAGING_WINDOW + MEDIAN_TIME_SPAN;
+
+/// Check the proof of work of the full header of a compact block,
+/// and that the block has the hash, previous hash and time of the header.
+/// Returns the header, or None if the server did not send it
+pub fn check_header(
+    network: &Network,
+    block: &CompactBlock,
+    validation: HeaderValidation,
+) -> Result<Option<BlockHeader>> {
+    // Otherwise the header is empty, or only has the hash, previous hash and time
+    if block.header.len() <= EQUIHASH_INPUT_LEN + NONCE_LEN {
+        if validation == HeaderValidation::Always {
+            bail!("The server does not send the full block headers");
+        }
+        return Ok(None);
+    }
+
+    // The hash of the header is computed as it is read
+    let header = BlockHeader::read(&*block.header)?;
+    let hash = header.hash().0;
+    if hash.as_slice() != block.hash.as_slice() {
+        bail!("Block hash differs from the header");
+    }
+    if header.prev_block.0.as_slice() != block.prev_hash.as_slice() {
+        bail!("Previous block hash differs from the header");
+    }
+    if header.time != block.time {
+        bail!("Block time differs from the header");
+    }
+
+    let (n, k) = network.equihash_params();
+    let (input, rest) = block.header.split_at(EQUIHASH_INPUT_LEN);
+    equihash::is_valid_solution(n, k, input, &rest[..NONCE_LEN], &header.solution)
+        .map_err(|e| anyhow!("Invalid Equihash solution: {e}"))?;
+
+    let target =
+        compact_target(header.bits).ok_or(anyhow!("Invalid target bits {:08x}", header.bits))?;
+    if target > network.pow_limit() {
+        bail!("Target bits {:08x} are above the limit", header.bits);
+    }
+    // The hash is a little endian number
+    let mut hash = hash;
+    hash.reverse();
+    if hash > target {
+        bail!("Block hash is above the target");
+    }
+    Ok(Some(header))
+}
+
+/// Whether the header of the block at `height` has the root of the Sapling
+/// tree after the block. From Heartwood, this field commits to the history
+/// of the chain instead
+pub fn has_sapling_root(network: &Network, height: u32) -> bool {
+    let height = BlockHeight::from_u32(height);
+    network.is_nu_active(NetworkUpgrade::Sapling, height)
+        && !network.is_nu_active(NetworkUpgrade::Heartwood, height)
+}
+
+/// Add the note commitment of a Sapling output to the tree
+pub fn append_sapling_cmu(tree: &mut CommitmentTree, cmu: &[u8]) -> Result<()> {
+    let cmu: [u8; 32] = cmu.try_into()?;
+    let node =
+        Option::<Node>::from(Node::from_bytes(cmu)).ok_or(anyhow!("Invalid note commitment"))?;
+    tree.append(node)
+        .map_err(|_| anyhow!("The Sapling tree is full"))
+}
+
+/// Check that the Sapling tree after the block has the root of its header.
+/// It binds the Sapling outputs of the compact block to the header
+pub fn check_sapling_root(header: &BlockHeader, tree: &CommitmentTree) -> Result<()> {
+    if tree.root().to_bytes() != header.final_sapling_root {
+        bail!("Sapling tree root differs from the header");
+    }
+    Ok(())
+}
+
+/// Times and target bits of the last headers, from which the
+/// target of the next block is computed
+#[derive(Default)]
+pub struct DifficultyWindow {
+    headers: VecDeque<(u32, u32)>,
+}
+
+impl DifficultyWindow {
+    /// Whether the targets are checked. Regtest does not adjust them
+    pub fn applies(network: &Network) -> bool {
+        matches!(network, Network::Main)
+    }
+
+    pub fn push(&mut self, time: u32, bits: u32) {
+        if self.headers.len() == DIFFICULTY_WINDOW {
+            self.headers.pop_front();
+        }
+        self.headers.push_back((time, bits));
+    }
+
+    /// Forget the headers, after a block without one
+    pub fn clear(&mut self) {
+        self.headers.clear();
+    }
+
+    /// Check the target bits of the block at `height`, which follows
+    /// the headers. They are not checked before the window is full
+    pub fn check(&self, network: &Network, height: u32, bits: u32) -> Result<()> {
+        if !Self::applies(network) {
+            return Ok(());
+        }
+        if let Some(expected) = self.expected_bits(network, height)? {
+            if bits != expected {
+                bail!("Target bits {bits:08x} should be {expected:08x}");
+            }
+        }
+        Ok(())
+    }
+
+    // GetNextWorkRequired of zcashd
+    fn expected_bits(&self, network: &Network, height: u32) -> Result<Option<u32>> {
+        if self.headers.len() < DIFFICULTY_WINDOW {
+            return Ok(None);
+        }
+        let mut total = [0u64; 4];
+        for (_, bits) in self.headers.range(MEDIAN_TIME_SPAN..) {
+            let target = compact_target(*bits).ok_or(anyhow!("Invalid target bits {bits:08x}"))?;
+            total = add(&total, &from_be_bytes(&target));
+        }
+        let average = div_small(&total, AVERAGING_WINDOW as u64);
+
+        let spacing: i64 =
+            if network.is_nu_active(NetworkUpgrade::Blossom, BlockHeight::from_u32(height)) {
+                75
+            } else {
+                150
+            };
+        let window_timespan = AVERAGING_WINDOW as i64 * spacing;
+        let min_timespan = window_timespan * (100 - MAX_ADJUST_UP) / 100;
+        let max_timespan = window_timespan * (100 + MAX_ADJUST_DOWN) / 100;
+        let first_time = self.median_time(0);
+        let last_time = self.median_time(AVERAGING_WINDOW);
+        let timespan = last_time - first_time;
+        let timespan = window_timespan + (timespan - window_timespan) / DAMPING_FACTOR;
+        let timespan = timespan.clamp(min_timespan, max_timespan);
+
+        let target = div_small(&average, window_timespan as u64);
+        let target = to_be_bytes(&mul_small(&target, timespan as u64));
+        let target = target.min(network.pow_limit());
+        Ok(Some(to_compact(&target)))
+    }
+
+    // Median time past of the block at the end of the span that starts at `start`
+    fn median_time(&self, start: usize) -> i64 {
+        let mut times: Vec<_> = self
+            .headers
+            .range(start..start + MEDIAN_TIME_SPAN)
+            .map(|(time, _)| *time as i64)
+            .collect();
+        times.sort();
+        times[MEDIAN_TIME_SPAN / 2]
+    }
+}
+
+// The targets are 256 bit numbers, stored as little endian 64 bit limbs
+
+fn from_be_bytes(bytes: &[u8; 32]) -> [u64; 4] {
+    let mut limbs = [0u64; 4];
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let end = 32 - 8 * i;
+        *limb = u64::from_be_bytes(bytes[end - 8..end].try_into().unwrap());
+    }
+    limbs
+}
+
+fn to_be_bytes(limbs: &[u64; 4]) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, limb) in limbs.iter().enumerate() {
+        let end = 32 - 8 * i;
+        bytes[end - 8..end].copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn add(a: &[u64; 4], b: &[u64; 4]) -> [u64; 4] {
+    let mut sum = [0u64; 4];
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let s = a[i] as u128 + b[i] as u128 + carry;
+        sum[i] = s as u64;
+        carry = s >> 64;
+    }
+    sum
+}
+
+fn mul_small(a: &[u64; 4], b: u64) -> [u64; 4] {
+    let mut product = [0u64; 4];
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let p = a[i] as u128 * b as u128 + carry;
+        product[i] = p as u64;
+        carry = p >> 64;
+    }
+    product
+}
+
+fn div_small(a: &[u64; 4], b: u64) -> [u64; 4] {
+    let mut quotient = [0u64; 4];
+    let mut remainder = 0u128;
+    for i in (0..4).rev() {
+        let d = (remainder << 64) | a[i] as u128;
+        quotient[i] = (d / b as u128) as u64;
+        remainder = d % b as u128;
+    }
+    quotient
+}
+
+/// Compact `bits` of a big endian target, rounded down
+fn to_compact(target: &[u8; 32]) -> u32 {
+    let zeros = target.iter().take_while(|b| **b == 0).count();
+    let mut size = (32 - zeros) as u32;
+    let mut mantissa = [0u8; 4];
+    for (i, byte) in mantissa[1..].iter_mut().enumerate() {
+        *byte = target.get(zeros + i).copied().unwrap_or_default();
+    }
+    let mut compact = u32::from_be_bytes(mantissa);
+    // The sign bit must stay clear
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | size << 24
+}
+
+/// Target of the compact `bits` of a header, big endian. None if
+/// it is negative, zero or overflows
+fn compact_target(bits: u32) -> Option<[u8; 32]> {
+    if bits & 0x0080_0000 != 0 {
+        return None;
+    }
+    // mantissa * 256^(exponent - 3)
+    let exponent = (bits >> 24) as i32;
+    let mantissa = (bits & 0x007f_ffff).to_be_bytes();
+    let mut target = [0u8; 32];
+    for (i, byte) in mantissa[1..].iter().enumerate() {
+        let position = exponent - 1 - i as i32;
+        if position < 0 {
+            continue;
+        }
+        if position >= 32 {
+            if *byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[31 - position as usize] = *byte;
+    }
+    (target != [0u8; 32]).then_some(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zcash_primitives::block::{BlockHash, BlockHeaderData};
+
+    #[test]
+    fn test_compact_target() {
+        let target = compact_target(0x1d00ffff).unwrap();
+        assert_eq!(
+            hex::encode(target),
+            "00000000ffff0000000000000000000000000000000000000000000000000000"
+        );
+        let target = compact_target(0x02123456).unwrap();
+        assert_eq!(&target[29..], &[0x00, 0x12, 0x34]);
+        assert!(compact_target(0x1f07ffff).unwrap() <= Network::Main.pow_limit());
+        assert!(compact_target(0x2007ffff).unwrap() > Network::Main.pow_limit());
+        assert_eq!(compact_target(0x1d800000), None);
+        assert_eq!(compact_target(0x01003456), None);
+        assert_eq!(compact_target(0x22010000), None);
+    }
+
+    #[test]
+    fn test_check_header() {
+        let network = Network::Regtest;
+        let mut block = CompactBlock {
+            hash: vec![1; 32],
+            prev_hash: vec![2; 32],
+            ..CompactBlock::default()
+        };
+        assert!(check_header(&network, &block, HeaderValidation::WhenPresent).is_ok());
+        assert!(check_header(&network, &block, HeaderValidation::Always).is_err());
+
+        // Truncated before the solution
+        block.header = vec![0; EQUIHASH_INPUT_LEN + NONCE_LEN + 1];
+        block.header[EQUIHASH_INPUT_LEN + NONCE_LEN] = 0xfd;
+        assert!(check_header(&network, &block, HeaderValidation::WhenPresent).is_err());
+
+        // Well formed, but its hash is not the one of the block
+        block.header[EQUIHASH_INPUT_LEN + NONCE_LEN] = 0;
+        assert!(check_header(&network, &block, HeaderValidation::WhenPresent).is_err());
+        let header = BlockHeader::read(&*block.header).unwrap();
+        block.hash = header.hash().0.to_vec();
+        block.prev_hash = vec![0; 32];
+        // The empty solution is invalid
+        assert!(check_header(&network, &block, HeaderValidation::Always)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid Equihash solution"));
+    }
+
+    #[test]
+    fn test_to_compact() {
+        for bits in [0x1d00ffff, 0x1f07ffff, 0x1c085e00, 0x02123400] {
+            assert_eq!(to_compact(&compact_target(bits).unwrap()), bits);
+        }
+        assert_eq!(to_compact(&Network::Main.pow_limit()), 0x1f07ffff);
+        // The sign bit moves the mantissa
+        let mut target = [0u8; 32];
+        target[4] = 0x80;
+        assert_eq!(to_compact(&target), 0x1d008000);
+    }
+
+    #[test]
+    fn test_difficulty_window() {
+        let network = Network::Main;
+        // Before Blossom, when the blocks come 150 seconds apart
+        let height = 600_000;
+        let mut window = DifficultyWindow::default();
+        for i in 0..DIFFICULTY_WINDOW as u32 - 1 {
+            window.push(1_000_000 + i * 150, 0x1c09f600);
+        }
+        assert!(window.check(&network, height, 0x1c000001).is_ok());
+        window.push(1_000_000 + 27 * 150, 0x1c09f600);
+        // The target of 2550 * 256^26 is divided by the timespan of the
+        // window, 2550 seconds, and multiplied by the same timespan
+        assert!(window.check(&network, height, 0x1c09f600).is_ok());
+        assert!(window.check(&network, height, 0x1c09f500).is_err());
+        // After Blossom, the window lasts 1275 seconds and the timespan
+        // of 2550 seconds is damped to 1593
+        assert!(window.check(&network, 700_000, 0x1c0c7200).is_ok());
+
+        // The blocks came all at once: the timespan is damped to 1913
+        // seconds and clamped to 2142, 84% of the window
+        window.clear();
+        for _ in 0..DIFFICULTY_WINDOW {
+            window.push(1_000_000, 0x1c09f600);
+        }
+        assert!(window.check(&network, height, 0x1c085e00).is_ok());
+        assert!(window.check(&network, height, 0x1c09f600).is_err());
+
+        // Regtest does not adjust the targets
+        assert!(window.check(&Network::Regtest, height, 0x200f0f0f).is_ok());
+    }
+
+    #[test]
+    fn test_sapling_root() {
+        let network = Network::Main;
+        assert!(!has_sapling_root(&network, 419_199));
+        assert!(has_sapling_root(&network, 419_200));
+        assert!(has_sapling_root(&network, 902_999));
+        assert!(!has_sapling_root(&network, 903_000));
+
+        let mut tree = CommitmentTree::empty();
+        append_sapling_cmu(&mut tree, &[0; 32]).unwrap();
+        assert!(append_sapling_cmu(&mut tree, &[0xff; 32]).is_err());
+        assert!(append_sapling_cmu(&mut tree, &[0; 31]).is_err());
+
+        let header = |final_sapling_root| {
+            BlockHeaderData {
+                version: 4,
+                prev_block: BlockHash([0; 32]),
+                merkle_root: [0; 32],
+                final_sapling_root,
+                time: 0,
+                bits: 0,
+                nonce: [0; 32],
+                solution: vec![],
+            }
+            .freeze()
+            .unwrap()
+        };
+        assert!(check_sapling_root(&header(tree.root().to_bytes()), &tree).is_ok());
+        let header = header([0; 32]);
+        assert!(check_sapling_root(&header, &tree).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use rocket::serde::{Deserialize, Serialize};
+
+#[derive(Default)]
+struct ScanState {
+    last_success: Option<u64>,
+    last_error: Option<String>,
+}
+
+/// Outcome of the latest scans, shared by the monitor task and the API
+#[derive(Clone, Default)]
+pub struct ScanStatus(Arc<Mutex<ScanState>>);
+
+impl ScanStatus {
+    pub fn record(&self, result: &Result<()>) {
+        let mut state = self.0.lock().unwrap();
+        match result {
+            Ok(()) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                state.last_success = Some(now);
+                state.last_error = None;
+            }
+            Err(e) => state.last_error = Some(e.to_string()),
+        }
+    }
+
+    pub fn last_success(&self) -> Option<u64> {
+        self.0.lock().unwrap().last_success
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.0.lock().unwrap().last_error.clone()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HealthReport {
+    pub synced_height: u32,
+    pub chain_tip_height: Option<u32>,
+    pub lag_blocks: Option<u32>,
+    pub lag_seconds: Option<u32>,
+    pub last_scan_time: Option<u64>,
+    pub last_error: Option<String>,
+    pub lwd_error: Option<String>,
+    pub db_writable: bool,
+    pub ready: bool,
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use zcash_keys::address::UnifiedAddress;
+use zcash_keys::encoding::decode_extended_full_viewing_key;
+use zcash_keys::keys::{UnifiedAddressRequest, UnifiedFullViewingKey, UnifiedIncomingViewingKey};
+use zcash_protocol::consensus::{NetworkConstants, Parameters};
+use zip32::DiversifierIndex;
+
+use crate::network::Network;
+
+/// Viewing key of a wallet. A full viewing key detects the notes
+/// received and spent, an incoming viewing key only the notes received
+#[derive(Clone, Debug)]
+pub enum ViewingKey {
+    Full(UnifiedFullViewingKey),
+    Incoming(UnifiedIncomingViewingKey),
+}
+
+impl ViewingKey {
+    /// Parse a unified full viewing key, a unified incoming viewing key
+    /// or a legacy Sapling extended full viewing key (zxviews).
+    /// The latter becomes a unified full viewing key with only Sapling
+    pub fn decode(network: &Network, vk: &str) -> Result<Self> {
+        if let Ok(ufvk) = UnifiedFullViewingKey::decode(network, vk) {
+            return Ok(ViewingKey::Full(ufvk));
+        }
+        if let Ok(uivk) = UnifiedIncomingViewingKey::decode(network, vk) {
+            return Ok(ViewingKey::Incoming(uivk));
+        }
+        let hrp = network
+            .network_type()
+            .hrp_sapling_extended_full_viewing_key();
+        if let Ok(extfvk) = decode_extended_full_viewing_key(hrp, vk) {
+            let ufvk = UnifiedFullViewingKey::from_sapling_extended_full_viewing_key(extfvk)
+                .map_err(|e| anyhow!("Invalid Sapling Viewing Key: {e:?}"))?;
+            return Ok(ViewingKey::Full(ufvk));
+        }
+        Err(anyhow!(
+            "Invalid Viewing Key, expected a UFVK, a UIVK or a Sapling extended full viewing key"
+        ))
+    }
+
+    pub fn encode(&self, network: &Network) -> String {
+        match self {
+            ViewingKey::Full(ufvk) => ufvk.encode(network),
+            ViewingKey::Incoming(uivk) => uivk.encode(network),
+        }
+    }
+
+    /// Spends can only be detected with the nullifier keys of a full viewing key
+    pub fn tracks_spends(&self) -> bool {
+        matches!(self, ViewingKey::Full(_))
+    }
+
+    pub fn to_uivk(&self) -> UnifiedIncomingViewingKey {
+        match self {
+            ViewingKey::Full(ufvk) => ufvk.to_unified_incoming_viewing_key(),
+            ViewingKey::Incoming(uivk) => uivk.clone(),
+        }
+    }
+
+    pub fn ufvk(&self) -> Option<&UnifiedFullViewingKey> {
+        match self {
+            ViewingKey::Full(ufvk) => Some(ufvk),
+            ViewingKey::Incoming(_) => None,
+        }
+    }
+
+    /// First valid address at or after the diversifier index `di`
+    pub fn find_address(&self, di: u64) -> Result<(UnifiedAddress, DiversifierIndex)> {
+        let address = self
+            .to_uivk()
+            .find_address(di.into(), UnifiedAddressRequest::AllAvailableKeys)?;
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sapling_crypto::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+    use zcash_keys::encoding::encode_extended_full_viewing_key;
+
+    const REGTEST_FVK: &str = "uviewregtest10lkfv9ck80w7hc50x02fkzwl004glax8gtyg6n3edgy5ld34xvutln5zwlezpmtadv9v2jge0damef7egg8tk93xncq73k0fdzpfrecpzmres8ucz82m8h9ephp53vasten7xrf95h9egdhyg2fqu2qz3hgyy0k6tny6d28m5duuzk72ma0nfr2y5cxqwjscspsdm5qkaafc9edtpzapmfxgzcdkqr60atx32g6q8fxhhh9n0hueslvzy04xyx5353nmmxx2k7uxwdv6t9y626f0d03lgufgkct3gkyxp4u24xdz9l5jsa5ne8cw9s5cjqernqj7xqmwzuc7lad6c7ayqk2ry3e66qea5pmq32a9v4spfswmtsvklljmd0fc4pk8f32g7snzxyrlmnkguch3execr9kqx02a6dc2ryuzvrg8vrrfxjkve6tpyk4vfz9j2zkuws9g5e06wm744yzsye3w74qwjrn5t2rzqfn6zmr8fgkjea8c";
+
+    #[test]
+    fn test_decode_viewing_keys() -> Result<()> {
+        let network = Network::Regtest;
+        let vk = ViewingKey::decode(&network, REGTEST_FVK)?;
+        assert!(vk.tracks_spends());
+        assert_eq!(vk.encode(&network), REGTEST_FVK);
+
+        let uivk = vk.to_uivk().encode(&network);
+        let ivk = ViewingKey::decode(&network, &uivk)?;
+        assert!(!ivk.tracks_spends());
+        assert_eq!(ivk.encode(&network), uivk);
+        // Both keys give the same addresses
+        let (ua, di) = vk.find_address(0)?;
+        let (ua2, di2) = ivk.find_address(0)?;
+        assert_eq!(ua.encode(&network), ua2.encode(&network));
+        assert_eq!(di, di2);
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[7; 32]));
+        let hrp = network
+            .network_type()
+            .hrp_sapling_extended_full_viewing_key();
+        let zxviews = encode_extended_full_viewing_key(hrp, &extfvk);
+        let vk = ViewingKey::decode(&network, &zxviews)?;
+        assert!(vk.tracks_spends());
+        let ufvk = vk.ufvk().unwrap();
+        assert!(ufvk.sapling().is_some());
+        assert!(ufvk.orchard().is_none());
+
+        assert!(ViewingKey::decode(&network, "zxviews1invalid").is_err());
+        Ok(())
+    }
+}
@@ -5,17 +5,28 @@ extern crate rocket;
 pub mod lwd_rpc;
 
 mod account;
+mod address;
+mod auth;
 mod db;
+mod header;
+mod health;
+mod keys;
+mod memo;
+mod metrics;
 mod monitor;
 mod network;
+mod payment_id;
+mod proxy;
 mod rpc;
 mod scan;
+mod storage;
 mod transaction;
 
 pub use crate::rpc::*;
 use anyhow::{anyhow, Result};
 use figment::providers::{Env, Format, Json};
 use network::Network;
+use rocket::config::{MutualTls, TlsConfig};
 use std::path::Path;
 use tonic::transport::Channel;
 use tracing::level_filters::LevelFilter;
@@ -36,6 +47,15 @@ use clap::Parser;
 struct Args {
     #[clap(short, long)]
     rescan: bool,
+    /// Write a snapshot of the database to this file and exit
+    #[clap(long)]
+    backup: Option<String>,
+    /// Restore the database from a snapshot before starting
+    #[clap(long)]
+    restore: Option<String>,
+    /// Delete the wallet data and bind the database to the viewing key of the config
+    #[clap(long)]
+    rotate_key: bool,
 }
 
 // They come from the config file
@@ -47,23 +67,116 @@ struct Args {
 // pub const NOTIFY_TX_URL: &str = "https://localhost:14142/zcashlikedaemoncallback/tx?cryptoCode=yec&hash=";
 
 use crate::{
-    db::Db, lwd_rpc::compact_tx_streamer_client::CompactTxStreamerClient, monitor::monitor_task,
+    auth::{ApiAuth, ApiKey, AuthMode},
+    db::Db,
+    header::HeaderValidation,
+    health::ScanStatus,
+    keys::ViewingKey,
+    lwd_rpc::compact_tx_streamer_client::CompactTxStreamerClient,
+    monitor::{catch_up_tasks, memo_task, monitor_task},
+    payment_id::{PaymentIdConfig, PaymentIdParser},
+    proxy::{connect_lwd, ProxyConfig},
+    scan::MemoPrivacy,
 };
 use serde::Deserialize;
-use zcash_client_backend::keys::UnifiedFullViewingKey;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 pub struct WalletConfig {
-    port: u16,
     db_path: String,
+    postgres_url: Option<String>,
+    /// Directory of the files of `/backup` and `/restore`
+    backup_dir: Option<String>,
     confirmations: u32,
     lwd_url: String,
     notify_tx_url: String,
     poll_interval: u16,
+    /// Blocks behind the chain tip before `/ready` fails
+    #[serde(default = "default_max_sync_lag")]
+    max_sync_lag: u32,
     regtest: bool,
     orchard: bool,
     vk: String,
     birth_height: u32,
+    /// How to find the payment id in the memos
+    payment_id: Option<PaymentIdConfig>,
+    /// Full transactions fetched at the same time for their memos
+    memo_fetch_concurrency: Option<usize>,
+    #[serde(default)]
+    memo_privacy: MemoPrivacy,
+    /// Server of the full transactions, if not the one of the compact blocks
+    memo_lwd_url: Option<String>,
+    proxy: Option<ProxyConfig>,
+    /// Whether the blocks must come with their full headers, by default they must
+    #[serde(default)]
+    header_validation: HeaderValidation,
+    /// Other wallets served with the one of `vk`, which is wallet 0
+    #[serde(default)]
+    wallets: Vec<WalletKeyConfig>,
+    #[serde(default)]
+    api_keys: Vec<ApiKey>,
+    /// `disabled` to serve the REST API without API keys
+    #[serde(default)]
+    auth: AuthMode,
+    #[serde(default)]
+    digest_auth: bool,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_client_ca: Option<String>,
+}
+
+fn default_max_sync_lag() -> u32 {
+    10
+}
+
+// Keep the password of the database and the viewing keys out of the logs
+impl std::fmt::Debug for WalletConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalletConfig")
+            .field("db_path", &self.db_path)
+            .field("postgres", &self.postgres_url.is_some())
+            .field("backup_dir", &self.backup_dir)
+            .field("confirmations", &self.confirmations)
+            .field("lwd_url", &self.lwd_url)
+            .field("notify_tx_url", &self.notify_tx_url)
+            .field("poll_interval", &self.poll_interval)
+            .field("max_sync_lag", &self.max_sync_lag)
+            .field("regtest", &self.regtest)
+            .field("orchard", &self.orchard)
+            .field("birth_height", &self.birth_height)
+            .field("payment_id", &self.payment_id)
+            .field("memo_fetch_concurrency", &self.memo_fetch_concurrency)
+            .field("memo_privacy", &self.memo_privacy)
+            .field("memo_lwd_url", &self.memo_lwd_url)
+            .field("proxy", &self.proxy)
+            .field("header_validation", &self.header_validation)
+            .field("wallets", &self.wallets)
+            .field("api_keys", &self.api_keys)
+            .field("auth", &self.auth)
+            .field("digest_auth", &self.digest_auth)
+            .field("tls_cert", &self.tls_cert)
+            .field("tls_key", &self.tls_key)
+            .field("tls_client_ca", &self.tls_client_ca)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WalletKeyConfig {
+    id: u32,
+    #[serde(default)]
+    label: String,
+    vk: String,
+    birth_height: u32,
+}
+
+impl std::fmt::Debug for WalletKeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalletKeyConfig")
+            .field("id", &self.id)
+            .field("label", &self.label)
+            .field("birth_height", &self.birth_height)
+            .finish()
+    }
 }
 
 impl WalletConfig {
@@ -74,10 +187,36 @@ impl WalletConfig {
             Network::Main
         }
     }
+
+    pub fn memo_concurrency(&self) -> usize {
+        self.memo_fetch_concurrency.unwrap_or(4)
+    }
+
+    pub fn memo_lwd_url(&self) -> &str {
+        self.memo_lwd_url.as_deref().unwrap_or(&self.lwd_url)
+    }
+
+    pub fn tls_config(&self) -> Result<Option<TlsConfig>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                let mut tls = TlsConfig::from_paths(cert, key);
+                // Client certificates are required when a CA is given
+                if let Some(ca) = &self.tls_client_ca {
+                    tls = tls.with_mutual(MutualTls::from_path(ca).mandatory(true));
+                }
+                Ok(Some(tls))
+            }
+            (None, None) if self.tls_client_ca.is_none() => Ok(None),
+            _ => Err(anyhow!(
+                "TLS needs both tls_cert and tls_key, and tls_client_ca requires them"
+            )),
+        }
+    }
 }
 
 #[rocket::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     dotenv::dotenv().ok();
     env_logger::init();
     let config_path = dotenv::var("CONFIG_PATH")
@@ -87,49 +226,126 @@ async fn main() -> Result<()> {
         .with(default_layer())
         .with(env_layer())
         .try_init();
-    let rocket = rocket::build();
+    let mut rocket = rocket::build();
     let mut figment = rocket.figment().clone();
     figment = figment.merge(Env::raw());
-    info!("figment {figment:?}");
     let config = Path::new(&config_path);
     if config.exists() {
         figment = figment.merge(Json::file(config_path));
     }
 
     let config: WalletConfig = figment.extract().unwrap();
+    let auth = ApiAuth::new(&config.api_keys, config.digest_auth, config.auth)?;
+    metrics::init();
+    proxy::init(config.proxy.clone());
     info!("Config {config:?}");
+    if let Some(tls) = config.tls_config()? {
+        let figment = rocket.figment().clone().merge(("tls", tls));
+        rocket = rocket.configure(figment);
+    }
     let network = config.network();
     assert!(config.orchard);
 
-    let ufvk = &config.vk;
     let birth_height = config.birth_height;
-    let ufvk = UnifiedFullViewingKey::decode(&network, ufvk)
-        .map_err(|_| anyhow!("Invalid Unified Viewing Key"))?;
-    let db = Db::new(network, &config.db_path, &ufvk, &config.notify_tx_url).await?;
-    let db_exists = db.create().await?;
-    if !db_exists {
-        db.new_account("").await?;
+    let vk = ViewingKey::decode(&network, &config.vk)?;
+    if !vk.tracks_spends() {
+        warn!("VK is an incoming viewing key, spends are not tracked");
+    }
+    if config.postgres_url.is_some() {
+        warn!("Backups need SQLite, /backup and /restore fail with PostgreSQL. Use pg_dump");
+    }
+    let store = storage::connect(&config).await?;
+    let payment_id = config
+        .payment_id
+        .as_ref()
+        .map(PaymentIdParser::new)
+        .transpose()?;
+    let db = Db::new(network, store, &vk, &config.notify_tx_url, payment_id);
+    db.create().await?;
+    if args.rotate_key {
+        db.rotate_key(birth_height).await?;
+    } else {
+        db.check_metadata(birth_height).await?;
+    }
+    let mut client = connect_lwd(&config.lwd_url).await?;
+    register_wallets(&db, &mut client, &config, &vk).await?;
+    db.load_wallets().await?;
+    // The wallets of the config must be known to accept a backup that has them
+    if let Some(path) = &args.restore {
+        db.restore(path).await?;
+        // and they come back if they were added after the backup
+        register_wallets(&db, &mut client, &config, &vk).await?;
+    }
+    if let Some(path) = &args.backup {
+        db.backup(path).await?;
+        return Ok(());
     }
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone()).await?;
-    db.fetch_block_hash(&mut client, birth_height).await?;
+    catch_up_tasks(
+        &db,
+        network,
+        &config.lwd_url,
+        config.header_validation,
+        config.poll_interval,
+    )
+    .await?;
 
-    monitor_task(config.port, config.poll_interval).await;
+    let scan_status = ScanStatus::default();
+    monitor_task(
+        db.clone(),
+        scan_status.clone(),
+        network,
+        config.lwd_url.clone(),
+        config.header_validation,
+        config.poll_interval,
+    )
+    .await;
+    memo_task(
+        db.clone(),
+        network,
+        config.memo_lwd_url().to_string(),
+        config.memo_concurrency(),
+        config.memo_privacy,
+        config.poll_interval,
+    );
     rocket
         .manage(db)
         .manage(config)
+        .manage(auth)
+        .manage(scan_status)
+        .register("/", catchers![auth::unauthorized])
         .mount(
             "/",
             routes![
                 create_account,
                 create_address,
                 get_accounts,
+                get_balance,
+                get_address,
+                get_address_index,
+                validate_address,
+                label_account,
+                label_address,
+                tag_accounts,
+                untag_accounts,
+                set_account_tag_description,
+                get_account_tags,
                 get_transaction,
                 get_transfers,
+                get_payments,
+                get_bulk_payments,
                 get_fee_estimate,
                 get_height,
                 sync_info,
                 request_scan,
                 reorg,
+                get_metrics,
+                get_health,
+                get_ready,
+                backup,
+                restore,
+                import_wallet,
+                get_wallets,
+                remove_wallet,
             ],
         )
         .launch()
@@ -138,6 +354,34 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Add the wallets of the config, or check that they have the same keys as before
+async fn register_wallets(
+    db: &Db,
+    client: &mut Client,
+    config: &WalletConfig,
+    vk: &ViewingKey,
+) -> Result<()> {
+    let network = config.network();
+    db.register_wallet(client, 0, "", vk, config.birth_height)
+        .await?;
+    for wallet in config.wallets.iter() {
+        if wallet.id == 0 {
+            anyhow::bail!("Wallet 0 is the one of vk");
+        }
+        let vk = ViewingKey::decode(&network, &wallet.vk)
+            .map_err(|e| anyhow!("{e} for wallet {}", wallet.id))?;
+        if !vk.tracks_spends() {
+            warn!(
+                "Wallet {} has an incoming viewing key, spends are not tracked",
+                wallet.id
+            );
+        }
+        db.register_wallet(client, wallet.id, &wallet.label, &vk, wallet.birth_height)
+            .await?;
+    }
+    Ok(())
+}
+
 #[allow(dead_code)]
 fn to_tonic<E: ToString>(e: E) -> tonic::Status {
     tonic::Status::internal(e.to_string())
@@ -0,0 +1,90 @@
+use rocket::serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use zcash_keys::address::Address;
+
+use crate::network::Network;
+
+/// Fields of a memo that follows one of the known conventions:
+/// a JSON object like `{"refund": "u1...", "order": "A123"}`,
+/// or a text with a `Reply-To:` line
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct MemoFields {
+    /// Where the payer wants the refunds. Only an address valid
+    /// on our network is kept
+    pub refund_address: Option<String>,
+    pub order: Option<String>,
+    /// All the fields of a JSON memo
+    pub fields: Map<String, Value>,
+}
+
+const REFUND_KEYS: [&str; 3] = ["refund", "reply_to", "reply-to"];
+const REPLY_TO: &str = "reply-to:";
+
+/// None if the memo has nothing we recognize
+pub fn parse_memo(network: &Network, memo: &str) -> Option<MemoFields> {
+    let memo = memo.trim();
+    if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(memo) {
+        let refund_address = REFUND_KEYS
+            .iter()
+            .find_map(|key| fields.get(*key).and_then(Value::as_str))
+            .and_then(|address| valid_address(network, address));
+        let order = fields.get("order").and_then(|order| match order {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        });
+        return Some(MemoFields {
+            refund_address,
+            order,
+            fields,
+        });
+    }
+
+    // The address is after the marker, on the same line or the next one
+    let start = memo.to_ascii_lowercase().find(REPLY_TO)? + REPLY_TO.len();
+    let address = memo[start..].split_whitespace().next()?;
+    Some(MemoFields {
+        refund_address: valid_address(network, address),
+        ..MemoFields::default()
+    })
+}
+
+fn valid_address(network: &Network, address: &str) -> Option<String> {
+    Address::decode(network, address).map(|_| address.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UA: &str = "uregtest1se78asch326c8czsa2wyzzfuytrvlezzjw42rest6nkqu3dzuvf4ua3lxjzf8gc5ygwca5sjdsqnpzcs087hdpgz4msfazfwfsjtr0lrln7dg0729rzp7y2acm2wrjyr5qjc8mj7x03dqh4a6frku9ue8gv3z54xgxev3dg895hepwej";
+    const SAPLING_ADDRESS: &str = "zregtestsapling1qag0mpkwcratr9zweyk973dzukaln3svpl0v8fpydajq8aq8ghsq0ah3my0qc2admygg6xt4snh";
+
+    #[test]
+    fn test_parse_memo() {
+        let network = Network::Regtest;
+        let memo = format!(r#"{{"refund": "{UA}", "order": 42, "note": "thanks"}}"#);
+        let fields = parse_memo(&network, &memo).unwrap();
+        assert_eq!(fields.refund_address.as_deref(), Some(UA));
+        assert_eq!(fields.order.as_deref(), Some("42"));
+        assert_eq!(fields.fields["note"], "thanks");
+
+        let memo = format!("Thanks for the coffee\nReply-To:\n{SAPLING_ADDRESS}");
+        let fields = parse_memo(&network, &memo).unwrap();
+        assert_eq!(fields.refund_address.as_deref(), Some(SAPLING_ADDRESS));
+        assert!(fields.fields.is_empty());
+        let memo = format!("reply-to: {SAPLING_ADDRESS} thanks");
+        let fields = parse_memo(&network, &memo).unwrap();
+        assert_eq!(fields.refund_address.as_deref(), Some(SAPLING_ADDRESS));
+
+        // Addresses of another network are dropped
+        let fields = parse_memo(&Network::Main, &memo).unwrap();
+        assert_eq!(fields.refund_address, None);
+        let fields = parse_memo(&network, r#"{"reply_to": "zs1invalid"}"#).unwrap();
+        assert_eq!(fields.refund_address, None);
+
+        assert_eq!(parse_memo(&network, "Thanks"), None);
+        assert_eq!(parse_memo(&network, "Reply-To:"), None);
+        assert_eq!(parse_memo(&network, "[1, 2]"), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::future::Future;
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use prometheus::{
+    register_gauge, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Gauge, Histogram, HistogramTimer,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+pub static SYNCED_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("walletd_synced_height", "Height of the last scanned block").unwrap()
+});
+
+pub static CHAIN_TIP_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "walletd_chain_tip_height",
+        "Height of the chain tip reported by lightwalletd"
+    )
+    .unwrap()
+});
+
+pub static SCAN_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "walletd_scan_duration_seconds",
+        "Duration of a scan from the synced height to the chain tip",
+        vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0]
+    )
+    .unwrap()
+});
+
+pub static SCAN_BLOCKS_PER_SECOND: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "walletd_scan_blocks_per_second",
+        "Scan speed of the last scan"
+    )
+    .unwrap()
+});
+
+pub static BLOCKS_SCANNED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("walletd_blocks_scanned_total", "Number of blocks scanned").unwrap()
+});
+
+pub static NOTES_DECRYPTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_notes_decrypted_total",
+        "Number of received notes decrypted",
+        &["pool"]
+    )
+    .unwrap()
+});
+
+pub static REORGS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "walletd_reorgs_total",
+        "Number of chain reorganizations handled"
+    )
+    .unwrap()
+});
+
+pub static MEMO_FETCH_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "walletd_memo_fetch_failures_total",
+        "Number of failed fetches of the full transactions for their memos"
+    )
+    .unwrap()
+});
+
+pub static NOTIFICATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_notifications_total",
+        "Number of new transaction notifications sent",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static LWD_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "walletd_lwd_request_duration_seconds",
+        "Latency of the lightwalletd RPC calls",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static LWD_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_lwd_errors_total",
+        "Number of failed lightwalletd RPC calls",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "walletd_db_query_duration_seconds",
+        "Duration of the database operations",
+        &["query"]
+    )
+    .unwrap()
+});
+
+/// Register all the metrics so that they are exported before
+/// being updated for the first time
+pub fn init() {
+    LazyLock::force(&SYNCED_HEIGHT);
+    LazyLock::force(&CHAIN_TIP_HEIGHT);
+    LazyLock::force(&SCAN_DURATION);
+    LazyLock::force(&SCAN_BLOCKS_PER_SECOND);
+    LazyLock::force(&BLOCKS_SCANNED);
+    LazyLock::force(&NOTES_DECRYPTED);
+    LazyLock::force(&REORGS);
+    LazyLock::force(&MEMO_FETCH_FAILURES);
+    LazyLock::force(&NOTIFICATIONS);
+    LazyLock::force(&LWD_REQUEST_DURATION);
+    LazyLock::force(&LWD_ERRORS);
+    LazyLock::force(&DB_QUERY_DURATION);
+}
+
+pub fn pool_label(pool: u8) -> &'static str {
+    match pool {
+        0 => "transparent",
+        1 => "sapling",
+        2 => "orchard",
+        _ => "unknown",
+    }
+}
+
+/// Time a lightwalletd call and count its failures
+pub async fn observe_lwd<T, E, F>(method: &str, call: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let timer = LWD_REQUEST_DURATION
+        .with_label_values(&[method])
+        .start_timer();
+    let res = call.await;
+    timer.observe_duration();
+    if res.is_err() {
+        LWD_ERRORS.with_label_values(&[method]).inc();
+    }
+    res
+}
+
+/// Times a database operation until the returned timer is dropped
+pub fn db_timer(query: &str) -> HistogramTimer {
+    DB_QUERY_DURATION.with_label_values(&[query]).start_timer()
+}
+
+pub fn encode() -> Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
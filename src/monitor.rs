@@ -1,17 +1,82 @@
 use std::time::Duration;
 
-#[allow(unreachable_code)]
-pub async fn monitor_task(port: u16, poll_interval: u16) {
+use anyhow::Result;
+
+use crate::{
+    catch_up_wallet, db::Db, fetch_memos, header::HeaderValidation, health::ScanStatus,
+    network::Network, scan::MemoPrivacy, scan_to_tip,
+};
+
+pub async fn monitor_task(
+    db: Db,
+    status: ScanStatus,
+    network: Network,
+    lwd_url: String,
+    headers: HeaderValidation,
+    poll_interval: u16,
+) {
     tokio::spawn(async move {
         loop {
-            let client = reqwest::Client::new();
-            client
-                .post(format!("http://localhost:{port}/request_scan",))
-                .send()
-                .await?;
+            // Scan in process rather than through the REST API so that
+            // it does not depend on the listener's TLS and auth settings
+            if let Err(e) = scan_to_tip(&db, &status, &network, &lwd_url, headers).await {
+                log::warn!("Scan failed: {e}");
+            }
 
             tokio::time::sleep(Duration::from_secs(poll_interval as u64)).await;
         }
-        Ok::<_, anyhow::Error>(())
+    });
+}
+
+/// Start the catch-up scan of the wallets that are behind the others,
+/// unless it is already running. It retries until the wallet has caught up
+pub async fn catch_up_tasks(
+    db: &Db,
+    network: Network,
+    lwd_url: &str,
+    headers: HeaderValidation,
+    poll_interval: u16,
+) -> Result<()> {
+    for wallet in db.get_wallets().await? {
+        if wallet.scan_height.is_none() || !db.start_catch_up(wallet.id) {
+            continue;
+        }
+        let db = db.clone();
+        let lwd_url = lwd_url.to_string();
+        tokio::spawn(async move {
+            while let Err(e) = catch_up_wallet(&db, &network, &lwd_url, headers, wallet.id).await {
+                log::warn!("Catch up of wallet {} failed: {e}", wallet.id);
+                tokio::time::sleep(Duration::from_secs(poll_interval as u64)).await;
+            }
+            db.end_catch_up(wallet.id);
+        });
+    }
+    Ok(())
+}
+
+/// Fetch the memos of the transactions found by the scans. It runs apart
+/// from them so that the sync never waits for the full transactions
+pub fn memo_task(
+    db: Db,
+    network: Network,
+    lwd_url: String,
+    concurrency: usize,
+    privacy: MemoPrivacy,
+    poll_interval: u16,
+) {
+    tokio::spawn(async move {
+        loop {
+            match fetch_memos(&db, &network, &lwd_url, concurrency, privacy).await {
+                // More may be due
+                Ok(count) if count > 0 => continue,
+                Ok(_) => {}
+                Err(e) => log::warn!("Memo fetch failed: {e}"),
+            }
+            // Retries are due after a poll interval at the earliest
+            tokio::select! {
+                _ = db.memo_queued() => {}
+                _ = tokio::time::sleep(Duration::from_secs(poll_interval as u64)) => {}
+            }
+        }
     });
 }
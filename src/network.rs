@@ -1,3 +1,4 @@
+use sapling_crypto::note_encryption::Zip212Enforcement;
 use zcash_protocol::{
     consensus::{BlockHeight, MainNetwork, NetworkUpgrade, Parameters},
     local_consensus::LocalNetwork,
@@ -9,6 +10,50 @@ pub enum Network {
     Regtest,
 }
 
+// Blocks after Canopy during which both note plaintext versions are valid
+const ZIP212_GRACE_PERIOD: u32 = 32256;
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Main => "main",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// Note plaintext versions accepted in the block at `height` (ZIP-212)
+    pub fn zip212_enforcement(&self, height: u32) -> Zip212Enforcement {
+        match self.activation_height(NetworkUpgrade::Canopy) {
+            Some(canopy) if height >= u32::from(canopy) + ZIP212_GRACE_PERIOD => {
+                Zip212Enforcement::On
+            }
+            Some(canopy) if height >= u32::from(canopy) => Zip212Enforcement::GracePeriod,
+            _ => Zip212Enforcement::Off,
+        }
+    }
+
+    /// Parameters (n, k) of the Equihash proof of work
+    pub fn equihash_params(&self) -> (u32, u32) {
+        match self {
+            Network::Main => (200, 9),
+            Network::Regtest => (48, 5),
+        }
+    }
+
+    /// Highest target of the proof of work, big endian
+    pub fn pow_limit(&self) -> [u8; 32] {
+        match self {
+            Network::Main => {
+                let mut limit = [0xff; 32];
+                limit[0] = 0x00;
+                limit[1] = 0x07;
+                limit
+            }
+            Network::Regtest => [0x0f; 32],
+        }
+    }
+}
+
 impl Parameters for Network {
     fn network_type(&self) -> zcash_protocol::consensus::NetworkType {
         match self {
@@ -0,0 +1,92 @@
+use anyhow::Result;
+use regex::Regex;
+use rocket::serde::Deserialize;
+
+/// Convention used by the payers to put a payment id in their memos.
+/// In the config, `payment_id` is one of
+/// `{"prefix": "order:"}`, `{"regex": "INV-([0-9]+)"}` or `{"json_field": "order"}`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentIdConfig {
+    /// The word that follows the prefix
+    Prefix(String),
+    /// The first capture group of the regex, or the whole match
+    /// if it has no group
+    Regex(String),
+    /// A string or number field of a memo that is a JSON object
+    JsonField(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum PaymentIdParser {
+    Prefix(String),
+    Regex(Regex),
+    JsonField(String),
+}
+
+impl PaymentIdParser {
+    pub fn new(config: &PaymentIdConfig) -> Result<Self> {
+        let parser = match config {
+            PaymentIdConfig::Prefix(prefix) => PaymentIdParser::Prefix(prefix.clone()),
+            PaymentIdConfig::Regex(regex) => PaymentIdParser::Regex(Regex::new(regex)?),
+            PaymentIdConfig::JsonField(field) => PaymentIdParser::JsonField(field.clone()),
+        };
+        Ok(parser)
+    }
+
+    /// Payment id of a text memo, if it follows the convention
+    pub fn parse(&self, memo: &str) -> Option<String> {
+        let payment_id = match self {
+            PaymentIdParser::Prefix(prefix) => {
+                let (_, rest) = memo.split_once(prefix.as_str())?;
+                rest.split_whitespace().next()?.to_string()
+            }
+            PaymentIdParser::Regex(regex) => {
+                let captures = regex.captures(memo)?;
+                captures.get(1).or(captures.get(0))?.as_str().to_string()
+            }
+            PaymentIdParser::JsonField(field) => {
+                let value: serde_json::Value = serde_json::from_str(memo.trim()).ok()?;
+                match value.get(field)? {
+                    serde_json::Value::String(s) => s.clone(),
+                    serde_json::Value::Number(n) => n.to_string(),
+                    _ => return None,
+                }
+            }
+        };
+        (!payment_id.is_empty()).then_some(payment_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser(config: &str) -> PaymentIdParser {
+        let config: PaymentIdConfig = serde_json::from_str(config).unwrap();
+        PaymentIdParser::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_parse_payment_id() {
+        let prefix = parser(r#"{"prefix": "order:"}"#);
+        assert_eq!(prefix.parse("order:A123 thanks"), Some("A123".to_string()));
+        assert_eq!(prefix.parse("Thanks! order:A123"), Some("A123".to_string()));
+        assert_eq!(prefix.parse("order: "), None);
+        assert_eq!(prefix.parse("A123"), None);
+
+        let regex = parser(r#"{"regex": "INV-([0-9]+)"}"#);
+        assert_eq!(regex.parse("Invoice INV-0042"), Some("0042".to_string()));
+        assert_eq!(regex.parse("INV-"), None);
+        let regex = parser(r#"{"regex": "[A-Z]{3}[0-9]{4}"}"#);
+        assert_eq!(regex.parse("ref ABC1234"), Some("ABC1234".to_string()));
+
+        let json = parser(r#"{"json_field": "order"}"#);
+        assert_eq!(json.parse(r#"{"order": "A123"}"#), Some("A123".to_string()));
+        assert_eq!(json.parse(r#" {"order": 42} "#), Some("42".to_string()));
+        assert_eq!(json.parse(r#"{"order": null}"#), None);
+        assert_eq!(json.parse("order A123"), None);
+
+        assert!(PaymentIdParser::new(&PaymentIdConfig::Regex("(".to_string())).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, bail, Result};
+use rocket::serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tonic::transport::{Endpoint, Uri};
+use tower_service::Service;
+
+use crate::lwd_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::Client;
+
+/// SOCKS5 proxy of the connections to lightwalletd and of the
+/// notifications, e.g. Tor
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProxyConfig {
+    /// Address of the proxy, e.g. 127.0.0.1:9050
+    pub address: String,
+    /// Connect with new credentials every time, so that Tor
+    /// uses another circuit for each connection
+    #[serde(default)]
+    pub isolation: bool,
+}
+
+static PROXY: OnceLock<Option<ProxyConfig>> = OnceLock::new();
+
+/// Set the proxy from the config, before any connection
+pub fn init(proxy: Option<ProxyConfig>) {
+    PROXY.set(proxy).expect("Proxy already set");
+}
+
+fn proxy() -> Option<&'static ProxyConfig> {
+    PROXY.get().and_then(Option::as_ref)
+}
+
+/// Connect to a lightwalletd server, through the proxy if there is one
+pub async fn connect_lwd(url: &str) -> Result<Client> {
+    let endpoint = Endpoint::new(url.to_string())?;
+    let channel = match proxy() {
+        Some(proxy) => {
+            let connector = SocksConnector {
+                proxy: proxy.clone(),
+            };
+            endpoint.connect_with_connector(connector).await?
+        }
+        None => {
+            if is_onion(url)? {
+                bail!("{url} is an onion service, it needs a SOCKS5 proxy");
+            }
+            endpoint.connect().await?
+        }
+    };
+    Ok(CompactTxStreamerClient::new(channel))
+}
+
+/// Route the requests of an HTTP client through the proxy, if there is one
+pub fn http_proxy(builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+    let Some(proxy) = proxy() else {
+        return Ok(builder);
+    };
+    // socks5h: the proxy resolves the host names, onion services included
+    let url = if proxy.isolation {
+        let credentials = isolation_credentials();
+        format!("socks5h://{credentials}:{credentials}@{}", proxy.address)
+    } else {
+        format!("socks5h://{}", proxy.address)
+    };
+    Ok(builder.proxy(reqwest::Proxy::all(url)?))
+}
+
+#[derive(Clone)]
+struct SocksConnector {
+    proxy: ProxyConfig,
+}
+
+impl Service<Uri> for SocksConnector {
+    type Response = Socks5Stream<TcpStream>;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let proxy = self.proxy.clone();
+        Box::pin(async move {
+            // The host name goes to the proxy unresolved
+            let (host, port) = target(&uri)?;
+            let target = (host.as_str(), port);
+            let stream = if proxy.isolation {
+                let credentials = isolation_credentials();
+                Socks5Stream::connect_with_password(
+                    proxy.address.as_str(),
+                    target,
+                    &credentials,
+                    &credentials,
+                )
+                .await?
+            } else {
+                Socks5Stream::connect(proxy.address.as_str(), target).await?
+            };
+            Ok(stream)
+        })
+    }
+}
+
+fn target(uri: &Uri) -> Result<(String, u16)> {
+    let host = uri.host().ok_or(anyhow!("No host in {uri}"))?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("https")) => 443,
+        (None, _) => 80,
+    };
+    Ok((host.to_string(), port))
+}
+
+fn is_onion(url: &str) -> Result<bool> {
+    let uri: Uri = url.parse()?;
+    Ok(uri.host().is_some_and(|host| host.ends_with(".onion")))
+}
+
+// Tor isolates the streams that have different SOCKS credentials
+fn isolation_credentials() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() -> Result<()> {
+        let onion = "http://lwd2zsjcklwgsjjrxcnncqxwd7bdzdhvzwn6psm7bgrabjtvwdgfyqd.onion:9067";
+        assert!(is_onion(onion)?);
+        assert!(!is_onion("https://zec.rocks")?);
+        assert_eq!(
+            target(&onion.parse()?)?,
+            (
+                "lwd2zsjcklwgsjjrxcnncqxwd7bdzdhvzwn6psm7bgrabjtvwdgfyqd.onion".to_string(),
+                9067
+            )
+        );
+        assert_eq!(
+            target(&"https://zec.rocks".parse()?)?,
+            ("zec.rocks".to_string(), 443)
+        );
+        assert_eq!(isolation_credentials().len(), 32);
+        assert_ne!(isolation_credentials(), isolation_credentials());
+        Ok(())
+    }
+}
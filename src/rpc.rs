@@ -1,18 +1,35 @@
-use crate::account::AccountBalance;
-use crate::db::Db;
-use crate::lwd_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::account::{AccountBalance, AccountTag, AddressInfo, SubAccountBalance};
+use crate::address::AddressValidation;
+use crate::auth::{AdminScope, ApiAuth, ApiError, Authorized, InvoiceScope, ReadOnlyScope};
+use crate::db::{fingerprint, Db};
+use crate::header::HeaderValidation;
+use crate::health::{HealthReport, ScanStatus};
+use crate::keys::ViewingKey;
 use crate::lwd_rpc::*;
-use crate::scan::{get_latest_height, Decoder, Orchard, Sapling, ScanError};
-use crate::transaction::Transfer;
-use crate::{from_tonic, WalletConfig};
+use crate::metrics::{self, observe_lwd};
+use crate::monitor::catch_up_tasks;
+use crate::network::Network;
+use crate::proxy::{connect_lwd, http_proxy};
+use crate::scan::{
+    get_block_hash, get_latest_height, make_decoders, scan_tx, MemoNote, MemoPrivacy, ScanError,
+    ScanEvent, WalletTx,
+};
+use crate::storage::{backup_path, SnapshotInfo};
+use crate::transaction::{MemoEncoding, SubAddress, Transfer, TransferFilter};
+use crate::{from_tonic, register_wallets, WalletConfig};
 use anyhow::Result;
-use rocket::response::Debug;
+use rocket::http::{ContentType, Status};
 use rocket::serde::{json::Json, Deserialize, Serialize};
 use rocket::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task::JoinSet;
 use tonic::Request;
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateAccountRequest {
+    #[serde(default)]
+    wallet_id: u32,
     label: Option<String>,
 }
 
@@ -26,11 +43,13 @@ pub struct CreateAccountResponse {
 pub async fn create_account(
     request: Json<CreateAccountRequest>,
     db: &State<Db>,
-) -> Result<Json<CreateAccountResponse>, Debug<anyhow::Error>> {
+    auth: Authorized<InvoiceScope>,
+) -> Result<Json<CreateAccountResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
     let request = request.into_inner();
     let name = request.label.unwrap_or("".to_string());
 
-    let account = db.new_account(&name).await?;
+    let account = db.new_account(request.wallet_id, &name).await?;
     let rep = CreateAccountResponse {
         account_index: account.account_index,
         address: account.address,
@@ -40,6 +59,8 @@ pub async fn create_account(
 }
 #[derive(Serialize, Deserialize)]
 pub struct CreateAddressRequest {
+    #[serde(default)]
+    wallet_id: u32,
     account_index: u32,
     label: Option<String>,
 }
@@ -54,10 +75,14 @@ pub struct CreateAddressResponse {
 pub async fn create_address(
     request: Json<CreateAddressRequest>,
     db: &State<Db>,
-) -> Result<Json<CreateAddressResponse>, Debug<anyhow::Error>> {
+    auth: Authorized<InvoiceScope>,
+) -> Result<Json<CreateAddressResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
     let request = request.into_inner();
     let name = request.label.unwrap_or("".to_string());
-    let sub_account = db.new_sub_account(request.account_index, &name).await?;
+    let sub_account = db
+        .new_sub_account(request.wallet_id, request.account_index, &name)
+        .await?;
 
     let rep = CreateAddressResponse {
         address: sub_account.address.clone(),
@@ -67,6 +92,8 @@ pub async fn create_address(
 }
 #[derive(Serialize, Deserialize)]
 pub struct GetAccountsRequest {
+    #[serde(default)]
+    wallet_id: u32,
     tag: Option<String>,
 }
 
@@ -77,17 +104,25 @@ pub struct GetAccountsResponse {
     total_unlocked_balance: u64,
 }
 
-#[post("/get_accounts", data = "<_request>")]
+#[post("/get_accounts", data = "<request>")]
 pub async fn get_accounts(
-    _request: Json<GetAccountsRequest>,
+    request: Json<GetAccountsRequest>,
     db: &State<Db>,
     config: &State<WalletConfig>,
-) -> Result<Json<GetAccountsResponse>, Debug<anyhow::Error>> {
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone())
-        .await
-        .map_err(from_tonic)?;
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetAccountsResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    let mut client = connect_lwd(&config.lwd_url).await?;
     let latest_height = get_latest_height(&mut client).await?;
-    let sub_accounts = db.get_accounts(latest_height, config.confirmations).await?;
+    let sub_accounts = db
+        .get_accounts(
+            request.wallet_id,
+            latest_height,
+            config.confirmations,
+            request.tag.as_deref().filter(|tag| !tag.is_empty()),
+        )
+        .await?;
     let total_balance: u64 = sub_accounts.iter().map(|sa| sa.balance).sum();
     let total_unlocked_balance: u64 = sub_accounts.iter().map(|sa| sa.unlocked_balance).sum();
 
@@ -99,10 +134,335 @@ pub async fn get_accounts(
     Ok(Json(rep))
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct GetAddressRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    account_index: u32,
+    #[serde(default)]
+    address_indices: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAddressResponse {
+    address: String,
+    addresses: Vec<AddressInfo>,
+}
+
+#[post("/get_address", data = "<request>")]
+pub async fn get_address(
+    request: Json<GetAddressRequest>,
+    db: &State<Db>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetAddressResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    let account_index = request.account_index;
+    let base = db
+        .get_addresses(request.wallet_id, account_index, &[0])
+        .await?;
+    let base = base
+        .into_iter()
+        .next()
+        .ok_or(anyhow::anyhow!("Unknown account index {account_index}"))?;
+    let addresses = db
+        .get_addresses(
+            request.wallet_id,
+            request.account_index,
+            &request.address_indices,
+        )
+        .await?;
+    let rep = GetAddressResponse {
+        address: base.address,
+        addresses,
+    };
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAddressIndexRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    address: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAddressIndexResponse {
+    index: SubAddress,
+}
+
+#[post("/get_address_index", data = "<request>")]
+pub async fn get_address_index(
+    request: Json<GetAddressIndexRequest>,
+    db: &State<Db>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetAddressIndexResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    let index = db
+        .get_address_index(request.wallet_id, &request.address)
+        .await?
+        .ok_or(anyhow::anyhow!("Address doesn't belong to the wallet"))?;
+    Ok(Json(GetAddressIndexResponse { index }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ValidateAddressRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    address: String,
+}
+
+#[post("/validate_address", data = "<request>")]
+pub async fn validate_address(
+    request: Json<ValidateAddressRequest>,
+    db: &State<Db>,
+    config: &State<WalletConfig>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<AddressValidation>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    let vk = db.viewing_key(request.wallet_id)?;
+    let mut rep = crate::address::validate_address(&config.network(), &vk, &request.address);
+    // A transparent address is ours only if we issued it
+    if rep.valid && rep.receivers.sapling.is_none() && rep.receivers.orchard.is_none() {
+        if let Some(transparent) = &rep.receivers.transparent {
+            rep.owned = db
+                .get_address_index(request.wallet_id, transparent)
+                .await?
+                .is_some();
+        }
+    }
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LabelAccountRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    account_index: u32,
+    label: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LabelAccountResponse {}
+
+#[post("/label_account", data = "<request>")]
+pub async fn label_account(
+    request: Json<LabelAccountRequest>,
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<LabelAccountResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    db.set_address_label(request.wallet_id, request.account_index, 0, &request.label)
+        .await?;
+    Ok(Json(LabelAccountResponse {}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LabelAddressRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    index: SubAddress,
+    label: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LabelAddressResponse {}
+
+#[post("/label_address", data = "<request>")]
+pub async fn label_address(
+    request: Json<LabelAddressRequest>,
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<LabelAddressResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    db.set_address_label(
+        request.wallet_id,
+        request.index.major,
+        request.index.minor,
+        &request.label,
+    )
+    .await?;
+    Ok(Json(LabelAddressResponse {}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TagAccountsRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    tag: String,
+    accounts: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TagAccountsResponse {}
+
+#[post("/tag_accounts", data = "<request>")]
+pub async fn tag_accounts(
+    request: Json<TagAccountsRequest>,
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<TagAccountsResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    if request.tag.is_empty() {
+        return Err(anyhow::anyhow!("Tag must not be empty").into());
+    }
+    db.tag_accounts(request.wallet_id, &request.tag, &request.accounts)
+        .await?;
+    Ok(Json(TagAccountsResponse {}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UntagAccountsRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    accounts: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UntagAccountsResponse {}
+
+#[post("/untag_accounts", data = "<request>")]
+pub async fn untag_accounts(
+    request: Json<UntagAccountsRequest>,
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<UntagAccountsResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    db.untag_accounts(request.wallet_id, &request.accounts)
+        .await?;
+    Ok(Json(UntagAccountsResponse {}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetAccountTagDescriptionRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    tag: String,
+    description: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetAccountTagDescriptionResponse {}
+
+#[post("/set_account_tag_description", data = "<request>")]
+pub async fn set_account_tag_description(
+    request: Json<SetAccountTagDescriptionRequest>,
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<SetAccountTagDescriptionResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    if request.tag.is_empty() {
+        return Err(anyhow::anyhow!("Tag must not be empty").into());
+    }
+    db.set_tag_description(request.wallet_id, &request.tag, &request.description)
+        .await?;
+    Ok(Json(SetAccountTagDescriptionResponse {}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAccountTagsRequest {
+    #[serde(default)]
+    wallet_id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAccountTagsResponse {
+    account_tags: Vec<AccountTag>,
+}
+
+#[post("/get_account_tags", data = "<request>")]
+pub async fn get_account_tags(
+    request: Json<GetAccountTagsRequest>,
+    db: &State<Db>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetAccountTagsResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let account_tags = db.get_account_tags(request.wallet_id).await?;
+    Ok(Json(GetAccountTagsResponse { account_tags }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetBalanceRequest {
+    #[serde(default)]
+    wallet_id: u32,
+    account_index: u32,
+    #[serde(default)]
+    address_indices: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetBalanceResponse {
+    balance: u64,
+    unlocked_balance: u64,
+    multisig_import_needed: bool,
+    per_subaddress: Vec<SubAccountBalance>,
+    // False for an incoming viewing key: spent notes stay in the balance
+    spend_tracking: bool,
+}
+
+#[post("/get_balance", data = "<request>")]
+pub async fn get_balance(
+    request: Json<GetBalanceRequest>,
+    db: &State<Db>,
+    config: &State<WalletConfig>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetBalanceResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    let mut client = connect_lwd(&config.lwd_url).await?;
+    let latest_height = get_latest_height(&mut client).await?;
+    let per_subaddress = db
+        .get_subaddress_balances(
+            request.wallet_id,
+            latest_height,
+            request.account_index,
+            &request.address_indices,
+            config.confirmations,
+        )
+        .await?;
+    // Like monero, the totals cover the whole account even when
+    // only some of its sub accounts are requested
+    let sub_accounts = if request.address_indices.is_empty() {
+        per_subaddress.clone()
+    } else {
+        db.get_subaddress_balances(
+            request.wallet_id,
+            latest_height,
+            request.account_index,
+            &[],
+            config.confirmations,
+        )
+        .await?
+    };
+    let balance: u64 = sub_accounts.iter().map(|sa| sa.balance).sum();
+    let unlocked_balance: u64 = sub_accounts.iter().map(|sa| sa.unlocked_balance).sum();
+
+    let rep = GetBalanceResponse {
+        balance,
+        unlocked_balance,
+        multisig_import_needed: false,
+        per_subaddress,
+        spend_tracking: db.viewing_key(request.wallet_id)?.tracks_spends(),
+    };
+    Ok(Json(rep))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetTransactionByIdRequest {
     pub txid: String,
-    pub account_index: u32,
+    /// Only the transfers of this account. All of them when missing
+    pub account_index: Option<u32>,
+    #[serde(default)]
+    pub wallet_id: u32,
+    #[serde(default)]
+    pub memo_encoding: MemoEncoding,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -116,18 +476,20 @@ pub async fn get_transaction(
     request: Json<GetTransactionByIdRequest>,
     db: &State<Db>,
     config: &State<WalletConfig>,
-) -> Result<Json<GetTransactionByIdResponse>, Debug<anyhow::Error>> {
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetTransactionByIdResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
     let request = request.into_inner();
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone())
-        .await
-        .map_err(from_tonic)?;
+    let mut client = connect_lwd(&config.lwd_url).await?;
     let latest_height = get_latest_height(&mut client).await?;
     let transfers = db
         .get_transfers_by_txid(
+            request.wallet_id,
+            request.account_index,
             latest_height,
             &request.txid,
-            request.account_index,
             config.confirmations,
+            request.memo_encoding,
         )
         .await?;
     if transfers.is_empty() {
@@ -137,20 +499,45 @@ pub async fn get_transaction(
         transfer: transfers[0].clone(),
         transfers,
     };
-    info!("{rep:?}");
     Ok(Json(rep))
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GetTransfersRequest {
-    pub account_index: u32,
+    #[serde(default)]
     pub r#in: bool,
+    #[serde(default)]
+    pub out: bool,
+    #[serde(default)]
+    pub pending: bool,
+    #[serde(default)]
+    pub failed: bool,
+    #[serde(default)]
+    pub pool: bool,
+    #[serde(default)]
+    pub filter_by_height: bool,
+    #[serde(default)]
+    pub min_height: u32,
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub account_index: u32,
+    #[serde(default)]
     pub subaddr_indices: Vec<u32>,
+    #[serde(default)]
+    pub all_accounts: bool,
+    #[serde(default)]
+    pub wallet_id: u32,
+    #[serde(default)]
+    pub memo_encoding: MemoEncoding,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GetTransfersResponse {
     pub r#in: Vec<Transfer>,
+    pub out: Vec<Transfer>,
+    pub pending: Vec<Transfer>,
+    pub failed: Vec<Transfer>,
+    pub pool: Vec<Transfer>,
 }
 
 #[post("/get_transfers", data = "<request>")]
@@ -158,23 +545,180 @@ pub async fn get_transfers(
     request: Json<GetTransfersRequest>,
     db: &State<Db>,
     config: &State<WalletConfig>,
-) -> Result<Json<GetTransfersResponse>, Debug<anyhow::Error>> {
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetTransfersResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
     let request = request.into_inner();
-    assert!(request.r#in);
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone())
-        .await
-        .map_err(from_tonic)?;
+    // The wallet is view only and only scans mined blocks: it has no
+    // outgoing transactions of its own, and it does not watch the mempool
+    if request.pending || request.failed {
+        return Err(anyhow::anyhow!(
+            "pending and failed are not supported, the wallet is view only"
+        )
+        .into());
+    }
+    if request.pool {
+        return Err(anyhow::anyhow!(
+            "pool is not supported, the wallet does not watch the mempool"
+        )
+        .into());
+    }
+    let mut client = connect_lwd(&config.lwd_url).await?;
+    let latest_height = get_latest_height(&mut client).await?;
+    let filter = TransferFilter {
+        wallet: request.wallet_id,
+        account_index: (!request.all_accounts).then_some(request.account_index),
+        sub_accounts: request.subaddr_indices,
+        payment_ids: vec![],
+        height_range: request
+            .filter_by_height
+            .then(|| (request.min_height, request.max_height.unwrap_or(u32::MAX))),
+    };
+    let r#in = if request.r#in {
+        db.get_transfers(
+            latest_height,
+            &filter,
+            config.confirmations,
+            request.memo_encoding,
+        )
+        .await?
+    } else {
+        vec![]
+    };
+    let out = if request.out {
+        db.get_outgoing_transfers(
+            latest_height,
+            &filter,
+            config.confirmations,
+            request.memo_encoding,
+        )
+        .await?
+    } else {
+        vec![]
+    };
+    let rep = GetTransfersResponse {
+        r#in,
+        out,
+        pending: vec![],
+        failed: vec![],
+        pool: vec![],
+    };
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetPaymentsRequest {
+    pub payment_id: String,
+    #[serde(default)]
+    pub wallet_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Payment {
+    pub payment_id: String,
+    pub tx_hash: String,
+    pub amount: u64,
+    pub block_height: u32,
+    pub unlock_time: u32,
+    pub locked: bool,
+    pub subaddr_index: SubAddress,
+    pub address: String,
+}
+
+impl From<Transfer> for Payment {
+    fn from(transfer: Transfer) -> Self {
+        Payment {
+            locked: transfer.confirmations < transfer.suggested_confirmations_threshold,
+            payment_id: transfer.payment_id,
+            tx_hash: transfer.txid,
+            amount: transfer.amount,
+            block_height: transfer.height,
+            unlock_time: transfer.unlock_time,
+            subaddr_index: transfer.subaddr_index,
+            address: transfer.address,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetPaymentsResponse {
+    pub payments: Vec<Payment>,
+}
+
+/// Received notes whose memo has this payment id, see the `payment_id` config
+#[post("/get_payments", data = "<request>")]
+pub async fn get_payments(
+    request: Json<GetPaymentsRequest>,
+    db: &State<Db>,
+    config: &State<WalletConfig>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetPaymentsResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    if request.payment_id.is_empty() {
+        return Err(anyhow::anyhow!("Missing payment_id").into());
+    }
+    let filter = TransferFilter {
+        wallet: request.wallet_id,
+        payment_ids: vec![request.payment_id],
+        ..TransferFilter::default()
+    };
+    let payments = get_payments_by_filter(db, config, &filter).await?;
+    Ok(Json(GetPaymentsResponse { payments }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetBulkPaymentsRequest {
+    #[serde(default)]
+    pub payment_ids: Vec<String>,
+    #[serde(default)]
+    pub min_block_height: u32,
+    #[serde(default)]
+    pub wallet_id: u32,
+}
+
+/// Payments with any of the payment ids, or all the payments with a payment id
+/// if none is given. Like monero, only the blocks after `min_block_height` count
+#[post("/get_bulk_payments", data = "<request>")]
+pub async fn get_bulk_payments(
+    request: Json<GetBulkPaymentsRequest>,
+    db: &State<Db>,
+    config: &State<WalletConfig>,
+    auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetPaymentsResponse>, ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    let request = request.into_inner();
+    let filter = TransferFilter {
+        wallet: request.wallet_id,
+        payment_ids: request.payment_ids,
+        height_range: Some((request.min_block_height, u32::MAX)),
+        ..TransferFilter::default()
+    };
+    let payments = get_payments_by_filter(db, config, &filter).await?;
+    Ok(Json(GetPaymentsResponse { payments }))
+}
+
+async fn get_payments_by_filter(
+    db: &Db,
+    config: &WalletConfig,
+    filter: &TransferFilter,
+) -> Result<Vec<Payment>> {
+    let mut client = connect_lwd(&config.lwd_url).await?;
     let latest_height = get_latest_height(&mut client).await?;
     let transfers = db
         .get_transfers(
             latest_height,
-            request.account_index,
-            &request.subaddr_indices,
+            filter,
             config.confirmations,
+            MemoEncoding::default(),
         )
         .await?;
-    let rep = GetTransfersResponse { r#in: transfers };
-    Ok(Json(rep))
+    let payments = transfers
+        .into_iter()
+        .filter(|t| !t.payment_id.is_empty())
+        .map(Payment::from)
+        .collect();
+    Ok(payments)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -193,7 +737,8 @@ const LOGICAL_ACTION_FEE: u64 = 5000u64;
 #[post("/get_fee_estimate", data = "<_request>")]
 pub fn get_fee_estimate(
     _request: Json<GetFeeEstimateRequest>,
-) -> Result<Json<GetFeeEstimateResponse>, Debug<anyhow::Error>> {
+    _auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetFeeEstimateResponse>, ApiError> {
     let rep = GetFeeEstimateResponse {
         fee: 4 * LOGICAL_ACTION_FEE,
     };
@@ -212,10 +757,9 @@ pub struct GetHeightResponse {
 pub async fn get_height(
     _request: Json<GetHeightRequest>,
     config: &State<WalletConfig>,
-) -> Result<Json<GetHeightResponse>, Debug<anyhow::Error>> {
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone())
-        .await
-        .map_err(from_tonic)?;
+    _auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<GetHeightResponse>, ApiError> {
+    let mut client = connect_lwd(&config.lwd_url).await?;
     let latest_height = get_latest_height(&mut client).await?;
     let rep = GetHeightResponse {
         height: latest_height,
@@ -223,6 +767,81 @@ pub async fn get_height(
     Ok(Json(rep))
 }
 
+#[get("/health")]
+pub async fn get_health(
+    db: &State<Db>,
+    status: &State<ScanStatus>,
+    config: &State<WalletConfig>,
+    _auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<HealthReport>, ApiError> {
+    let report = health_report(db, status, config).await?;
+    Ok(Json(report))
+}
+
+#[get("/ready")]
+pub async fn get_ready(
+    db: &State<Db>,
+    status: &State<ScanStatus>,
+    config: &State<WalletConfig>,
+    _auth: Authorized<ReadOnlyScope>,
+) -> Result<(Status, Json<HealthReport>), ApiError> {
+    let report = health_report(db, status, config).await?;
+    let status = if report.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    Ok((status, Json(report)))
+}
+
+async fn health_report(
+    db: &Db,
+    status: &ScanStatus,
+    config: &WalletConfig,
+) -> Result<HealthReport> {
+    let synced_height = db.get_synced_height().await?;
+    let synced_time = db.get_block_time(synced_height).await?;
+    let (chain_tip_height, tip_time, lwd_error) = match get_chain_tip(&config.lwd_url).await {
+        Ok((height, time)) => (Some(height), Some(time), None),
+        Err(e) => (None, None, Some(e.to_string())),
+    };
+    let lag_blocks = chain_tip_height.map(|tip| tip.saturating_sub(synced_height));
+    let lag_seconds = tip_time
+        .zip(synced_time)
+        .map(|(tip_time, synced_time)| tip_time.saturating_sub(synced_time));
+    let db_writable = db.check_writable().await.is_ok();
+    let ready = db_writable && lag_blocks.is_some_and(|lag| lag <= config.max_sync_lag);
+
+    Ok(HealthReport {
+        synced_height,
+        chain_tip_height,
+        lag_blocks,
+        lag_seconds,
+        last_scan_time: status.last_success(),
+        last_error: status.last_error(),
+        lwd_error,
+        db_writable,
+        ready,
+    })
+}
+
+// Height and timestamp of the chain tip
+async fn get_chain_tip(lwd_url: &str) -> Result<(u32, u32)> {
+    let mut client = connect_lwd(lwd_url).await?;
+    let height = get_latest_height(&mut client).await?;
+    let tree_state = observe_lwd(
+        "get_tree_state",
+        client.get_tree_state(Request::new(BlockId {
+            height: height as u64,
+            hash: vec![],
+        })),
+    )
+    .await
+    .map_err(from_tonic)?
+    .into_inner();
+    Ok((height, tree_state.time))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SyncInfoRequest {}
 
@@ -235,19 +854,23 @@ pub struct SyncInfoResponse {
 #[post("/sync_info", data = "<_request>")]
 pub async fn sync_info(
     _request: Json<SyncInfoRequest>,
+    db: &State<Db>,
     config: &State<WalletConfig>,
-) -> Result<Json<SyncInfoResponse>, Debug<anyhow::Error>> {
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone())
-        .await
-        .map_err(from_tonic)?;
-    let rep = client
-        .get_lightd_info(Request::new(Empty {}))
-        .await
-        .map_err(from_tonic)?
-        .into_inner();
+    _auth: Authorized<ReadOnlyScope>,
+) -> Result<Json<SyncInfoResponse>, ApiError> {
+    let mut client = connect_lwd(&config.lwd_url).await?;
+    let rep = observe_lwd(
+        "get_lightd_info",
+        client.get_lightd_info(Request::new(Empty {})),
+    )
+    .await
+    .map_err(from_tonic)?
+    .into_inner();
+    // Report how far the wallet has scanned, not the server's height
+    let height = db.get_synced_height().await?;
     let rep = SyncInfoResponse {
         target_height: rep.block_height as u32,
-        height: rep.estimated_height as u32,
+        height,
     };
     Ok(Json(rep))
 }
@@ -255,49 +878,89 @@ pub async fn sync_info(
 #[post("/request_scan")]
 pub async fn request_scan(
     db: &State<Db>,
+    status: &State<ScanStatus>,
     config: &State<WalletConfig>,
-) -> Result<(), Debug<anyhow::Error>> {
+    auth: Authorized<AdminScope>,
+) -> Result<(), ApiError> {
+    auth.check_all_wallets()?;
     let network = config.network();
-    let ufvk = db.ufvk();
+    scan_to_tip(
+        db,
+        status,
+        &network,
+        &config.lwd_url,
+        config.header_validation,
+    )
+    .await?;
+    // The memos of the new transactions, on a best effort basis
+    let memos = fetch_memos(
+        db,
+        &network,
+        config.memo_lwd_url(),
+        config.memo_concurrency(),
+        config.memo_privacy,
+    )
+    .await;
+    if let Err(e) = memos {
+        warn!("Memo fetch failed: {e}");
+    }
+    Ok(())
+}
+
+/// Scan from the last synced block to the chain tip and store the results.
+/// Used by `request_scan` and the periodic monitor task
+pub async fn scan_to_tip(
+    db: &Db,
+    status: &ScanStatus,
+    network: &Network,
+    lwd_url: &str,
+    headers: HeaderValidation,
+) -> Result<()> {
+    let res = scan_blocks(db, network, lwd_url, headers).await;
+    status.record(&res);
+    res
+}
+
+async fn scan_blocks(
+    db: &Db,
+    network: &Network,
+    lwd_url: &str,
+    headers: HeaderValidation,
+) -> Result<()> {
+    let _guard = db.lock_scan().await;
     let start = db.get_synced_height().await?;
     let prev_hash = db
         .get_block_hash(start)
         .await?
         .ok_or(anyhow::anyhow!("Block Hash missing from db"))?;
 
-    let nfs = db.get_nfs().await?;
-    let mut sap_dec = ufvk.sapling().map(|fvk| {
-        let nk = fvk.fvk().vk.nk;
-        let ivk = fvk.to_ivk(zip32::Scope::External);
-        let pivk = sapling_crypto::keys::PreparedIncomingViewingKey::new(&ivk);
-        // TODO: Load nfs
-        Decoder::<Sapling>::new(nk, fvk.clone(), pivk, &nfs)
-    });
-    let mut orc_dec = ufvk.orchard().map(|fvk| {
-        let ivk = fvk.to_ivk(zip32::Scope::External);
-        let pivk = orchard::keys::PreparedIncomingViewingKey::new(&ivk);
-        // TODO: Load nfs
-        Decoder::<Orchard>::new(fvk.clone(), ivk, pivk, &nfs)
-    });
+    // A single pass over the blocks for all the wallets
+    // that have caught up
+    let mut wallets = vec![];
+    for (wallet, vk) in db.synced_wallets().await? {
+        let nfs = db.get_nfs(wallet).await?;
+        wallets.push(make_decoders(wallet, &vk, &nfs));
+    }
 
-    let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone())
-        .await
-        .map_err(anyhow::Error::new)?;
+    let mut client = connect_lwd(lwd_url).await?;
     let end = get_latest_height(&mut client).await?;
+    metrics::SYNCED_HEIGHT.set(start as i64);
+    metrics::CHAIN_TIP_HEIGHT.set(end as i64);
 
     info!("Scan from {start} to {end}");
     if start >= end {
         return Ok(());
     }
 
+    let timer = metrics::SCAN_DURATION.start_timer();
     let res = crate::scan::scan(
-        &network,
+        network,
         &mut client,
         start + 1,
         end,
         &prev_hash,
-        &mut sap_dec,
-        &mut orc_dec,
+        &mut wallets,
+        headers,
     )
     .await;
     match res {
@@ -306,16 +969,32 @@ pub async fn request_scan(
         {
             match error {
                 ScanError::Reorganization => {
+                    metrics::REORGS.inc();
                     let synced_height = db.get_synced_height().await?;
                     db.truncate_height(synced_height - SAFE_REORG_DISTANCE)
                         .await
                 }
+                error @ (ScanError::TreeSizeMismatch { .. } | ScanError::InvalidHeader { .. }) => {
+                    Err(error.into())
+                }
                 ScanError::Other(error) => Err(error),
             }?
         }
 
-        Ok(events) => {
-            db.store_events(&events).await?;
+        Ok(mut events) => {
+            db.store_events(&mut events).await?;
+            let elapsed = timer.stop_and_record();
+            let blocks = end - start;
+            metrics::BLOCKS_SCANNED.inc_by(blocks as u64);
+            metrics::SCAN_BLOCKS_PER_SECOND.set(blocks as f64 / elapsed.max(f64::EPSILON));
+            metrics::SYNCED_HEIGHT.set(end as i64);
+            for event in events.iter() {
+                if let ScanEvent::Received(note) = event {
+                    metrics::NOTES_DECRYPTED
+                        .with_label_values(&[metrics::pool_label(note.pool)])
+                        .inc();
+                }
+            }
         }
     }
     Ok(())
@@ -323,16 +1002,320 @@ pub async fn request_scan(
 
 pub const SAFE_REORG_DISTANCE: u32 = 100u32;
 
+// Blocks scanned between two checkpoints of a catch-up scan
+const CATCH_UP_BATCH: u32 = 10_000;
+
+/// Scan the blocks for a single wallet, from its birth height up to the
+/// synced height of the others. Then the wallet joins the regular scans
+pub async fn catch_up_wallet(
+    db: &Db,
+    network: &Network,
+    lwd_url: &str,
+    headers: HeaderValidation,
+    wallet: u32,
+) -> Result<()> {
+    let vk = db.viewing_key(wallet)?;
+    let mut client = connect_lwd(lwd_url).await?;
+    loop {
+        let scan_height = db
+            .get_wallets()
+            .await?
+            .into_iter()
+            .find(|w| w.id == wallet)
+            .and_then(|w| w.scan_height);
+        // Removed, or done
+        let Some(start) = scan_height else {
+            return Ok(());
+        };
+        let synced_height = db.get_synced_height().await?;
+        if start >= synced_height {
+            // The regular scans must not move on while the wallet joins them
+            let _guard = db.lock_scan().await;
+            if db.get_synced_height().await? == start {
+                db.set_wallet_synced(wallet).await?;
+                info!("Wallet {wallet} caught up at {start}");
+                return Ok(());
+            }
+            continue;
+        }
+
+        let end = synced_height.min(start + CATCH_UP_BATCH);
+        let (prev_hash, _) = get_block_hash(&mut client, start).await?;
+        let nfs = db.get_nfs(wallet).await?;
+        let mut wallets = [make_decoders(wallet, &vk, &nfs)];
+        info!("Catch up wallet {wallet} from {start} to {end}");
+        let events = crate::scan::scan(
+            network,
+            &mut client,
+            start + 1,
+            end,
+            &prev_hash,
+            &mut wallets,
+            headers,
+        )
+        .await
+        .map_err(|error| match error {
+            ScanError::Reorganization => anyhow::anyhow!("Chain reorganization at {start}"),
+            error @ (ScanError::TreeSizeMismatch { .. } | ScanError::InvalidHeader { .. }) => {
+                error.into()
+            }
+            ScanError::Other(error) => error,
+        })?;
+        // The regular scans already stored these blocks
+        let mut events: Vec<_> = events
+            .into_iter()
+            .filter(|e| !matches!(e, ScanEvent::Block(..)))
+            .collect();
+        events.insert(0, ScanEvent::WalletHeight(wallet, start, end));
+        db.store_events(&mut events).await?;
+    }
+}
+
+// Transactions taken from the memo queue at a time
+const MEMO_BATCH: u32 = 100;
+
+/// Fetch the full transactions of the memo queue that are due, with at most
+/// `concurrency` requests at a time, and store their memos. A failed fetch is
+/// retried later. Returns the number of transactions taken from the queue
+pub async fn fetch_memos(
+    db: &Db,
+    network: &Network,
+    lwd_url: &str,
+    concurrency: usize,
+    privacy: MemoPrivacy,
+) -> Result<usize> {
+    let _guard = db.lock_memos().await;
+    let queue = db.get_memo_queue(MEMO_BATCH).await?;
+    if queue.is_empty() {
+        return Ok(0);
+    }
+    let client = connect_lwd(lwd_url).await?;
+    // Every wallet decrypts the outputs, like in the scan
+    let decoders: Arc<Vec<_>> = Arc::new(
+        db.wallets()
+            .iter()
+            .map(|(wallet, vk)| make_decoders(*wallet, vk, &HashMap::new()))
+            .collect(),
+    );
+    let count = queue.len();
+    let mut fetches = JoinSet::new();
+    for (wtx, attempts) in queue {
+        while fetches.len() >= concurrency.max(1) {
+            if let Some(fetch) = fetches.join_next().await {
+                store_memos(db, fetch?).await?;
+            }
+        }
+        let network = *network;
+        let mut client = client.clone();
+        let decoders = decoders.clone();
+        fetches.spawn(async move {
+            let memos = scan_tx(&network, &mut client, &wtx, &decoders, privacy).await;
+            (wtx, attempts, memos)
+        });
+    }
+    while let Some(fetch) = fetches.join_next().await {
+        store_memos(db, fetch?).await?;
+    }
+    Ok(count)
+}
+
+async fn store_memos(
+    db: &Db,
+    (wtx, attempts, memos): (WalletTx, u32, Result<Vec<MemoNote>>),
+) -> Result<()> {
+    match memos {
+        Ok(memos) => {
+            let mut events: Vec<_> = memos.into_iter().map(ScanEvent::Memo).collect();
+            events.push(ScanEvent::MemoFetched(wtx.txid));
+            db.store_events(&mut events).await?;
+        }
+        Err(e) => {
+            warn!("Fetching the memos of the tx at {} failed: {e}", wtx.height);
+            metrics::MEMO_FETCH_FAILURES.inc();
+            db.retry_memo_tx(&wtx.txid, attempts + 1).await?;
+        }
+    }
+    Ok(())
+}
+
 #[post("/reorg")]
-pub async fn reorg(
-    db: &State<Db>,
-) -> Result<(), Debug<anyhow::Error>> {
+pub async fn reorg(db: &State<Db>, auth: Authorized<AdminScope>) -> Result<(), ApiError> {
+    auth.check_all_wallets()?;
+    // Not in the middle of a scan, which would store blocks above the new height
+    let _guard = db.lock_scan().await;
     let synced_height = db.get_synced_height().await?;
     db.truncate_height(synced_height - SAFE_REORG_DISTANCE)
         .await?;
+    metrics::REORGS.inc();
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupRequest {
+    path: String,
+}
+
+/// Write a snapshot of the database to a file on the server
+#[post("/backup", data = "<request>")]
+pub async fn backup(
+    request: Json<BackupRequest>,
+    db: &State<Db>,
+    config: &State<WalletConfig>,
+    api_auth: &State<ApiAuth>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<SnapshotInfo>, ApiError> {
+    auth.check_all_wallets()?;
+    let path = backup_file(config, api_auth, &request.path)?;
+    let snapshot = db.backup(&path).await?;
+    Ok(Json(snapshot))
+}
+
+// The files of the API are kept in backup_dir, and never
+// written or read by an unauthenticated request
+fn backup_file(config: &WalletConfig, api_auth: &ApiAuth, name: &str) -> Result<String> {
+    if api_auth.is_disabled() {
+        anyhow::bail!("Backups over the API need authentication");
+    }
+    let path = backup_path(config.backup_dir.as_deref(), name)?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[post("/restore", data = "<request>")]
+pub async fn restore(
+    request: Json<BackupRequest>,
+    db: &State<Db>,
+    status: &State<ScanStatus>,
+    config: &State<WalletConfig>,
+    api_auth: &State<ApiAuth>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<SnapshotInfo>, ApiError> {
+    auth.check_all_wallets()?;
+    let path = backup_file(config, api_auth, &request.path)?;
+    let snapshot = db.restore(&path).await?;
+    // The wallets of the config that were added after the snapshot
+    let mut client = connect_lwd(&config.lwd_url).await?;
+    register_wallets(db, &mut client, config, &db.viewing_key(0)?).await?;
+
+    // Catch up from the height of the snapshot without waiting for the monitor
+    let db = db.inner().clone();
+    let status = status.inner().clone();
+    let network = config.network();
+    let lwd_url = config.lwd_url.clone();
+    let headers = config.header_validation;
+    catch_up_tasks(&db, network, &lwd_url, headers, config.poll_interval).await?;
+    tokio::spawn(async move {
+        if let Err(e) = scan_to_tip(&db, &status, &network, &lwd_url, headers).await {
+            log::warn!("Scan failed: {e}");
+        }
+    });
+    Ok(Json(snapshot))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportWalletRequest {
+    vk: String,
+    birth_height: u32,
+    #[serde(default)]
+    label: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportWalletResponse {
+    wallet_id: u32,
+}
+
+/// Add a wallet for a viewing key. Its past transactions are
+/// scanned in the background from the birth height
+#[post("/import_wallet", data = "<request>")]
+pub async fn import_wallet(
+    request: Json<ImportWalletRequest>,
+    db: &State<Db>,
+    config: &State<WalletConfig>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<ImportWalletResponse>, ApiError> {
+    auth.check_all_wallets()?;
+    let request = request.into_inner();
+    let network = config.network();
+    let vk = ViewingKey::decode(&network, &request.vk)?;
+    let mut client = connect_lwd(&config.lwd_url).await?;
+    let wallet_id = db
+        .import_wallet(&mut client, &request.label, &vk, request.birth_height)
+        .await?;
+    catch_up_tasks(
+        db,
+        network,
+        &config.lwd_url,
+        config.header_validation,
+        config.poll_interval,
+    )
+    .await?;
+    Ok(Json(ImportWalletResponse { wallet_id }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WalletInfo {
+    wallet_id: u32,
+    label: String,
+    fingerprint: String,
+    birth_height: u32,
+    // Height reached by the catch-up scan, null once the wallet is synced
+    scan_height: Option<u32>,
+    spend_tracking: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetWalletsResponse {
+    wallets: Vec<WalletInfo>,
+}
+
+#[post("/get_wallets")]
+pub async fn get_wallets(
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<Json<GetWalletsResponse>, ApiError> {
+    let mut wallets = vec![];
+    for w in db.get_wallets().await? {
+        if !auth.has_wallet(w.id) {
+            continue;
+        }
+        wallets.push(WalletInfo {
+            wallet_id: w.id,
+            spend_tracking: db.viewing_key(w.id)?.tracks_spends(),
+            label: w.label,
+            fingerprint: fingerprint(&w.vk),
+            birth_height: w.birth_height,
+            scan_height: w.scan_height,
+        });
+    }
+    Ok(Json(GetWalletsResponse { wallets }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveWalletRequest {
+    wallet_id: u32,
+}
+
+/// Delete a wallet with its accounts and notes
+#[post("/remove_wallet", data = "<request>")]
+pub async fn remove_wallet(
+    request: Json<RemoveWalletRequest>,
+    db: &State<Db>,
+    auth: Authorized<AdminScope>,
+) -> Result<(), ApiError> {
+    auth.check_wallet(request.wallet_id)?;
+    db.remove_wallet(request.wallet_id).await?;
     Ok(())
 }
 
+#[get("/metrics")]
+pub async fn get_metrics(
+    db: &State<Db>,
+    _auth: Authorized<ReadOnlyScope>,
+) -> Result<(ContentType, String), ApiError> {
+    metrics::SYNCED_HEIGHT.set(db.get_synced_height().await? as i64);
+    Ok((ContentType::Plain, metrics::encode()?))
+}
+
 pub async fn notify_tx(txid: &[u8], notify_tx_url: &str) -> Result<()> {
     let mut txid = txid.to_vec();
     txid.reverse();
@@ -341,14 +1324,17 @@ pub async fn notify_tx(txid: &[u8], notify_tx_url: &str) -> Result<()> {
 
     let url = notify_tx_url.to_string() + &txid;
     // TODO: Remove self signed certificate accept
-    let res = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
+    let res = http_proxy(reqwest::Client::builder().danger_accept_invalid_certs(true))?
         .build()?
         .get(url)
         .send()
         .await;
-    if let Err(e) = res {
-        log::warn!("Failed to notify new tx: {e}",);
+    match res.and_then(|r| r.error_for_status()) {
+        Ok(_) => metrics::NOTIFICATIONS.with_label_values(&["success"]).inc(),
+        Err(e) => {
+            metrics::NOTIFICATIONS.with_label_values(&["failure"]).inc();
+            log::warn!("Failed to notify new tx: {e}",);
+        }
     }
 
     Ok(())
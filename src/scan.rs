@@ -8,11 +8,10 @@ use orchard::{
     primitives::redpallas::{Signature, SpendAuth},
     Action, Address,
 };
+use rand::seq::SliceRandom;
+use rocket::serde::Deserialize;
 use sapling_crypto::{
-    bundle::OutputDescription,
-    note_encryption::{SaplingDomain, Zip212Enforcement},
-    zip32::DiversifiableFullViewingKey,
-    NullifierDerivingKey, PaymentAddress,
+    bundle::OutputDescription, note_encryption::SaplingDomain, NullifierDerivingKey, PaymentAddress,
 };
 use thiserror::Error;
 use tonic::{transport::Channel, Request};
@@ -31,42 +30,71 @@ use zcash_protocol::{
 };
 
 use crate::{
+    header::{
+        append_sapling_cmu, check_header, check_sapling_root, has_sapling_root, DifficultyWindow,
+        HeaderValidation, DIFFICULTY_WINDOW,
+    },
+    keys::ViewingKey,
     lwd_rpc::{
         compact_tx_streamer_client::CompactTxStreamerClient, BlockId, BlockRange, ChainSpec,
-        CompactOrchardAction, CompactSaplingOutput, TxFilter,
+        CompactOrchardAction, CompactSaplingOutput, RawTransaction, TreeState, TxFilter,
     },
-    network::Network, Client, Hash,
+    metrics::observe_lwd,
+    network::Network,
+    Client, Hash,
 };
 
 pub async fn get_latest_height(client: &mut CompactTxStreamerClient<Channel>) -> Result<u32> {
-    let latest_block_id = client
-        .get_latest_block(Request::new(ChainSpec {}))
-        .await?
-        .into_inner();
+    let latest_block_id = observe_lwd(
+        "get_latest_block",
+        client.get_latest_block(Request::new(ChainSpec {})),
+    )
+    .await?
+    .into_inner();
     let latest_height = latest_block_id.height;
     Ok(latest_height as u32)
 }
 
+/// Hash and time of the block at `height`
+pub async fn get_block_hash(client: &mut Client, height: u32) -> Result<(Hash, u32)> {
+    let block = observe_lwd(
+        "get_block",
+        client.get_block(Request::new(BlockId {
+            height: height as u64,
+            hash: vec![],
+        })),
+    )
+    .await?
+    .into_inner();
+    let hash: Hash = block.hash.try_into().unwrap();
+    Ok((hash, block.time))
+}
+
 pub async fn scan(
     network: &Network,
     client: &mut Client,
     start: u32,
     end: u32,
     prev_hash: &Hash,
-    sap_dec: &mut Option<Decoder<Sapling>>,
-    orc_dec: &mut Option<Decoder<Orchard>>,
+    wallets: &mut [WalletDecoders],
+    headers: HeaderValidation,
 ) -> Result<Vec<ScanEvent>, ScanError> {
-    let tree_state = client
-        .get_tree_state(Request::new(BlockId {
-            height: start as u64,
-            hash: vec![],
-        }))
-        .await
-        .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
-        .into_inner();
+    // The positions start after the notes of the block before `start`
+    let tree_state = get_tree_state(client, start - 1).await?;
+    let (mut sap_position, mut orc_position) = get_tree_sizes(&tree_state)?;
+    // Before Heartwood, the headers have the root of the Sapling tree.
+    // It is dropped when the server does not send them
+    let mut sapling_tree = if has_sapling_root(network, start) {
+        Some(read_sapling_tree(&tree_state.sapling_tree)?)
+    } else {
+        None
+    };
+    // Loaded with the previous headers at the first full header
+    let mut difficulty: Option<DifficultyWindow> = None;
 
-    let mut blocks = client
-        .get_block_range(Request::new(BlockRange {
+    let mut blocks = observe_lwd(
+        "get_block_range",
+        client.get_block_range(Request::new(BlockRange {
             start: Some(BlockId {
                 height: start as u64,
                 hash: vec![],
@@ -76,142 +104,301 @@ pub async fn scan(
                 hash: vec![],
             }),
             spam_filter_threshold: 0,
-        }))
-        .await
-        .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
-        .into_inner();
+        })),
+    )
+    .await
+    .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
+    .into_inner();
     let mut prev_hash = *prev_hash;
-    let mut sap_position = get_tree_size(&tree_state.sapling_tree).unwrap();
-    let mut orc_position = get_tree_size(&tree_state.orchard_tree).unwrap();
+    let mut block_time = None;
 
     let mut events = vec![];
-    let mut new_txids = vec![];
-    while let Ok(Some(block)) = blocks.message().await {
+    // A stream error is reported as is, not as a tree size mismatch
+    while let Some(block) = blocks
+        .message()
+        .await
+        .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
+    {
         let height = block.height as u32;
+        let header = check_header(network, &block, headers).map_err(invalid_header(height))?;
         let block_prev_hash: Hash = block.prev_hash.try_into().unwrap();
         if prev_hash != block_prev_hash {
             info!("Reorg at {} {}", block.height, hex::encode(block_prev_hash));
             return Err(ScanError::Reorganization);
         }
+        match &header {
+            Some(header) => {
+                if difficulty.is_none() && DifficultyWindow::applies(network) {
+                    difficulty = Some(
+                        get_difficulty_window(network, client, height, &prev_hash, headers).await?,
+                    );
+                }
+                if let Some(window) = &mut difficulty {
+                    window
+                        .check(network, height, header.bits)
+                        .map_err(invalid_header(height))?;
+                    window.push(header.time, header.bits);
+                }
+            }
+            None => {
+                if let Some(window) = &mut difficulty {
+                    window.clear();
+                }
+            }
+        }
         prev_hash = block.hash.try_into().unwrap();
+        block_time = Some(block.time);
 
         for vtx in block.vtx.iter() {
             let mut found = false;
-            if let Some(sap_dec) = sap_dec {
-                for i in vtx.spends.iter() {
-                    let nf: &Hash = i.nf.as_slice().try_into().unwrap();
-                    if let Some(value) = sap_dec.nfs.get(nf) {
-                        events.push(ScanEvent::Spent(SpentNote {
-                            height,
-                            nf: *nf,
-                            txid: vtx.hash.clone().try_into().unwrap(),
-                            value: *value,
-                        }));
+            // Every wallet trial decrypts the same blocks
+            for wallet in wallets.iter_mut() {
+                if let Some(sap_dec) = &mut wallet.sapling {
+                    for i in vtx.spends.iter() {
+                        let nf: &Hash = i.nf.as_slice().try_into().unwrap();
+                        if let Some(value) = sap_dec.nfs.get(nf) {
+                            events.push(ScanEvent::Spent(SpentNote {
+                                height,
+                                nf: *nf,
+                                txid: vtx.hash.clone().try_into().unwrap(),
+                                value: *value,
+                            }));
+                        }
                     }
-                }
 
-                for (vout, o) in vtx.outputs.iter().enumerate() {
-                    if let Some(n) = sap_dec.try_compact_note_decryption(
-                        network,
-                        height,
-                        &vtx.hash,
-                        sap_position + vout as u32,
-                        o,
-                    )? {
-                        sap_dec.add_nf(n.nf, n.value);
-                        events.push(ScanEvent::Received(n));
-                        found = true;
+                    for (vout, o) in vtx.outputs.iter().enumerate() {
+                        if let Some(n) = sap_dec.try_compact_note_decryption(
+                            network,
+                            height,
+                            &vtx.hash,
+                            sap_position + vout as u32,
+                            o,
+                        )? {
+                            sap_dec.add_nf(n.nf, n.value);
+                            events.push(ScanEvent::Received(n));
+                            found = true;
+                        }
                     }
                 }
-            }
 
-            if let Some(orc_dec) = orc_dec {
-                for (vout, a) in vtx.actions.iter().enumerate() {
-                    let nf: &Hash = a.nullifier.as_slice().try_into().unwrap();
-                    if let Some(value) = orc_dec.nfs.get(nf) {
-                        events.push(ScanEvent::Spent(SpentNote {
+                if let Some(orc_dec) = &mut wallet.orchard {
+                    for (vout, a) in vtx.actions.iter().enumerate() {
+                        let nf: &Hash = a.nullifier.as_slice().try_into().unwrap();
+                        if let Some(value) = orc_dec.nfs.get(nf) {
+                            events.push(ScanEvent::Spent(SpentNote {
+                                height,
+                                nf: *nf,
+                                txid: vtx.hash.clone().try_into().unwrap(),
+                                value: *value,
+                            }));
+                        }
+                        if let Some(n) = orc_dec.try_compact_note_decryption(
+                            network,
                             height,
-                            nf: *nf,
-                            txid: vtx.hash.clone().try_into().unwrap(),
-                            value: *value,
-                        }));
-                    }
-                    if let Some(n) = orc_dec.try_compact_note_decryption(
-                        network,
-                        height,
-                        &vtx.hash,
-                        orc_position + vout as u32,
-                        a,
-                    )? {
-                        orc_dec.add_nf(n.nf, n.value);
-                        events.push(ScanEvent::Received(n));
-                        found = true;
+                            &vtx.hash,
+                            orc_position + vout as u32,
+                            a,
+                        )? {
+                            orc_dec.add_nf(n.nf, n.value);
+                            events.push(ScanEvent::Received(n));
+                            found = true;
+                        }
                     }
                 }
             }
 
+            // The memos are fetched later from the full transaction
             if found {
                 let txid: Hash = vtx.hash.clone().try_into().unwrap();
-                new_txids.push(WalletTx {
+                events.push(ScanEvent::MemoTx(WalletTx {
                     height,
                     txid,
                     sap_position,
                     orc_position,
-                });
+                }));
             }
 
+            if let Some(tree) = &mut sapling_tree {
+                for o in vtx.outputs.iter() {
+                    append_sapling_cmu(tree, &o.cmu)?;
+                }
+            }
             sap_position += vtx.outputs.len() as u32;
             orc_position += vtx.actions.len() as u32;
         }
-    }
 
-    for wtx in new_txids.iter() {
-        let memos = scan_tx(network, client, wtx, sap_dec, orc_dec).await?;
-        for m in memos {
-            events.push(ScanEvent::Memo(m));
+        if let Some(tree) = &sapling_tree {
+            match &header {
+                Some(header) if has_sapling_root(network, height) => {
+                    check_sapling_root(header, tree).map_err(invalid_header(height))?
+                }
+                // From Heartwood, or without the full headers
+                _ => sapling_tree = None,
+            }
         }
     }
-    events.push(ScanEvent::Block(end, prev_hash));
+
+    // A wrong tree state or a missing block would shift the positions,
+    // and the nullifiers derived from them
+    let tree_sizes = get_tree_sizes(&get_tree_state(client, end).await?)?;
+    if (sap_position, orc_position) != tree_sizes {
+        return Err(ScanError::TreeSizeMismatch {
+            height: end,
+            computed: (sap_position, orc_position),
+            tree_state: tree_sizes,
+        });
+    }
+    events.push(ScanEvent::Block(end, prev_hash, block_time));
 
     Ok(events)
 }
 
+fn invalid_header(height: u32) -> impl Fn(anyhow::Error) -> ScanError {
+    move |e| ScanError::InvalidHeader {
+        height,
+        reason: e.to_string(),
+    }
+}
+
+/// Times and target bits of the headers before the block at `height`,
+/// checked like the blocks and linked to `prev_hash`
+async fn get_difficulty_window(
+    network: &Network,
+    client: &mut Client,
+    height: u32,
+    prev_hash: &Hash,
+    headers: HeaderValidation,
+) -> Result<DifficultyWindow, ScanError> {
+    let mut window = DifficultyWindow::default();
+    let window_size = DIFFICULTY_WINDOW as u32;
+    if height <= window_size {
+        return Ok(window);
+    }
+    let start = height - window_size;
+    let mut blocks = observe_lwd(
+        "get_block_range",
+        client.get_block_range(Request::new(BlockRange {
+            start: Some(BlockId {
+                height: start as u64,
+                hash: vec![],
+            }),
+            end: Some(BlockId {
+                height: (height - 1) as u64,
+                hash: vec![],
+            }),
+            spam_filter_threshold: 0,
+        })),
+    )
+    .await
+    .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
+    .into_inner();
+
+    let mut next_height = start;
+    let mut last_hash: Option<Vec<u8>> = None;
+    while let Some(block) = blocks
+        .message()
+        .await
+        .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
+    {
+        let block_height = block.height as u32;
+        if block_height != next_height {
+            return Err(ScanError::InvalidHeader {
+                height: block_height,
+                reason: format!("Expected block {next_height}"),
+            });
+        }
+        if let Some(hash) = &last_hash {
+            if *hash != block.prev_hash {
+                return Err(ScanError::InvalidHeader {
+                    height: block_height,
+                    reason: "Previous block hash differs from the previous block".to_string(),
+                });
+            }
+        }
+        match check_header(network, &block, headers).map_err(invalid_header(block_height))? {
+            Some(header) => window.push(header.time, header.bits),
+            None => window.clear(),
+        }
+        last_hash = Some(block.hash);
+        next_height += 1;
+    }
+    // The window ends with the block that the scan is at
+    if next_height != height || last_hash.as_deref() != Some(prev_hash.as_slice()) {
+        return Err(ScanError::Reorganization);
+    }
+    Ok(window)
+}
+
+/// Commitment trees at the end of a block
+async fn get_tree_state(client: &mut Client, height: u32) -> Result<TreeState, ScanError> {
+    let tree_state = observe_lwd(
+        "get_tree_state",
+        client.get_tree_state(Request::new(BlockId {
+            height: height as u64,
+            hash: vec![],
+        })),
+    )
+    .await
+    .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
+    .into_inner();
+    Ok(tree_state)
+}
+
+/// Sizes of the Sapling and Orchard commitment trees at the end of a block
+fn get_tree_sizes(tree_state: &TreeState) -> Result<(u32, u32), ScanError> {
+    let sapling = get_tree_size(&tree_state.sapling_tree)?;
+    let orchard = get_tree_size(&tree_state.orchard_tree)?;
+    Ok((sapling, orchard))
+}
+
+/// How the full transactions are requested for their memos. A request
+/// by txid tells the server that the transaction is ours
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoPrivacy {
+    /// Only our transaction
+    #[default]
+    Direct,
+    /// Our transaction and this number of other transactions of its block
+    Decoys(usize),
+    /// Every transaction of the block
+    Block,
+}
+
 pub async fn scan_tx(
     network: &Network,
     client: &mut Client,
     wtx: &WalletTx,
-    sap_dec: &Option<Decoder<Sapling>>,
-    orc_dec: &Option<Decoder<Orchard>>,
+    wallets: &[WalletDecoders],
+    privacy: MemoPrivacy,
 ) -> Result<Vec<MemoNote>> {
     let mut notes = vec![];
-    let raw_tx = client
-        .get_transaction(Request::new(TxFilter {
-            hash: wtx.txid.to_vec(),
-            ..TxFilter::default()
-        }))
-        .await?
-        .into_inner();
+    let raw_tx = fetch_tx(client, wtx, privacy).await?;
     let branch_id = BranchId::for_height(network, BlockHeight::from_u32(wtx.height));
     let tx = Transaction::read(&*raw_tx.data, branch_id)?;
     let tx = tx.into_data();
 
-    if let Some(sap_dec) = sap_dec {
-        if let Some(sapling_bundle) = tx.sapling_bundle() {
+    for wallet in wallets.iter() {
+        if let (Some(sap_dec), Some(sapling_bundle)) = (&wallet.sapling, tx.sapling_bundle()) {
             for (vout, o) in sapling_bundle.shielded_outputs().iter().enumerate() {
-                if let Some(note) =
-                    sap_dec.try_note_decryption(vout as u32 + wtx.sap_position, o)?
-                {
+                if let Some(note) = sap_dec.try_note_decryption(
+                    network,
+                    wtx.height,
+                    vout as u32 + wtx.sap_position,
+                    o,
+                )? {
                     notes.push(note);
                 }
             }
         }
-    }
-    if let Some(orc_dec) = orc_dec {
-        if let Some(orchard_bundle) = tx.orchard_bundle() {
+        if let (Some(orc_dec), Some(orchard_bundle)) = (&wallet.orchard, tx.orchard_bundle()) {
             for (vout, a) in orchard_bundle.actions().iter().enumerate() {
-                if let Some(note) =
-                    orc_dec.try_note_decryption(vout as u32 + wtx.orc_position, a)?
-                {
+                if let Some(note) = orc_dec.try_note_decryption(
+                    network,
+                    wtx.height,
+                    vout as u32 + wtx.orc_position,
+                    a,
+                )? {
                     notes.push(note);
                 }
             }
@@ -220,6 +407,61 @@ pub async fn scan_tx(
     Ok(notes)
 }
 
+/// Fetch our transaction among the other transactions of its block
+/// required by the privacy mode, in random order
+async fn fetch_tx(
+    client: &mut Client,
+    wtx: &WalletTx,
+    privacy: MemoPrivacy,
+) -> Result<RawTransaction> {
+    let block_txids = if privacy == MemoPrivacy::Direct {
+        vec![]
+    } else {
+        let block = observe_lwd(
+            "get_block",
+            client.get_block(Request::new(BlockId {
+                height: wtx.height as u64,
+                hash: vec![],
+            })),
+        )
+        .await?
+        .into_inner();
+        block.vtx.into_iter().map(|tx| tx.hash).collect()
+    };
+    let mut raw_tx = None;
+    for txid in cover_txids(&wtx.txid, block_txids, privacy) {
+        let tx = observe_lwd(
+            "get_transaction",
+            client.get_transaction(Request::new(TxFilter {
+                hash: txid.clone(),
+                ..TxFilter::default()
+            })),
+        )
+        .await?
+        .into_inner();
+        if txid == wtx.txid {
+            raw_tx = Some(tx);
+        }
+    }
+    Ok(raw_tx.unwrap())
+}
+
+/// Txids to request so that ours is hidden among those of its block
+fn cover_txids(txid: &Hash, block_txids: Vec<Vec<u8>>, privacy: MemoPrivacy) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let mut others: Vec<_> = block_txids.into_iter().filter(|t| t != txid).collect();
+    others.shuffle(&mut rng);
+    match privacy {
+        MemoPrivacy::Direct => others.clear(),
+        MemoPrivacy::Decoys(count) => others.truncate(count),
+        MemoPrivacy::Block => {}
+    }
+    let mut txids = others;
+    txids.push(txid.to_vec());
+    txids.shuffle(&mut rng);
+    txids
+}
+
 pub fn get_tree_size(tree: &str) -> Result<u32> {
     let tree = hex::decode(tree)?;
     if tree.is_empty() {
@@ -230,6 +472,39 @@ pub fn get_tree_size(tree: &str) -> Result<u32> {
     Ok(tree.size() as u32)
 }
 
+pub fn read_sapling_tree(tree: &str) -> Result<sapling_crypto::CommitmentTree> {
+    let tree = hex::decode(tree)?;
+    if tree.is_empty() {
+        return Ok(sapling_crypto::CommitmentTree::empty());
+    }
+    Ok(read_commitment_tree(&*tree)?)
+}
+
+/// Decoders of the shielded pools of a wallet
+pub struct WalletDecoders {
+    pub sapling: Option<Decoder<Sapling>>,
+    pub orchard: Option<Decoder<Orchard>>,
+}
+
+/// Decoders of a wallet. Without a full viewing key, they have no
+/// nullifier key and do not detect spends
+pub fn make_decoders(wallet: u32, vk: &ViewingKey, nfs: &HashMap<Hash, u64>) -> WalletDecoders {
+    let uivk = vk.to_uivk();
+    let ufvk = vk.ufvk();
+    let sapling = uivk.sapling().as_ref().map(|ivk| {
+        let nk = ufvk
+            .and_then(|ufvk| ufvk.sapling())
+            .map(|fvk| fvk.fvk().vk.nk);
+        Decoder::<Sapling>::new(wallet, nk, ivk.clone(), ivk.prepare(), nfs)
+    });
+    let orchard = uivk.orchard().as_ref().map(|ivk| {
+        let nk = ufvk.and_then(|ufvk| ufvk.orchard()).cloned();
+        let pivk = orchard::keys::PreparedIncomingViewingKey::new(ivk);
+        Decoder::<Orchard>::new(wallet, nk, ivk.clone(), pivk, nfs)
+    });
+    WalletDecoders { sapling, orchard }
+}
+
 pub trait Pool {
     type Address;
     type PreparedIncomingViewingKey;
@@ -243,6 +518,7 @@ pub struct Sapling;
 
 #[derive(Debug)]
 pub struct ReceivedNote {
+    pub wallet: u32,
     pub txid: Hash,
     pub pool: u8,
     pub position: u32,
@@ -260,6 +536,10 @@ pub struct ReceivedNote {
 pub struct MemoNote {
     pub nf: Hash,
     pub memo: String,
+    pub memo_type: &'static str,
+    pub memo_bytes: Vec<u8>,
+    /// Set by `Db` when the memo follows the payment id convention
+    pub payment_id: Option<String>,
 }
 
 #[derive(Debug)]
@@ -272,17 +552,24 @@ pub struct SpentNote {
 
 #[derive(Debug)]
 pub enum ScanEvent {
-    Block(u32, Hash),
+    Block(u32, Hash, Option<u32>),
     Received(ReceivedNote),
     Spent(SpentNote),
     Memo(MemoNote),
+    /// Progress of the catch-up scan of a wallet from a height to another,
+    /// in place of `Block`
+    WalletHeight(u32, u32, u32),
+    /// Transaction with notes of ours, queued to fetch their memos
+    MemoTx(WalletTx),
+    /// The memos of a queued transaction are in the `Memo` events
+    MemoFetched(Hash),
 }
 
 impl Pool for Sapling {
     type Address = PaymentAddress;
     type PreparedIncomingViewingKey = sapling_crypto::keys::PreparedIncomingViewingKey;
     type NullifierKey = NullifierDerivingKey;
-    type DiversifierKey = DiversifiableFullViewingKey;
+    type DiversifierKey = sapling_crypto::zip32::IncomingViewingKey;
     type CompactOutput = CompactSaplingOutput;
     type Output = OutputDescription<[u8; 192]>;
 }
@@ -296,12 +583,19 @@ pub trait Decode<P: Pool> {
         position: u32,
         output: &P::CompactOutput,
     ) -> Result<Option<ReceivedNote>>;
-    fn try_note_decryption(&self, position: u32, output: &P::Output) -> Result<Option<MemoNote>>;
+    fn try_note_decryption(
+        &self,
+        network: &Network,
+        height: u32,
+        position: u32,
+        output: &P::Output,
+    ) -> Result<Option<MemoNote>>;
     fn decrypt_diversifier(&self, address: &P::Address) -> Result<Option<u64>>;
 }
 
 pub struct Decoder<P: Pool> {
-    pub nk: P::NullifierKey,
+    pub wallet: u32,
+    pub nk: Option<P::NullifierKey>,
     pub dk: P::DiversifierKey,
     pub pivk: P::PreparedIncomingViewingKey,
     pub nfs: HashMap<Hash, u64>,
@@ -309,12 +603,14 @@ pub struct Decoder<P: Pool> {
 
 impl<P: Pool> Decoder<P> {
     pub fn new(
-        nk: P::NullifierKey,
+        wallet: u32,
+        nk: Option<P::NullifierKey>,
         dk: P::DiversifierKey,
         pivk: P::PreparedIncomingViewingKey,
         nfs: &HashMap<Hash, u64>,
     ) -> Self {
         Self {
+            wallet,
             nk,
             dk,
             pivk,
@@ -323,7 +619,10 @@ impl<P: Pool> Decoder<P> {
     }
 
     pub fn add_nf(&mut self, nf: Hash, value: u64) {
-        self.nfs.insert(nf, value);
+        // Without a nullifier key, `nf` is the note commitment
+        if self.nk.is_some() {
+            self.nfs.insert(nf, value);
+        }
     }
 }
 
@@ -336,16 +635,22 @@ impl Decode<Sapling> for Decoder<Sapling> {
         position: u32,
         output: &CompactSaplingOutput,
     ) -> Result<Option<ReceivedNote>> {
-        let domain = SaplingDomain::new(Zip212Enforcement::On);
+        let domain = SaplingDomain::new(network.zip212_enforcement(height));
         if let Some((note, pa)) = try_compact_note_decryption(&domain, &self.pivk, output) {
             let address = pa.encode(network);
             let diversifier = pa.diversifier().0;
             let value = note.value().inner();
             let rcm = note.rcm().to_bytes();
-            let nf = note.nf(&self.nk, position as u64);
+            // The note commitment identifies the note when
+            // the nullifier cannot be derived
+            let nf = match &self.nk {
+                Some(nk) => note.nf(nk, position as u64).0,
+                None => output.cmu.as_slice().try_into().unwrap(),
+            };
             let di = self.decrypt_diversifier(&pa)?;
 
             let note = ReceivedNote {
+                wallet: self.wallet,
                 txid: txid.try_into().unwrap(),
                 pool: 1,
                 position,
@@ -355,7 +660,7 @@ impl Decode<Sapling> for Decoder<Sapling> {
                 diversifier_index: di,
                 value,
                 rcm,
-                nf: nf.to_vec().try_into().unwrap(),
+                nf,
                 rho: None,
             };
             return Ok(Some(note));
@@ -365,15 +670,20 @@ impl Decode<Sapling> for Decoder<Sapling> {
 
     fn try_note_decryption(
         &self,
+        network: &Network,
+        height: u32,
         position: u32,
         output: &OutputDescription<[u8; 192]>,
     ) -> Result<Option<MemoNote>> {
-        let domain = SaplingDomain::new(Zip212Enforcement::On);
+        let domain = SaplingDomain::new(network.zip212_enforcement(height));
         if let Some((note, _pa, memo_bytes)) = try_note_decryption(&domain, &self.pivk, output) {
-            let nf = note.nf(&self.nk, position as u64);
+            let nf = match &self.nk {
+                Some(nk) => note.nf(nk, position as u64).0,
+                None => output.cmu().to_bytes(),
+            };
             let memo_note = MemoNote {
-                nf: nf.0,
-                memo: memo_text(&memo_bytes)?,
+                nf,
+                ..decode_memo(&memo_bytes)?
             };
             return Ok(Some(memo_note));
         }
@@ -381,7 +691,7 @@ impl Decode<Sapling> for Decoder<Sapling> {
     }
 
     fn decrypt_diversifier(&self, address: &PaymentAddress) -> Result<Option<u64>> {
-        if let Some((di, _)) = self.dk.decrypt_diversifier(address) {
+        if let Some(di) = self.dk.decrypt_diversifier(address) {
             let di: u64 = di.try_into()?;
             return Ok(Some(di));
         }
@@ -424,11 +734,15 @@ impl Decode<Orchard> for Decoder<Orchard> {
             let diversifier = *address.diversifier().as_array();
             let value = note.value().inner();
             let rcm = *note.rseed().as_bytes();
-            let nf = note.nullifier(&self.nk);
+            let nf = match &self.nk {
+                Some(nk) => note.nullifier(nk).to_bytes(),
+                None => action.cmx.as_slice().try_into().unwrap(),
+            };
             let rho = note.rho().to_bytes();
             let di = self.decrypt_diversifier(&address)?;
 
             let note = ReceivedNote {
+                wallet: self.wallet,
                 txid: txid.try_into().unwrap(),
                 pool: 2,
                 position,
@@ -438,7 +752,7 @@ impl Decode<Orchard> for Decoder<Orchard> {
                 diversifier_index: di,
                 value,
                 rcm,
-                nf: nf.to_bytes(),
+                nf,
                 rho: Some(rho),
             };
             return Ok(Some(note));
@@ -448,16 +762,21 @@ impl Decode<Orchard> for Decoder<Orchard> {
 
     fn try_note_decryption(
         &self,
+        _network: &Network,
+        _height: u32,
         _position: u32,
         action: &Action<Signature<SpendAuth>>,
     ) -> Result<Option<MemoNote>> {
         let domain = OrchardDomain::for_action(action);
         if let Some((note, _address, memo_bytes)) = try_note_decryption(&domain, &self.pivk, action)
         {
-            let nf = note.nullifier(&self.nk);
+            let nf = match &self.nk {
+                Some(nk) => note.nullifier(nk).to_bytes(),
+                None => action.cmx().to_bytes(),
+            };
             let memo_note = MemoNote {
-                nf: nf.to_bytes(),
-                memo: memo_text(&memo_bytes)?,
+                nf,
+                ..decode_memo(&memo_bytes)?
             };
             return Ok(Some(memo_note));
         }
@@ -513,7 +832,7 @@ impl ShieldedOutput<SaplingDomain, 52> for CompactSaplingOutput {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct WalletTx {
     pub height: u32,
     pub txid: Hash,
@@ -521,21 +840,39 @@ pub struct WalletTx {
     pub orc_position: u32,
 }
 
-pub fn memo_text(memo_bytes: &[u8]) -> Result<String> {
-    let memo_bytes = MemoBytes::from_bytes(memo_bytes)?;
-    let memo = Memo::try_from(memo_bytes)?;
-    let memo = if let Memo::Text(memo) = memo {
-        memo.to_string()
-    } else {
-        String::new()
+/// Text and type of a memo (ZIP-302). Only text memos have a text,
+/// the others are kept as raw bytes
+pub fn decode_memo(memo_bytes: &[u8]) -> Result<MemoNote> {
+    let (memo, memo_type) = match Memo::try_from(MemoBytes::from_bytes(memo_bytes)?) {
+        Ok(Memo::Empty) => (String::new(), "empty"),
+        Ok(Memo::Text(text)) => (text.to_string(), "text"),
+        Ok(Memo::Future(_)) => (String::new(), "future"),
+        Ok(Memo::Arbitrary(_)) => (String::new(), "arbitrary"),
+        // A text memo that is not valid UTF-8
+        Err(_) => (String::new(), "text"),
     };
-    Ok(memo)
+    Ok(MemoNote {
+        nf: Hash::default(),
+        memo,
+        memo_type,
+        memo_bytes: memo_bytes.to_vec(),
+        payment_id: None,
+    })
 }
 
 #[derive(Error, Debug)]
 pub enum ScanError {
     #[error("Blockchain Reorganization")]
     Reorganization,
+    /// The (Sapling, Orchard) tree sizes after the scan differ from the server's
+    #[error("Commitment tree sizes at {height} are {computed:?}, the server has {tree_state:?}")]
+    TreeSizeMismatch {
+        height: u32,
+        computed: (u32, u32),
+        tree_state: (u32, u32),
+    },
+    #[error("Invalid header of block {height}: {reason}")]
+    InvalidHeader { height: u32, reason: String },
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -543,12 +880,155 @@ pub enum ScanError {
 #[cfg(test)]
 mod tests {
     use crate::db::Db;
+    use crate::storage::SqliteStorage;
 
     use super::*;
     use anyhow::Result;
+    use rand::rngs::OsRng;
+    use sapling_crypto::note_encryption::{sapling_note_encryption, Zip212Enforcement};
+    use sapling_crypto::value::NoteValue;
+    use sapling_crypto::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+    use sapling_crypto::{Note, Rseed};
+    use std::sync::Arc;
+    use zcash_keys::keys::UnifiedFullViewingKey;
+    use zcash_note_encryption::Domain;
 
     const FVK: &str = "uview1s5ranpd74zd2pseylw0fmt0cnudf9765mwjjd9mqf8tvjq2nlw9vgypzqayfvs7aeedguwl4r7exz50nrw6llfs3n9xfd4sm2slaay7smysc4yjyuwu3z7n5ccvyw70qkw28yt6xwra6c8d20ewpjeqq4enmftyly3fmn78hwwkyffp2y4x2vk8050vcly8y5fuse5s9e5j4wmwuldemxahrp4zrgatj63mnpqlpacvcudqfsm5ee29pj8lr5wt93eyrx3fwa64m6505cge6n46c7eqw59e0n3m9rmsntcflfmu9wyjgfk2pmjf4npkml93vyq0fps2rh4mdwpz4ld059m6mamjht99j7sdypwx52lj6lvrfgwja4uf7qy2g8d6gkmvkh7u4dksq5gazxvye4gtwfgwmuygg2sqmkkf4fjd3ymf0mq99rhf0trsl0lpddw64r4n7jj7mxy6fcpj64vkx0pre2lla9p8nknrt2c33zy3vaczd";
 
+    // Canopy activated at 1_046_400 on main
+    const BEFORE_CANOPY: u32 = 1_000_000;
+    const GRACE_PERIOD: u32 = 1_046_400;
+    const AFTER_GRACE_PERIOD: u32 = 1_046_400 + 32_256;
+
+    // Compact output of a note sent to `address`. Notes created before
+    // ZIP-212 carry rcm in their plaintext, whose lead byte is 0x01
+    fn compact_output(address: PaymentAddress, value: u64, zip212: bool) -> CompactSaplingOutput {
+        let note = Note::from_parts(
+            address,
+            NoteValue::from_raw(value),
+            Rseed::AfterZip212([7; 32]),
+        );
+        let note = if zip212 {
+            note
+        } else {
+            Note::from_parts(
+                address,
+                NoteValue::from_raw(value),
+                Rseed::BeforeZip212(note.rcm()),
+            )
+        };
+        let encryptor = sapling_note_encryption(None, note.clone(), [0xF6; 512], &mut OsRng);
+        let ciphertext = encryptor.encrypt_note_plaintext();
+        CompactSaplingOutput {
+            cmu: note.cmu().to_bytes().to_vec(),
+            epk: SaplingDomain::epk_bytes(encryptor.epk()).0.to_vec(),
+            ciphertext: ciphertext[..52].to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_cover_txids() {
+        let txid = [1; 32];
+        let block_txids: Vec<Vec<u8>> = (1..=5).map(|i| vec![i; 32]).collect();
+
+        let txids = cover_txids(&txid, block_txids.clone(), MemoPrivacy::Direct);
+        assert_eq!(txids, vec![txid.to_vec()]);
+        let txids = cover_txids(&txid, block_txids.clone(), MemoPrivacy::Decoys(2));
+        assert_eq!(txids.len(), 3);
+        assert!(txids.contains(&txid.to_vec()));
+        let mut txids = cover_txids(&txid, block_txids.clone(), MemoPrivacy::Block);
+        txids.sort();
+        assert_eq!(txids, block_txids);
+        // Not enough transactions in the block for the decoys
+        let txids = cover_txids(&txid, vec![txid.to_vec()], MemoPrivacy::Decoys(2));
+        assert_eq!(txids, vec![txid.to_vec()]);
+
+        let privacy: MemoPrivacy = serde_json::from_str(r#"{"decoys": 4}"#).unwrap();
+        assert_eq!(privacy, MemoPrivacy::Decoys(4));
+        let privacy: MemoPrivacy = serde_json::from_str(r#""block""#).unwrap();
+        assert_eq!(privacy, MemoPrivacy::Block);
+    }
+
+    #[test]
+    fn test_decode_memo() -> Result<()> {
+        let mut memo = [0u8; 512];
+        memo[..5].copy_from_slice(b"hello");
+        let decoded = decode_memo(&memo)?;
+        assert_eq!(
+            (decoded.memo.as_str(), decoded.memo_type),
+            ("hello", "text")
+        );
+        assert_eq!(decoded.memo_bytes, memo.to_vec());
+
+        let mut empty = [0u8; 512];
+        empty[0] = 0xF6;
+        assert_eq!(decode_memo(&empty)?.memo_type, "empty");
+
+        let mut arbitrary = [0xABu8; 512];
+        arbitrary[0] = 0xFF;
+        let decoded = decode_memo(&arbitrary)?;
+        assert_eq!(
+            (decoded.memo.as_str(), decoded.memo_type),
+            ("", "arbitrary")
+        );
+        assert_eq!(decoded.memo_bytes, arbitrary.to_vec());
+
+        let mut future = [0u8; 512];
+        future[0] = 0xF5;
+        assert_eq!(decode_memo(&future)?.memo_type, "future");
+
+        let mut invalid = [0u8; 512];
+        invalid[..2].copy_from_slice(&[0xC3, 0x28]);
+        assert_eq!(decode_memo(&invalid)?.memo_type, "text");
+        Ok(())
+    }
+
+    #[test]
+    fn test_zip212_enforcement() -> Result<()> {
+        let network = Network::Main;
+        assert_eq!(
+            network.zip212_enforcement(BEFORE_CANOPY),
+            Zip212Enforcement::Off
+        );
+        assert_eq!(
+            network.zip212_enforcement(GRACE_PERIOD),
+            Zip212Enforcement::GracePeriod
+        );
+        assert_eq!(
+            network.zip212_enforcement(AFTER_GRACE_PERIOD),
+            Zip212Enforcement::On
+        );
+        assert_eq!(
+            Network::Regtest.zip212_enforcement(1),
+            Zip212Enforcement::GracePeriod
+        );
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1; 32]));
+        let (_, address) = extfvk.default_address();
+        let ufvk = UnifiedFullViewingKey::from_sapling_extended_full_viewing_key(extfvk).unwrap();
+        let decoders = make_decoders(0, &ViewingKey::Full(ufvk), &HashMap::new());
+        let decoder = decoders.sapling.unwrap();
+        let decrypt = |output: &CompactSaplingOutput, height: u32| {
+            decoder
+                .try_compact_note_decryption(&network, height, &[0; 32], 0, output)
+                .map(|note| note.map(|n| n.value))
+        };
+
+        // Both kinds of outputs are encrypted here, they do not come from
+        // mainnet blocks. This checks which plaintexts are accepted at each
+        // height, not that the notes of the chain decrypt
+        let legacy = compact_output(address, 10_000, false);
+        assert_eq!(decrypt(&legacy, BEFORE_CANOPY)?, Some(10_000));
+        assert_eq!(decrypt(&legacy, GRACE_PERIOD)?, Some(10_000));
+        assert_eq!(decrypt(&legacy, AFTER_GRACE_PERIOD)?, None);
+
+        let output = compact_output(address, 20_000, true);
+        assert_eq!(decrypt(&output, BEFORE_CANOPY)?, None);
+        assert_eq!(decrypt(&output, GRACE_PERIOD)?, Some(20_000));
+        assert_eq!(decrypt(&output, AFTER_GRACE_PERIOD)?, Some(20_000));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test() -> Result<()> {
         let mut client = CompactTxStreamerClient::connect("https://zec.rocks".to_string()).await?;
@@ -556,34 +1036,38 @@ mod tests {
         let prev_hash =
             hex::decode("5f03d35ae940bb840564c3b7af7ab72255096d3eca15c910c0e40d0000000000")
                 .unwrap();
-        let ufvk = zcash_keys::keys::UnifiedFullViewingKey::decode(&Network::Main, FVK).unwrap();
-        let mut sap_dec = ufvk.sapling().map(|fvk| {
-            let nk = fvk.fvk().vk.nk;
-            let ivk = fvk.to_ivk(zip32::Scope::External);
-            let pivk = sapling_crypto::keys::PreparedIncomingViewingKey::new(&ivk);
-            Decoder::<Sapling>::new(nk, fvk.clone(), pivk, &HashMap::new())
-        });
-        let mut orc_dec = ufvk.orchard().map(|fvk| {
-            let ivk = fvk.to_ivk(zip32::Scope::External);
-            let pivk = orchard::keys::PreparedIncomingViewingKey::new(&ivk);
-            Decoder::<Orchard>::new(fvk.clone(), ivk, pivk, &HashMap::new())
-        });
+        let vk = ViewingKey::decode(&Network::Main, FVK).unwrap();
+        let mut wallets = [make_decoders(0, &vk, &HashMap::new())];
 
-        let events = scan(
+        let mut events = scan(
             &Network::Main,
             &mut client,
             2_890_000,
             2_900_000,
             &prev_hash.try_into().unwrap(),
-            &mut sap_dec,
-            &mut orc_dec,
+            &mut wallets,
+            HeaderValidation::WhenPresent,
         )
         .await?;
 
-        println!("{events:?}");
+        // The scan ends with the last block, and the notes it finds are
+        // in the range and queued for their memos
+        assert!(matches!(
+            events.last(),
+            Some(ScanEvent::Block(2_900_000, _, _))
+        ));
+        for event in events.iter() {
+            if let ScanEvent::Received(note) = event {
+                assert!((2_890_000..=2_900_000).contains(&note.height));
+                assert!(events
+                    .iter()
+                    .any(|e| matches!(e, ScanEvent::MemoTx(wtx) if wtx.txid == note.txid)));
+            }
+        }
 
-        let db = Db::new(Network::Main, "zec-wallet-test.db", &ufvk, "").await?;
-        db.store_events(&events).await?;
+        let store = SqliteStorage::open("zec-wallet-test.db").await?;
+        let db = Db::new(Network::Main, Arc::new(store), &vk, "", None);
+        db.store_events(&mut events).await?;
 
         Ok(())
     }
@@ -0,0 +1,939 @@
+use anyhow::{bail, Result};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Acquire, Row, SqliteConnection};
+
+use crate::address::address_receivers;
+use crate::network::Network;
+
+/// A change of the schema, written for one database
+pub enum Step {
+    /// Statements run in order
+    Sql(&'static [&'static str]),
+    /// Column added to a table, unless it is already there
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+    /// Change that is not plain SQL, run by the code of the database
+    Custom(Custom),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Custom {
+    /// Tables of 1.1.2, or the upgrade of those of an older version
+    CreateSchema,
+    /// Wallet in the primary keys of the tag tables, which SQLite
+    /// cannot change in place
+    AddWalletToTags,
+    /// Receivers of pool 0 of the addresses, decoded from their UA
+    AddTransparentReceivers,
+    /// Rescan of the notes spent before their spending height was stored
+    RescanLegacySpends,
+}
+
+/// Version `n` of the schema is reached by the migration `n - 1` of the list,
+/// with the steps of the database. PostgreSQL support starts with version 4,
+/// which creates the tables of the three versions before
+pub struct Migration {
+    pub description: &'static str,
+    pub sqlite: &'static [Step],
+    pub postgres: &'static [Step],
+}
+
+/// Migrations of both databases. To change the schema, add one at the end
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Schema of 1.1.2, the first version with Orchard and UA support",
+        sqlite: &[Step::Custom(Custom::CreateSchema)],
+        postgres: &[],
+    },
+    Migration {
+        description: "Transaction that spends a note",
+        sqlite: &[Step::AddColumn {
+            table: "received_notes",
+            column: "spent_tx",
+            definition: "INTEGER",
+        }],
+        postgres: &[],
+    },
+    Migration {
+        description: "Account tags",
+        sqlite: &[Step::Sql(&[
+            "CREATE TABLE IF NOT EXISTS account_tags (
+            account INTEGER PRIMARY KEY,
+            tag TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS tags (
+            tag TEXT PRIMARY KEY,
+            description TEXT NOT NULL)",
+        ])],
+        postgres: &[],
+    },
+    Migration {
+        description: "Block times",
+        sqlite: &[Step::AddColumn {
+            table: "blocks",
+            column: "time",
+            definition: "INTEGER",
+        }],
+        postgres: &[Step::Sql(&[
+            "CREATE TABLE blocks (
+            height BIGINT PRIMARY KEY,
+            hash BYTEA NOT NULL,
+            time BIGINT)",
+            "CREATE TABLE addresses (
+            id_address BIGSERIAL PRIMARY KEY,
+            label TEXT NOT NULL,
+            account BIGINT NOT NULL,
+            sub_account BIGINT NOT NULL,
+            address TEXT NOT NULL,
+            diversifier_index BIGINT NOT NULL)",
+            "CREATE TABLE receivers (
+            id_receiver BIGSERIAL PRIMARY KEY,
+            pool BIGINT NOT NULL,
+            id_address BIGINT NOT NULL,
+            receiver_address TEXT NOT NULL)",
+            "CREATE TABLE account_tags (
+            account BIGINT PRIMARY KEY,
+            tag TEXT NOT NULL)",
+            "CREATE TABLE tags (
+            tag TEXT PRIMARY KEY,
+            description TEXT NOT NULL)",
+            "CREATE TABLE transactions (
+            id_tx BIGSERIAL PRIMARY KEY,
+            txid BYTEA NOT NULL UNIQUE,
+            height BIGINT NOT NULL,
+            value BIGINT NOT NULL)",
+            "CREATE TABLE received_notes (
+            id_note BIGSERIAL PRIMARY KEY,
+            address TEXT NOT NULL,
+            account BIGINT,
+            sub_account BIGINT,
+            id_tx BIGINT NOT NULL,
+            position BIGINT NOT NULL,
+            height BIGINT NOT NULL,
+            diversifier BYTEA NOT NULL,
+            value BIGINT NOT NULL,
+            rcm BYTEA NOT NULL,
+            nf BYTEA NOT NULL UNIQUE,
+            rho BYTEA,
+            memo TEXT,
+            spent BIGINT,
+            spent_tx BIGINT,
+            CONSTRAINT tx_output UNIQUE (position))",
+        ])],
+    },
+    Migration {
+        description: "Wallet metadata",
+        sqlite: &[Step::Sql(&["CREATE TABLE IF NOT EXISTS metadata (
+            ufvk_fingerprint TEXT NOT NULL,
+            network TEXT NOT NULL,
+            birth_height INTEGER NOT NULL)"])],
+        postgres: &[Step::Sql(&["CREATE TABLE metadata (
+            ufvk_fingerprint TEXT NOT NULL,
+            network TEXT NOT NULL,
+            birth_height BIGINT NOT NULL)"])],
+    },
+    // Accounts, notes and tags belong to a wallet. The existing ones
+    // go to wallet 0, the viewing key of the config
+    Migration {
+        description: "Wallets",
+        sqlite: &[
+            Step::Sql(&["CREATE TABLE IF NOT EXISTS wallets (
+                id_wallet INTEGER PRIMARY KEY,
+                label TEXT NOT NULL,
+                vk TEXT NOT NULL,
+                birth_height INTEGER NOT NULL)"]),
+            Step::AddColumn {
+                table: "addresses",
+                column: "wallet",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            Step::AddColumn {
+                table: "received_notes",
+                column: "wallet",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            Step::Custom(Custom::AddWalletToTags),
+        ],
+        postgres: &[
+            Step::Sql(&["CREATE TABLE wallets (
+                id_wallet BIGINT PRIMARY KEY,
+                label TEXT NOT NULL,
+                vk TEXT NOT NULL,
+                birth_height BIGINT NOT NULL)"]),
+            Step::AddColumn {
+                table: "addresses",
+                column: "wallet",
+                definition: "BIGINT NOT NULL DEFAULT 0",
+            },
+            Step::AddColumn {
+                table: "received_notes",
+                column: "wallet",
+                definition: "BIGINT NOT NULL DEFAULT 0",
+            },
+            Step::Sql(&[
+                "ALTER TABLE account_tags ADD COLUMN wallet BIGINT NOT NULL DEFAULT 0,
+                DROP CONSTRAINT account_tags_pkey, ADD PRIMARY KEY (wallet, account)",
+                "ALTER TABLE tags ADD COLUMN wallet BIGINT NOT NULL DEFAULT 0,
+                DROP CONSTRAINT tags_pkey, ADD PRIMARY KEY (wallet, tag)",
+            ]),
+        ],
+    },
+    Migration {
+        description: "Scan height of the wallets",
+        sqlite: &[Step::AddColumn {
+            table: "wallets",
+            column: "scan_height",
+            definition: "INTEGER",
+        }],
+        postgres: &[Step::AddColumn {
+            table: "wallets",
+            column: "scan_height",
+            definition: "BIGINT",
+        }],
+    },
+    // Memos that are not text were dropped before
+    Migration {
+        description: "Memo types and bytes",
+        sqlite: &[
+            Step::AddColumn {
+                table: "received_notes",
+                column: "memo_type",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "received_notes",
+                column: "memo_bytes",
+                definition: "BLOB",
+            },
+        ],
+        postgres: &[
+            Step::AddColumn {
+                table: "received_notes",
+                column: "memo_type",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "received_notes",
+                column: "memo_bytes",
+                definition: "BYTEA",
+            },
+        ],
+    },
+    // Payments are looked up by id
+    Migration {
+        description: "Payment ids",
+        sqlite: &[
+            Step::AddColumn {
+                table: "received_notes",
+                column: "payment_id",
+                definition: "TEXT",
+            },
+            Step::Sql(&["CREATE INDEX IF NOT EXISTS i_received_notes_payment_id
+                ON received_notes(wallet, payment_id)"]),
+        ],
+        postgres: &[
+            Step::AddColumn {
+                table: "received_notes",
+                column: "payment_id",
+                definition: "TEXT",
+            },
+            Step::Sql(&["CREATE INDEX IF NOT EXISTS i_received_notes_payment_id
+                ON received_notes(wallet, payment_id)"]),
+        ],
+    },
+    // The memos of the notes stored before were fetched with their block
+    Migration {
+        description: "Queue of the memos to fetch",
+        sqlite: &[
+            Step::AddColumn {
+                table: "received_notes",
+                column: "memo_status",
+                definition: "TEXT NOT NULL DEFAULT 'fetched'",
+            },
+            Step::Sql(&["CREATE TABLE IF NOT EXISTS memo_queue (
+                txid BLOB PRIMARY KEY,
+                height INTEGER NOT NULL,
+                sap_position INTEGER NOT NULL,
+                orc_position INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt INTEGER NOT NULL DEFAULT 0)"]),
+        ],
+        postgres: &[
+            Step::AddColumn {
+                table: "received_notes",
+                column: "memo_status",
+                definition: "TEXT NOT NULL DEFAULT 'fetched'",
+            },
+            Step::Sql(&["CREATE TABLE IF NOT EXISTS memo_queue (
+                txid BYTEA PRIMARY KEY,
+                height BIGINT NOT NULL,
+                sap_position BIGINT NOT NULL,
+                orc_position BIGINT NOT NULL,
+                attempts BIGINT NOT NULL DEFAULT 0,
+                next_attempt BIGINT NOT NULL DEFAULT 0)"]),
+        ],
+    },
+    // Only the shielded receivers of the addresses were stored before,
+    // their transparent receivers could not be looked up
+    Migration {
+        description: "Transparent receivers",
+        sqlite: &[Step::Custom(Custom::AddTransparentReceivers)],
+        postgres: &[Step::Custom(Custom::AddTransparentReceivers)],
+    },
+    // Notes spent by the earlier versions have `spent = 1` and no `spent_tx`.
+    // A reorg cannot unspend them, and the spending height is lost
+    Migration {
+        description: "Spending height of the old spent notes",
+        sqlite: &[Step::Custom(Custom::RescanLegacySpends)],
+        postgres: &[Step::Custom(Custom::RescanLegacySpends)],
+    },
+    // Wallets whose keys overlap receive the same notes.
+    // SQLite cannot change the constraints in place
+    Migration {
+        description: "Nullifiers and positions unique per wallet",
+        sqlite: &[Step::Sql(&[
+            "CREATE TABLE received_notes_new (
+            id_note INTEGER PRIMARY KEY,
+            wallet INTEGER NOT NULL DEFAULT 0,
+            address TEXT NOT NULL,
+            account INTEGER,
+            sub_account INTEGER,
+            id_tx INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            diversifier BLOB NOT NULL,
+            value INTEGER NOT NULL,
+            rcm BLOB NOT NULL,
+            nf BLOB NOT NULL,
+            rho BLOB,
+            memo TEXT,
+            memo_type TEXT,
+            memo_bytes BLOB,
+            memo_status TEXT NOT NULL DEFAULT 'fetched',
+            payment_id TEXT,
+            spent INTEGER,
+            spent_tx INTEGER,
+            CONSTRAINT received_notes_nf UNIQUE (wallet, nf),
+            CONSTRAINT tx_output UNIQUE (wallet, position))",
+            "INSERT INTO received_notes_new(id_note, wallet, address, account, sub_account,
+            id_tx, position, height, diversifier, value, rcm, nf, rho, memo, memo_type,
+            memo_bytes, memo_status, payment_id, spent, spent_tx)
+            SELECT id_note, wallet, address, account, sub_account,
+            id_tx, position, height, diversifier, value, rcm, nf, rho, memo, memo_type,
+            memo_bytes, memo_status, payment_id, spent, spent_tx FROM received_notes",
+            "DROP TABLE received_notes",
+            "ALTER TABLE received_notes_new RENAME TO received_notes",
+            "CREATE INDEX i_received_notes_payment_id ON received_notes(wallet, payment_id)",
+        ])],
+        postgres: &[Step::Sql(&[
+            "ALTER TABLE received_notes DROP CONSTRAINT received_notes_nf_key,
+            DROP CONSTRAINT tx_output,
+            ADD CONSTRAINT received_notes_nf UNIQUE (wallet, nf),
+            ADD CONSTRAINT tx_output UNIQUE (wallet, position)",
+        ])],
+    },
+    // The positions of the Sapling and Orchard trees overlap.
+    // Only the Orchard notes have a rho
+    Migration {
+        description: "Pool of the notes",
+        sqlite: &[Step::Sql(&[
+            "CREATE TABLE received_notes_new (
+            id_note INTEGER PRIMARY KEY,
+            wallet INTEGER NOT NULL DEFAULT 0,
+            pool INTEGER NOT NULL,
+            address TEXT NOT NULL,
+            account INTEGER,
+            sub_account INTEGER,
+            id_tx INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            diversifier BLOB NOT NULL,
+            value INTEGER NOT NULL,
+            rcm BLOB NOT NULL,
+            nf BLOB NOT NULL,
+            rho BLOB,
+            memo TEXT,
+            memo_type TEXT,
+            memo_bytes BLOB,
+            memo_status TEXT NOT NULL DEFAULT 'fetched',
+            payment_id TEXT,
+            spent INTEGER,
+            spent_tx INTEGER,
+            CONSTRAINT received_notes_nf UNIQUE (wallet, nf),
+            CONSTRAINT tx_output UNIQUE (wallet, pool, position))",
+            "INSERT INTO received_notes_new(id_note, wallet, pool, address, account, sub_account,
+            id_tx, position, height, diversifier, value, rcm, nf, rho, memo, memo_type,
+            memo_bytes, memo_status, payment_id, spent, spent_tx)
+            SELECT id_note, wallet, CASE WHEN rho IS NULL THEN 1 ELSE 2 END, address, account,
+            sub_account, id_tx, position, height, diversifier, value, rcm, nf, rho, memo,
+            memo_type, memo_bytes, memo_status, payment_id, spent, spent_tx FROM received_notes",
+            "DROP TABLE received_notes",
+            "ALTER TABLE received_notes_new RENAME TO received_notes",
+            "CREATE INDEX i_received_notes_payment_id ON received_notes(wallet, payment_id)",
+        ])],
+        postgres: &[Step::Sql(&[
+            "ALTER TABLE received_notes ADD COLUMN pool BIGINT",
+            "UPDATE received_notes SET pool = CASE WHEN rho IS NULL THEN 1 ELSE 2 END",
+            "ALTER TABLE received_notes ALTER COLUMN pool SET NOT NULL,
+            DROP CONSTRAINT tx_output,
+            ADD CONSTRAINT tx_output UNIQUE (wallet, pool, position)",
+        ])],
+    },
+];
+
+/// Version of the schema created by this build
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Bring the database schema up to `SCHEMA_VERSION`.
+/// Each migration runs in its own transaction, together with
+/// the update of the version stored in `schema_version`
+pub async fn migrate(network: &Network, connection: &mut SqliteConnection) -> Result<()> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+        .execute(&mut *connection)
+        .await?;
+    let mut version = get_schema_version(connection).await?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "Database schema version {version} is more recent than this version of zcash-walletd ({SCHEMA_VERSION})"
+        );
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        let mut db_tx = connection.begin().await?;
+        for step in migration.sqlite {
+            apply(network, &mut db_tx, step).await?;
+        }
+        version += 1;
+        sqlx::query("DELETE FROM schema_version")
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query("INSERT INTO schema_version(version) VALUES (?1)")
+            .bind(version)
+            .execute(&mut *db_tx)
+            .await?;
+        db_tx.commit().await?;
+        info!(
+            "Database schema migrated to version {version}: {}",
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+pub async fn get_schema_version(connection: &mut SqliteConnection) -> Result<u32> {
+    let version = sqlx::query("SELECT version FROM schema_version")
+        .map(|row: SqliteRow| row.get::<u32, _>(0))
+        .fetch_optional(&mut *connection)
+        .await?;
+    Ok(version.unwrap_or_default())
+}
+
+// Databases created before the schema was versioned start at 0.
+// Their tables may already be up to date, so migrations must
+// not fail if they were partially applied
+async fn apply(network: &Network, connection: &mut SqliteConnection, step: &Step) -> Result<()> {
+    match step {
+        Step::Sql(statements) => {
+            for statement in statements.iter() {
+                sqlx::query(statement).execute(&mut *connection).await?;
+            }
+            Ok(())
+        }
+        Step::AddColumn {
+            table,
+            column,
+            definition,
+        } => add_column(connection, table, column, definition).await,
+        Step::Custom(Custom::CreateSchema) => create_schema(network, connection).await,
+        Step::Custom(Custom::AddWalletToTags) => add_wallet_to_tags(connection).await,
+        Step::Custom(Custom::AddTransparentReceivers) => {
+            add_transparent_receivers(network, connection).await
+        }
+        Step::Custom(Custom::RescanLegacySpends) => rescan_legacy_spends(connection).await,
+    }
+}
+
+// Schema of 1.1.2, the first version with Orchard and UA support
+async fn create_schema(network: &Network, connection: &mut SqliteConnection) -> Result<()> {
+    if has_table(connection, "received_notes").await?
+        && !has_column(connection, "received_notes", "rho").await?
+    {
+        return upgrade_pre_orchard_schema(network, connection).await;
+    }
+    create_tables(connection).await
+}
+
+async fn create_tables(connection: &mut SqliteConnection) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS blocks (
+        height INTEGER PRIMARY KEY,
+        hash BLOB NOT NULL)",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS addresses (
+        id_address INTEGER PRIMARY KEY,
+        label TEXT NOT NULL,
+        account INTEGER NOT NULL,
+        sub_account INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        diversifier_index INTEGER NOT NULL)",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS receivers (
+        id_receiver INTEGER PRIMARY KEY,
+        pool INTEGER NOT NULL,
+        id_address INTEGER NOT NULL,
+        receiver_address TEXT NOT NULL)",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS transactions (
+        id_tx INTEGER PRIMARY KEY,
+        txid BLOB NOT NULL UNIQUE,
+        height INTEGER NOT NULL,
+        value INTEGER NOT NULL)",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS received_notes (
+        id_note INTEGER PRIMARY KEY,
+        address TEXT NOT NULL,
+        account INTEGER,
+        sub_account INTEGER,
+        id_tx INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        diversifier BLOB NOT NULL,
+        value INTEGER NOT NULL,
+        rcm BLOB NOT NULL,
+        nf BLOB NOT NULL UNIQUE,
+        rho BLOB,
+        memo TEXT,
+        spent INTEGER,
+        CONSTRAINT tx_output UNIQUE (position))",
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Before 1.1.2, notes were stored without `rho` and addresses
+/// had no receivers. Orchard notes cannot be spent tracked without
+/// `rho`, so we keep the addresses, drop the sync data and
+/// let the wallet rescan from the birth height
+async fn upgrade_pre_orchard_schema(
+    network: &Network,
+    connection: &mut SqliteConnection,
+) -> Result<()> {
+    warn!("Upgrading a database from before 1.1.2. The wallet will rescan from the birth height");
+    for table in ["received_notes", "transactions", "blocks"] {
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&mut *connection)
+            .await?;
+    }
+    create_tables(connection).await?;
+
+    let addresses = sqlx::query(
+        "SELECT a.id_address, a.address FROM addresses a
+        WHERE NOT EXISTS (SELECT 1 FROM receivers r WHERE r.id_address = a.id_address)",
+    )
+    .map(|row: SqliteRow| (row.get::<u32, _>(0), row.get::<String, _>(1)))
+    .fetch_all(&mut *connection)
+    .await?;
+    for (id_address, address) in addresses {
+        for (pool, receiver) in address_receivers(network, &address)? {
+            sqlx::query(
+                "INSERT INTO receivers(pool, id_address, receiver_address)
+                VALUES (?1, ?2, ?3)",
+            )
+            .bind(pool)
+            .bind(id_address)
+            .bind(receiver)
+            .execute(&mut *connection)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+// The primary keys of the tag tables change, they must be rebuilt
+async fn add_wallet_to_tags(connection: &mut SqliteConnection) -> Result<()> {
+    if !has_column(connection, "account_tags", "wallet").await? {
+        sqlx::query(
+            "CREATE TABLE account_tags_new (
+            wallet INTEGER NOT NULL,
+            account INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (wallet, account))",
+        )
+        .execute(&mut *connection)
+        .await?;
+        sqlx::query(
+            "INSERT INTO account_tags_new(wallet, account, tag)
+            SELECT 0, account, tag FROM account_tags",
+        )
+        .execute(&mut *connection)
+        .await?;
+        sqlx::query("DROP TABLE account_tags")
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query("ALTER TABLE account_tags_new RENAME TO account_tags")
+            .execute(&mut *connection)
+            .await?;
+    }
+    if !has_column(connection, "tags", "wallet").await? {
+        sqlx::query(
+            "CREATE TABLE tags_new (
+            wallet INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            description TEXT NOT NULL,
+            PRIMARY KEY (wallet, tag))",
+        )
+        .execute(&mut *connection)
+        .await?;
+        sqlx::query(
+            "INSERT INTO tags_new(wallet, tag, description)
+            SELECT 0, tag, description FROM tags",
+        )
+        .execute(&mut *connection)
+        .await?;
+        sqlx::query("DROP TABLE tags")
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query("ALTER TABLE tags_new RENAME TO tags")
+            .execute(&mut *connection)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn add_transparent_receivers(
+    network: &Network,
+    connection: &mut SqliteConnection,
+) -> Result<()> {
+    let addresses = sqlx::query(
+        "SELECT a.id_address, a.address FROM addresses a
+        WHERE NOT EXISTS (SELECT 1 FROM receivers r
+        WHERE r.id_address = a.id_address AND r.pool = 0)",
+    )
+    .map(|row: SqliteRow| (row.get::<u32, _>(0), row.get::<String, _>(1)))
+    .fetch_all(&mut *connection)
+    .await?;
+    for (id_address, address) in addresses {
+        for (pool, receiver) in address_receivers(network, &address)? {
+            if pool != 0 {
+                continue;
+            }
+            sqlx::query(
+                "INSERT INTO receivers(pool, id_address, receiver_address)
+                VALUES (?1, ?2, ?3)",
+            )
+            .bind(pool)
+            .bind(id_address)
+            .bind(receiver)
+            .execute(&mut *connection)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+// The blocks from the first of these notes are scanned again,
+// as after a reorg, and find their spends
+async fn rescan_legacy_spends(connection: &mut SqliteConnection) -> Result<()> {
+    let height =
+        sqlx::query("SELECT MIN(height) FROM received_notes WHERE spent = 1 AND spent_tx IS NULL")
+            .map(|row: SqliteRow| row.get::<Option<u32>, _>(0))
+            .fetch_one(&mut *connection)
+            .await?;
+    let Some(height) = height else {
+        return Ok(());
+    };
+    warn!("Notes were spent before their spending height was stored. The wallet will rescan from {height}");
+    for statement in [
+        "DELETE FROM transactions WHERE height >= ?1",
+        "DELETE FROM blocks WHERE height >= ?1",
+        "UPDATE received_notes SET spent = 0, spent_tx = NULL WHERE spent >= ?1",
+        // The notes with `spent = 1` are all at or above `height`
+        "DELETE FROM received_notes WHERE height >= ?1",
+        "UPDATE wallets SET scan_height = ?1 - 1 WHERE scan_height >= ?1",
+        "DELETE FROM memo_queue WHERE height >= ?1",
+    ] {
+        sqlx::query(statement)
+            .bind(height)
+            .execute(&mut *connection)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn add_column(
+    connection: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    if !has_column(connection, table, column).await? {
+        sqlx::query(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))
+        .execute(&mut *connection)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn has_table(connection: &mut SqliteConnection, table: &str) -> Result<bool> {
+    let r = sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")
+        .bind(table)
+        .fetch_optional(&mut *connection)
+        .await?;
+    Ok(r.is_some())
+}
+
+async fn has_column(connection: &mut SqliteConnection, table: &str, column: &str) -> Result<bool> {
+    let r = sqlx::query("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(&mut *connection)
+        .await?;
+    Ok(r.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use sqlx::{ConnectOptions, Connection};
+    use zcash_keys::address::{Address, UnifiedAddress};
+    use zcash_keys::encoding::AddressCodec;
+    use zcash_primitives::legacy::TransparentAddress;
+
+    #[tokio::test]
+    async fn test_upgrade_pre_orchard_schema() -> Result<()> {
+        let mut connection = SqliteConnectOptions::new()
+            .filename(":memory:")
+            .connect()
+            .await?;
+        sqlx::query(
+            "CREATE TABLE addresses (
+            id_address INTEGER PRIMARY KEY,
+            label TEXT NOT NULL,
+            account INTEGER NOT NULL,
+            sub_account INTEGER NOT NULL,
+            address TEXT NOT NULL,
+            diversifier_index INTEGER NOT NULL)",
+        )
+        .execute(&mut connection)
+        .await?;
+        sqlx::query(
+            "INSERT INTO addresses(label, account, sub_account, address, diversifier_index)
+            VALUES ('', 0, 0, 'zregtestsapling1qag0mpkwcratr9zweyk973dzukaln3svpl0v8fpydajq8aq8ghsq0ah3my0qc2admygg6xt4snh', 0)",
+        )
+        .execute(&mut connection)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE received_notes (
+            id_note INTEGER PRIMARY KEY,
+            address TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            nf BLOB NOT NULL UNIQUE)",
+        )
+        .execute(&mut connection)
+        .await?;
+        sqlx::query("CREATE TABLE transactions (id_tx INTEGER PRIMARY KEY)")
+            .execute(&mut connection)
+            .await?;
+        sqlx::query("CREATE TABLE blocks (height INTEGER PRIMARY KEY, hash BLOB NOT NULL)")
+            .execute(&mut connection)
+            .await?;
+
+        migrate(&Network::Regtest, &mut connection).await?;
+        assert_eq!(get_schema_version(&mut connection).await?, SCHEMA_VERSION);
+        assert!(has_column(&mut connection, "received_notes", "rho").await?);
+        assert!(has_column(&mut connection, "received_notes", "spent_tx").await?);
+        assert!(has_column(&mut connection, "blocks", "time").await?);
+        assert!(has_table(&mut connection, "metadata").await?);
+        assert!(has_column(&mut connection, "addresses", "wallet").await?);
+        assert!(has_column(&mut connection, "account_tags", "wallet").await?);
+        assert!(has_column(&mut connection, "received_notes", "payment_id").await?);
+        assert!(has_table(&mut connection, "memo_queue").await?);
+        let pools: Vec<u8> = sqlx::query("SELECT pool FROM receivers WHERE id_address = 1")
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_all(&mut connection)
+            .await?;
+        assert_eq!(pools, vec![1]);
+
+        // Running again is a no-op
+        migrate(&Network::Regtest, &mut connection).await?;
+        assert_eq!(get_schema_version(&mut connection).await?, SCHEMA_VERSION);
+        connection.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_transparent_receivers() -> Result<()> {
+        let network = Network::Regtest;
+        let mut connection = SqliteConnectOptions::new()
+            .filename(":memory:")
+            .connect()
+            .await?;
+        migrate(&network, &mut connection).await?;
+
+        let Some(Address::Sapling(pa)) = Address::decode(
+            &network,
+            "zregtestsapling1qag0mpkwcratr9zweyk973dzukaln3svpl0v8fpydajq8aq8ghsq0ah3my0qc2admygg6xt4snh",
+        ) else {
+            panic!("Not a Sapling address");
+        };
+        let ta = TransparentAddress::PublicKeyHash([7; 20]);
+        let ua = UnifiedAddress::from_receivers(None, Some(pa), Some(ta)).unwrap();
+        sqlx::query(
+            "INSERT INTO addresses(label, account, sub_account, address, diversifier_index)
+            VALUES ('', 0, 0, ?1, 0)",
+        )
+        .bind(ua.encode(&network))
+        .execute(&mut connection)
+        .await?;
+        sqlx::query("INSERT INTO receivers(pool, id_address, receiver_address) VALUES (1, 1, ?1)")
+            .bind(pa.encode(&network))
+            .execute(&mut connection)
+            .await?;
+
+        // The address was stored before the migration to version 11
+        sqlx::query("UPDATE schema_version SET version = 10")
+            .execute(&mut connection)
+            .await?;
+        migrate(&network, &mut connection).await?;
+        let receivers: Vec<(u8, String)> = sqlx::query(
+            "SELECT pool, receiver_address FROM receivers WHERE id_address = 1 ORDER BY pool",
+        )
+        .map(|row: SqliteRow| (row.get(0), row.get(1)))
+        .fetch_all(&mut connection)
+        .await?;
+        assert_eq!(
+            receivers,
+            vec![(0, ta.encode(&network)), (1, pa.encode(&network))]
+        );
+        connection.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rescan_legacy_spends() -> Result<()> {
+        let network = Network::Regtest;
+        let mut connection = SqliteConnectOptions::new()
+            .filename(":memory:")
+            .connect()
+            .await?;
+        migrate(&network, &mut connection).await?;
+
+        // Spent at 150 with its height, by the previous version at an unknown
+        // height, and unspent
+        for (id_note, height, spent, spent_tx) in [
+            (1, 100, Some(150), Some(2)),
+            (2, 120, Some(1), None),
+            (3, 130, None, None),
+        ] {
+            sqlx::query(
+                "INSERT INTO received_notes(id_note, pool, address, id_tx, position, height,
+                diversifier, value, rcm, nf, spent, spent_tx)
+                VALUES (?1, 1, '', ?1, ?1, ?2, x'', 0, x'', ?1, ?3, ?4)",
+            )
+            .bind(id_note)
+            .bind(height)
+            .bind(spent)
+            .bind(spent_tx)
+            .execute(&mut connection)
+            .await?;
+        }
+        for height in [100, 120, 130, 150] {
+            sqlx::query("INSERT INTO blocks(height, hash) VALUES (?1, x'')")
+                .bind(height)
+                .execute(&mut connection)
+                .await?;
+        }
+
+        // The notes were stored before the migration to version 12
+        sqlx::query("UPDATE schema_version SET version = 11")
+            .execute(&mut connection)
+            .await?;
+        migrate(&network, &mut connection).await?;
+        let notes: Vec<(u32, Option<u32>)> =
+            sqlx::query("SELECT id_note, spent FROM received_notes ORDER BY id_note")
+                .map(|row: SqliteRow| (row.get(0), row.get(1)))
+                .fetch_all(&mut connection)
+                .await?;
+        assert_eq!(notes, vec![(1, Some(0))]);
+        let blocks: Vec<u32> = sqlx::query("SELECT height FROM blocks")
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_all(&mut connection)
+            .await?;
+        assert_eq!(blocks, vec![100]);
+        connection.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notes_per_wallet() -> Result<()> {
+        let mut connection = SqliteConnectOptions::new()
+            .filename(":memory:")
+            .connect()
+            .await?;
+        migrate(&Network::Regtest, &mut connection).await?;
+
+        let insert = "INSERT INTO received_notes(wallet, pool, address, id_tx, position,
+            height, diversifier, value, rcm, nf) VALUES (?1, 1, '', 1, 5, 100, x'', 0, x'', x'01')";
+        for wallet in [0, 1] {
+            sqlx::query(insert)
+                .bind(wallet)
+                .execute(&mut connection)
+                .await?;
+        }
+        assert!(sqlx::query(insert)
+            .bind(1)
+            .execute(&mut connection)
+            .await
+            .is_err());
+        assert!(has_column(&mut connection, "received_notes", "payment_id").await?);
+        connection.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_note_pools() -> Result<()> {
+        let mut connection = SqliteConnectOptions::new()
+            .filename(":memory:")
+            .connect()
+            .await?;
+        migrate(&Network::Regtest, &mut connection).await?;
+
+        // A Sapling and an Orchard note at the same position
+        for (pool, nf, rho) in [(1, vec![1u8], None), (2, vec![2u8], Some(vec![0u8]))] {
+            sqlx::query(
+                "INSERT INTO received_notes(pool, address, id_tx, position, height,
+                diversifier, value, rcm, nf, rho) VALUES (?1, '', 1, 5, 100, x'', 0, x'', ?2, ?3)",
+            )
+            .bind(pool)
+            .bind(nf)
+            .bind(rho)
+            .execute(&mut connection)
+            .await?;
+        }
+        connection.close().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::account::{AccountBalance, AddressInfo, SubAccountBalance};
+use crate::network::Network;
+use crate::scan::{ScanEvent, WalletTx};
+use crate::transaction::{MemoEncoding, SubAddress, Transfer, TransferFilter};
+use crate::{Hash, WalletConfig};
+
+mod migrations;
+mod postgres;
+mod sqlite;
+
+pub use migrations::SCHEMA_VERSION;
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// Persistence of the wallet data. `Db` implements the wallet logic
+/// (key derivation, notifications) on top of it
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    /// Bring the schema up to date and remove the data left by an interrupted scan.
+    /// Returns true if the database already has accounts
+    async fn create(&self, network: &Network) -> Result<bool>;
+    /// Viewing key, network and birth height the database is bound to
+    async fn get_metadata(&self) -> Result<Option<WalletMetadata>>;
+    async fn set_metadata(&self, metadata: &WalletMetadata) -> Result<()>;
+    /// Delete the addresses and the sync data, and bind the database
+    /// to another viewing key
+    async fn reset(&self, metadata: &WalletMetadata) -> Result<()>;
+
+    async fn get_wallets(&self) -> Result<Vec<WalletKey>>;
+    async fn store_wallet(&self, wallet: &WalletKey) -> Result<()>;
+    async fn set_wallet_scan_height(&self, wallet: u32, height: Option<u32>) -> Result<()>;
+    /// Delete a wallet with its addresses, notes and tags
+    async fn delete_wallet(&self, wallet: u32) -> Result<()>;
+
+    async fn max_account(&self, wallet: u32) -> Result<Option<u32>>;
+    async fn max_sub_account(&self, wallet: u32, account: u32) -> Result<Option<u32>>;
+    async fn max_diversifier_index(&self, wallet: u32) -> Result<Option<u64>>;
+    /// Store an address and its receivers as (pool, receiver address)
+    async fn store_address(
+        &self,
+        wallet: u32,
+        label: &str,
+        account: u32,
+        sub_account: u32,
+        diversifier_index: u64,
+        address: &str,
+        receivers: &[(u8, String)],
+    ) -> Result<()>;
+    async fn get_addresses(
+        &self,
+        wallet: u32,
+        account: u32,
+        sub_accounts: &[u32],
+    ) -> Result<Vec<AddressInfo>>;
+    /// Account and sub account of an address or of one of its receivers
+    async fn find_address(&self, wallet: u32, address: &str) -> Result<Option<SubAddress>>;
+    async fn get_accounts(
+        &self,
+        wallet: u32,
+        confirmed_height: u32,
+        tag: Option<&str>,
+    ) -> Result<Vec<AccountBalance>>;
+    async fn get_subaddress_balances(
+        &self,
+        wallet: u32,
+        height: u32,
+        account_index: u32,
+        sub_accounts: &[u32],
+        confirmations: u32,
+    ) -> Result<Vec<SubAccountBalance>>;
+    async fn set_address_label(
+        &self,
+        wallet: u32,
+        account: u32,
+        sub_account: u32,
+        label: &str,
+    ) -> Result<()>;
+    async fn tag_accounts(&self, wallet: u32, tag: &str, accounts: &[u32]) -> Result<()>;
+    async fn untag_accounts(&self, wallet: u32, accounts: &[u32]) -> Result<()>;
+    async fn set_tag_description(&self, wallet: u32, tag: &str, description: &str) -> Result<()>;
+    /// (tag, description, account) ordered by tag and account
+    async fn get_tagged_accounts(&self, wallet: u32) -> Result<Vec<(String, String, u32)>>;
+
+    async fn get_synced_height(&self) -> Result<Option<u32>>;
+    async fn get_block_hash(&self, height: u32) -> Result<Option<Hash>>;
+    async fn get_block_time(&self, height: u32) -> Result<Option<u32>>;
+    async fn store_block(&self, height: u32, hash: &Hash, time: Option<u32>) -> Result<()>;
+    /// Runs a write statement that changes nothing and rolls it back
+    async fn check_writable(&self) -> Result<()>;
+
+    async fn get_transfers(&self, filter: &TransferFilter) -> Result<Vec<TransferRow>>;
+    async fn get_outgoing_transfers(&self, filter: &TransferFilter) -> Result<Vec<TransferRow>>;
+    /// Transfers of the transaction, in every account unless `account` is set
+    async fn get_transfers_by_txid(
+        &self,
+        wallet: u32,
+        account: Option<u32>,
+        txid: &[u8],
+    ) -> Result<Vec<TransferRow>>;
+
+    async fn truncate_height(&self, height: u32) -> Result<()>;
+    /// Nullifiers and values of the unspent notes of a wallet
+    async fn get_nfs(&self, wallet: u32) -> Result<HashMap<Hash, u64>>;
+    /// Queued transactions whose memos are due to be fetched at `now`,
+    /// with their number of failed fetches
+    async fn get_memo_queue(&self, now: u64, limit: u32) -> Result<Vec<(WalletTx, u32)>>;
+    /// Record a failed fetch of the memos of a transaction. Without a next attempt,
+    /// the transaction leaves the queue and the memos of its notes are marked failed
+    async fn retry_memo_tx(
+        &self,
+        txid: &Hash,
+        attempts: u32,
+        next_attempt: Option<u64>,
+    ) -> Result<()>;
+    /// Store the events of a scan in a single transaction.
+    /// Returns the ids of the transactions to notify: those we didn't
+    /// know about, and those whose memos were fetched
+    async fn store_events(&self, events: &[ScanEvent]) -> Result<Vec<Hash>>;
+
+    /// Write a consistent snapshot of the database to a new file at `path`,
+    /// while it is in use
+    async fn backup(&self, path: &str, snapshot: &SnapshotInfo) -> Result<()>;
+    async fn read_snapshot_info(&self, path: &str) -> Result<SnapshotInfo>;
+    /// Replace the content of the database with the snapshot at `path`
+    async fn restore(&self, path: &str) -> Result<()>;
+}
+
+/// Viewing key of a wallet. Wallet 0 has the key of the config
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WalletKey {
+    pub id: u32,
+    pub label: String,
+    pub vk: String,
+    pub birth_height: u32,
+    /// Height reached by the catch-up scan of the wallet,
+    /// None once it is scanned with the others
+    pub scan_height: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WalletMetadata {
+    pub ufvk_fingerprint: String,
+    pub network: String,
+    pub birth_height: u32,
+}
+
+/// Stamped on backups to check them before they are restored
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotInfo {
+    pub schema_version: u32,
+    pub synced_height: u32,
+    pub ufvk_fingerprint: String,
+    pub created_at: u64,
+    /// Fingerprints of the viewing keys of all the wallets, by wallet id
+    #[serde(default)]
+    pub wallet_fingerprints: BTreeMap<u32, String>,
+}
+
+/// Path of a backup file named by an API request. The name must be
+/// relative to `backup_dir`, without `..`
+pub fn backup_path(backup_dir: Option<&str>, name: &str) -> Result<PathBuf> {
+    let backup_dir = backup_dir.ok_or(anyhow!("Set backup_dir to back up over the API"))?;
+    let name = Path::new(name);
+    let relative = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if name.as_os_str().is_empty() || !relative {
+        bail!("Invalid backup name {}", name.display());
+    }
+    Ok(Path::new(backup_dir).join(name))
+}
+
+/// Open the database selected by the config: PostgreSQL if
+/// `postgres_url` is set, SQLite otherwise
+pub async fn connect(config: &WalletConfig) -> Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> = match &config.postgres_url {
+        Some(url) => Arc::new(PostgresStorage::connect(url).await?),
+        None => Arc::new(SqliteStorage::open(&config.db_path).await?),
+    };
+    Ok(storage)
+}
+
+pub struct TransferRow {
+    pub address: String,
+    pub value: u64,
+    pub account: u32,
+    pub sub_account: u32,
+    pub txid: Vec<u8>,
+    pub memo: String,
+    pub height: u32,
+    pub r#type: String,
+    pub label: String,
+    pub memo_type: String,
+    pub memo_bytes: Vec<u8>,
+    pub payment_id: String,
+    pub memo_status: String,
+}
+
+impl TransferRow {
+    pub fn into_transfer(
+        self,
+        latest_height: u32,
+        confirmations: u32,
+        memo_encoding: MemoEncoding,
+    ) -> Transfer {
+        let mut txid = self.txid;
+        txid.reverse();
+        Transfer {
+            address: self.address,
+            amount: self.value,
+            confirmations: latest_height - self.height + 1,
+            height: self.height,
+            fee: 0,
+            note: self.memo,
+            payment_id: self.payment_id,
+            subaddr_index: SubAddress {
+                major: self.account,
+                minor: self.sub_account,
+            },
+            suggested_confirmations_threshold: confirmations,
+            timestamp: 0, // TODO: Check if needed
+            txid: hex::encode(txid),
+            r#type: self.r#type,
+            unlock_time: 0,
+            label: self.label,
+            memo_type: self.memo_type,
+            memo_bytes: memo_encoding.encode(&self.memo_bytes),
+            memo_fields: None,
+            memo_status: self.memo_status,
+        }
+    }
+}
+
+fn blocks_to_unlock(last_height: Option<u32>, height: u32, confirmations: u32) -> u32 {
+    last_height
+        .map(|h| (h + confirmations - 1).saturating_sub(height))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::{MemoNote, ReceivedNote, WalletTx};
+
+    const ADDRESS: &str = "uregtest1se78asch326c8czsa2wyzzfuytrvlezzjw42rest6nkqu3dzuvf4ua3lxjzf8gc5ygwca5sjdsqnpzcs087hdpgz4msfazfwfsjtr0lrln7dg0729rzp7y2acm2wrjyr5qjc8mj7x03dqh4a6frku9ue8gv3z54xgxev3dg895hepwej";
+    const SAPLING_ADDRESS: &str = "zregtestsapling1qag0mpkwcratr9zweyk973dzukaln3svpl0v8fpydajq8aq8ghsq0ah3my0qc2admygg6xt4snh";
+
+    #[test]
+    fn test_backup_path() {
+        let dir = Some("/data/backups");
+        assert_eq!(
+            backup_path(dir, "backup.db").unwrap(),
+            PathBuf::from("/data/backups/backup.db")
+        );
+        assert_eq!(
+            backup_path(dir, "daily/backup.db").unwrap(),
+            PathBuf::from("/data/backups/daily/backup.db")
+        );
+        assert!(backup_path(dir, "/etc/passwd").is_err());
+        assert!(backup_path(dir, "../config.json").is_err());
+        assert!(backup_path(dir, "daily/../../config.json").is_err());
+        assert!(backup_path(dir, "./backup.db").is_err());
+        assert!(backup_path(dir, "").is_err());
+        assert!(backup_path(None, "backup.db").is_err());
+    }
+
+    // Same scenario for every backend
+    async fn check_storage(storage: &dyn Storage) -> Result<()> {
+        assert!(!storage.create(&Network::Regtest).await?);
+        assert_eq!(storage.get_metadata().await?, None);
+        let metadata = WalletMetadata {
+            ufvk_fingerprint: "fingerprint".to_string(),
+            network: "regtest".to_string(),
+            birth_height: 1,
+        };
+        storage.set_metadata(&metadata).await?;
+        assert_eq!(storage.get_metadata().await?, Some(metadata.clone()));
+        let wallet = WalletKey {
+            id: 1,
+            label: "merchant".to_string(),
+            vk: "uviewregtest".to_string(),
+            birth_height: 1,
+            scan_height: Some(100),
+        };
+        storage.store_wallet(&wallet).await?;
+        assert_eq!(storage.get_wallets().await?, vec![wallet.clone()]);
+        storage
+            .store_address(
+                1,
+                "main",
+                0,
+                0,
+                2,
+                ADDRESS,
+                &[(1, SAPLING_ADDRESS.to_string())],
+            )
+            .await?;
+        assert!(storage.create(&Network::Regtest).await?);
+        assert_eq!(storage.max_account(1).await?, Some(0));
+        assert_eq!(storage.max_account(0).await?, None);
+        assert_eq!(storage.max_diversifier_index(1).await?, Some(2));
+        let index = storage.find_address(1, SAPLING_ADDRESS).await?.unwrap();
+        assert_eq!((index.major, index.minor), (0, 0));
+        assert!(storage.find_address(0, SAPLING_ADDRESS).await?.is_none());
+
+        let note = ReceivedNote {
+            wallet: 1,
+            txid: [1; 32],
+            pool: 1,
+            position: 0,
+            height: 101,
+            address: SAPLING_ADDRESS.to_string(),
+            diversifier: [0; 11],
+            diversifier_index: Some(2),
+            value: 50_000,
+            rcm: [2; 32],
+            nf: [3; 32],
+            rho: None,
+        };
+        let new_txids = storage
+            .store_events(&[
+                ScanEvent::WalletHeight(1, 100, 101),
+                ScanEvent::Received(note),
+                ScanEvent::Memo(MemoNote {
+                    nf: [3; 32],
+                    memo: String::new(),
+                    memo_type: "arbitrary",
+                    memo_bytes: vec![0xFF; 512],
+                    payment_id: Some("A123".to_string()),
+                }),
+                ScanEvent::MemoTx(WalletTx {
+                    height: 101,
+                    txid: [1; 32],
+                    sap_position: 0,
+                    orc_position: 0,
+                }),
+                ScanEvent::Block(101, [4; 32], Some(1_700_000_000)),
+            ])
+            .await?;
+        assert_eq!(new_txids, vec![[1; 32]]);
+        assert_eq!(storage.get_wallets().await?[0].scan_height, Some(101));
+        assert!(storage
+            .store_events(&[ScanEvent::WalletHeight(1, 100, 102)])
+            .await
+            .is_err());
+        assert_eq!(storage.get_synced_height().await?, Some(101));
+        let queue = storage.get_memo_queue(0, 10).await?;
+        assert_eq!(queue.len(), 1);
+        assert_eq!((queue[0].0.txid, queue[0].1), ([1; 32], 0));
+        storage.retry_memo_tx(&[1; 32], 1, Some(100)).await?;
+        assert!(storage.get_memo_queue(99, 10).await?.is_empty());
+        assert_eq!(storage.get_memo_queue(100, 10).await?[0].1, 1);
+        let notify_txids = storage
+            .store_events(&[ScanEvent::MemoFetched([1; 32])])
+            .await?;
+        assert_eq!(notify_txids, vec![[1; 32]]);
+        assert!(storage.get_memo_queue(100, 10).await?.is_empty());
+        assert_eq!(storage.get_block_time(101).await?, Some(1_700_000_000));
+        assert_eq!(storage.get_nfs(1).await?.get(&[3; 32]), Some(&50_000));
+        assert!(storage.get_nfs(0).await?.is_empty());
+
+        let accounts = storage.get_accounts(1, 101, None).await?;
+        assert_eq!(accounts[0].balance, 50_000);
+        assert_eq!(accounts[0].unlocked_balance, 50_000);
+        assert!(storage.get_accounts(0, 101, None).await?.is_empty());
+        let filter = TransferFilter {
+            wallet: 1,
+            ..TransferFilter::default()
+        };
+        let transfers = storage.get_transfers(&filter).await?;
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].label, "main");
+        assert_eq!(transfers[0].memo_type, "arbitrary");
+        assert_eq!(transfers[0].memo_bytes, vec![0xFF; 512]);
+        assert_eq!(transfers[0].payment_id, "A123");
+        assert_eq!(transfers[0].memo_status, "fetched");
+        let filter = TransferFilter {
+            payment_ids: vec!["B456".to_string(), "A123".to_string()],
+            ..filter
+        };
+        assert_eq!(storage.get_transfers(&filter).await?.len(), 1);
+        let filter = TransferFilter {
+            payment_ids: vec!["B456".to_string()],
+            ..filter
+        };
+        assert!(storage.get_transfers(&filter).await?.is_empty());
+        assert!(storage
+            .get_transfers(&TransferFilter::default())
+            .await?
+            .is_empty());
+
+        storage.tag_accounts(1, "store", &[0]).await?;
+        assert_eq!(
+            storage.get_tagged_accounts(1).await?,
+            vec![("store".to_string(), "".to_string(), 0)]
+        );
+        assert!(storage.tag_accounts(0, "store", &[0]).await.is_err());
+        storage.check_writable().await?;
+
+        storage.truncate_height(101).await?;
+        assert_eq!(storage.get_synced_height().await?, None);
+        assert!(storage.get_nfs(1).await?.is_empty());
+        assert_eq!(storage.get_wallets().await?[0].scan_height, Some(100));
+        storage.set_wallet_scan_height(1, None).await?;
+        assert_eq!(storage.get_wallets().await?[0].scan_height, None);
+
+        storage
+            .store_events(&[ScanEvent::MemoTx(WalletTx {
+                height: 101,
+                txid: [1; 32],
+                sap_position: 0,
+                orc_position: 0,
+            })])
+            .await?;
+        assert_eq!(storage.get_memo_queue(0, 10).await?.len(), 1);
+        storage.delete_wallet(1).await?;
+        assert!(storage.get_memo_queue(0, 10).await?.is_empty());
+        assert!(storage.get_wallets().await?.is_empty());
+        assert_eq!(storage.max_account(1).await?, None);
+        assert!(storage.find_address(1, SAPLING_ADDRESS).await?.is_none());
+        assert!(storage.get_tagged_accounts(1).await?.is_empty());
+        storage.store_wallet(&wallet).await?;
+
+        let metadata = WalletMetadata {
+            ufvk_fingerprint: "other".to_string(),
+            ..metadata
+        };
+        storage.reset(&metadata).await?;
+        assert!(!storage.create(&Network::Regtest).await?);
+        assert_eq!(storage.get_metadata().await?, Some(metadata));
+        assert!(storage.get_wallets().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage() -> Result<()> {
+        let storage = SqliteStorage::open_in_memory().await?;
+        check_storage(&storage).await
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_backup() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("walletd-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        let storage = SqliteStorage::open(&path("wallet.db")).await?;
+        storage.create(&Network::Regtest).await?;
+        storage
+            .store_address(0, "main", 0, 0, 2, ADDRESS, &[])
+            .await?;
+        storage.store_block(100, &[4; 32], None).await?;
+        let snapshot = SnapshotInfo {
+            schema_version: SCHEMA_VERSION,
+            synced_height: 100,
+            ufvk_fingerprint: "fingerprint".to_string(),
+            created_at: 0,
+            wallet_fingerprints: BTreeMap::from([
+                (0, "fingerprint".to_string()),
+                (1, "fingerprint1".to_string()),
+            ]),
+        };
+        storage.backup(&path("backup.db"), &snapshot).await?;
+        // Never overwrite an existing file
+        assert!(storage.backup(&path("backup.db"), &snapshot).await.is_err());
+
+        let restored = SqliteStorage::open(&path("restored.db")).await?;
+        let info = restored.read_snapshot_info(&path("backup.db")).await?;
+        assert_eq!(info.ufvk_fingerprint, "fingerprint");
+        assert_eq!(info.synced_height, 100);
+        assert_eq!(info.wallet_fingerprints, snapshot.wallet_fingerprints);
+        restored.restore(&path("backup.db")).await?;
+        assert!(restored.create(&Network::Regtest).await?);
+        assert_eq!(restored.get_synced_height().await?, Some(100));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local PostgreSQL database in POSTGRES_URL"]
+    async fn test_postgres_storage() -> Result<()> {
+        let url = std::env::var("POSTGRES_URL")?;
+        let storage = PostgresStorage::connect(&url).await?;
+        storage.drop_tables().await?;
+        check_storage(&storage).await
+    }
+}
@@ -0,0 +1,1200 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{Acquire, PgConnection, Postgres, QueryBuilder, Row};
+
+use super::migrations::{Custom, Step, MIGRATIONS, SCHEMA_VERSION};
+use super::{blocks_to_unlock, SnapshotInfo, Storage, TransferRow, WalletKey, WalletMetadata};
+use crate::account::{AccountBalance, AddressInfo, Receivers, SubAccountBalance};
+use crate::address::address_receivers;
+use crate::network::Network;
+use crate::scan::{ScanEvent, WalletTx};
+use crate::transaction::{SubAddress, TransferFilter};
+use crate::Hash;
+
+/// PostgreSQL has no unsigned integers. Every integer column is a BIGINT
+/// and values are converted when they are bound and read
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let pool = PgPool::connect(url).await?;
+        Ok(PostgresStorage { pool })
+    }
+
+    async fn migrate(network: &Network, connection: &mut PgConnection) -> Result<()> {
+        sqlx::query("CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL)")
+            .execute(&mut *connection)
+            .await?;
+        let mut version = sqlx::query("SELECT version FROM schema_version")
+            .map(|row: PgRow| row.get::<i64, _>(0) as u32)
+            .fetch_optional(&mut *connection)
+            .await?
+            .unwrap_or_default();
+        if version > SCHEMA_VERSION {
+            bail!(
+                "Database schema version {version} is more recent than this version of zcash-walletd ({SCHEMA_VERSION})"
+            );
+        }
+
+        for migration in &MIGRATIONS[version as usize..] {
+            let mut db_tx = connection.begin().await?;
+            for step in migration.postgres {
+                Self::apply(network, &mut db_tx, step).await?;
+            }
+            version += 1;
+            sqlx::query("DELETE FROM schema_version")
+                .execute(&mut *db_tx)
+                .await?;
+            sqlx::query("INSERT INTO schema_version(version) VALUES ($1)")
+                .bind(version as i64)
+                .execute(&mut *db_tx)
+                .await?;
+            db_tx.commit().await?;
+            info!(
+                "Database schema migrated to version {version}: {}",
+                migration.description
+            );
+        }
+        Ok(())
+    }
+
+    async fn apply(network: &Network, connection: &mut PgConnection, step: &Step) -> Result<()> {
+        match step {
+            Step::Sql(statements) => {
+                for statement in statements.iter() {
+                    sqlx::query(statement).execute(&mut *connection).await?;
+                }
+            }
+            Step::AddColumn {
+                table,
+                column,
+                definition,
+            } => {
+                sqlx::query(&format!(
+                    "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} {definition}"
+                ))
+                .execute(&mut *connection)
+                .await?;
+            }
+            Step::Custom(Custom::AddTransparentReceivers) => {
+                Self::add_transparent_receivers(network, connection).await?;
+            }
+            Step::Custom(Custom::RescanLegacySpends) => {
+                Self::rescan_legacy_spends(connection).await?;
+            }
+            // PostgreSQL starts with the schema of version 4
+            Step::Custom(Custom::CreateSchema | Custom::AddWalletToTags) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // See the SQLite migration
+    async fn rescan_legacy_spends(connection: &mut PgConnection) -> Result<()> {
+        let height = sqlx::query(
+            "SELECT MIN(height) FROM received_notes WHERE spent = 1 AND spent_tx IS NULL",
+        )
+        .map(|row: PgRow| row.get::<Option<i64>, _>(0))
+        .fetch_one(&mut *connection)
+        .await?;
+        let Some(height) = height else {
+            return Ok(());
+        };
+        warn!("Notes were spent before their spending height was stored. The wallet will rescan from {height}");
+        for statement in [
+            "DELETE FROM transactions WHERE height >= $1",
+            "DELETE FROM blocks WHERE height >= $1",
+            "UPDATE received_notes SET spent = 0, spent_tx = NULL WHERE spent >= $1",
+            "DELETE FROM received_notes WHERE height >= $1",
+            "UPDATE wallets SET scan_height = $1 - 1 WHERE scan_height >= $1",
+            "DELETE FROM memo_queue WHERE height >= $1",
+        ] {
+            sqlx::query(statement)
+                .bind(height)
+                .execute(&mut *connection)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn add_transparent_receivers(
+        network: &Network,
+        connection: &mut PgConnection,
+    ) -> Result<()> {
+        let addresses = sqlx::query(
+            "SELECT a.id_address, a.address FROM addresses a
+            WHERE NOT EXISTS (SELECT 1 FROM receivers r
+            WHERE r.id_address = a.id_address AND r.pool = 0)",
+        )
+        .map(|row: PgRow| (row.get::<i64, _>(0), row.get::<String, _>(1)))
+        .fetch_all(&mut *connection)
+        .await?;
+        for (id_address, address) in addresses {
+            for (pool, receiver) in address_receivers(network, &address)? {
+                if pool != 0 {
+                    continue;
+                }
+                sqlx::query(
+                    "INSERT INTO receivers(pool, id_address, receiver_address)
+                    VALUES ($1, $2, $3)",
+                )
+                .bind(pool as i64)
+                .bind(id_address)
+                .bind(receiver)
+                .execute(&mut *connection)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_metadata(
+        connection: &mut PgConnection,
+        metadata: &WalletMetadata,
+    ) -> Result<()> {
+        sqlx::query("DELETE FROM metadata")
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query(
+            "INSERT INTO metadata(ufvk_fingerprint, network, birth_height)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(&metadata.ufvk_fingerprint)
+        .bind(&metadata.network)
+        .bind(metadata.birth_height as i64)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub async fn drop_tables(&self) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        sqlx::query(
+            "DROP TABLE IF EXISTS schema_version, blocks, addresses, receivers, \
+            account_tags, tags, transactions, received_notes, metadata, wallets, memo_queue",
+        )
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn check_account_exists(
+        connection: &mut PgConnection,
+        wallet: u32,
+        account: u32,
+    ) -> Result<()> {
+        if sqlx::query(
+            "SELECT 1 FROM addresses WHERE wallet = $1 AND account = $2 AND sub_account = 0",
+        )
+        .bind(wallet as i64)
+        .bind(account as i64)
+        .fetch_optional(&mut *connection)
+        .await?
+        .is_none()
+        {
+            bail!("Unknown account index {account}");
+        }
+        Ok(())
+    }
+
+    fn row_to_transfer(row: PgRow) -> TransferRow {
+        TransferRow {
+            address: row.get(0),
+            value: row.get::<i64, _>(1) as u64,
+            account: row.get::<i64, _>(2) as u32,
+            sub_account: row.get::<i64, _>(3) as u32,
+            txid: row.get(4),
+            memo: row.get(5),
+            height: row.get::<i64, _>(6) as u32,
+            r#type: row.get(7),
+            label: row.get(8),
+            memo_type: row.get(9),
+            memo_bytes: row.get::<Option<Vec<u8>>, _>(10).unwrap_or_default(),
+            payment_id: row.get::<Option<String>, _>(11).unwrap_or_default(),
+            memo_status: row.get(12),
+        }
+    }
+
+    fn push_transfer_filter(
+        builder: &mut QueryBuilder<'_, Postgres>,
+        filter: &TransferFilter,
+        height_column: &str,
+    ) {
+        builder
+            .push(" AND n.wallet = ")
+            .push_bind(filter.wallet as i64);
+        if let Some(account_index) = filter.account_index {
+            builder
+                .push(" AND n.account = ")
+                .push_bind(account_index as i64);
+        }
+        // An empty list of sub accounts selects all of them
+        if !filter.sub_accounts.is_empty() {
+            builder.push(" AND n.sub_account IN (");
+            let mut separated = builder.separated(", ");
+            for sub_account in filter.sub_accounts.iter() {
+                separated.push_bind(*sub_account as i64);
+            }
+            separated.push_unseparated(")");
+        }
+        if !filter.payment_ids.is_empty() {
+            builder.push(" AND n.payment_id IN (");
+            let mut separated = builder.separated(", ");
+            for payment_id in filter.payment_ids.iter() {
+                separated.push_bind(payment_id.clone());
+            }
+            separated.push_unseparated(")");
+        }
+        // Same semantics as monero: min_height is exclusive, max_height inclusive
+        if let Some((min_height, max_height)) = filter.height_range {
+            builder
+                .push(format!(" AND {height_column} > "))
+                .push_bind(min_height as i64)
+                .push(format!(" AND {height_column} <= "))
+                .push_bind(max_height as i64);
+        }
+    }
+
+    // Notes whose output is not in the full transaction will never get their memo
+    async fn dequeue_memo_tx(txid: &Hash, connection: &mut PgConnection) -> Result<()> {
+        sqlx::query("DELETE FROM memo_queue WHERE txid = $1")
+            .bind(txid.as_slice())
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query(
+            "UPDATE received_notes SET memo_status = 'failed'
+            WHERE memo_status = 'pending'
+            AND id_tx IN (SELECT id_tx FROM transactions WHERE txid = $1)",
+        )
+        .bind(txid.as_slice())
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_tx_if_not_exists(
+        height: u32,
+        txid: &[u8],
+        db_tx: &mut PgConnection,
+    ) -> Result<(i64, bool)> {
+        let result = match sqlx::query("SELECT id_tx FROM transactions WHERE txid = $1")
+            .bind(txid)
+            .map(|r: PgRow| r.get::<i64, _>(0))
+            .fetch_optional(&mut *db_tx)
+            .await?
+        {
+            Some(id_tx) => (id_tx, false),
+            None => {
+                let id_tx = sqlx::query(
+                    "INSERT INTO transactions(txid, height, value) VALUES ($1, $2, 0)
+                    RETURNING id_tx",
+                )
+                .bind(txid)
+                .bind(height as i64)
+                .map(|r: PgRow| r.get::<i64, _>(0))
+                .fetch_one(db_tx)
+                .await?;
+
+                (id_tx, true)
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for PostgresStorage {
+    async fn create(&self, network: &Network) -> Result<bool> {
+        let mut connection = self.pool.acquire().await?;
+
+        Self::migrate(network, &mut connection).await?;
+        sqlx::query(
+            "DELETE FROM received_notes WHERE height >=
+            (SELECT MAX(height) FROM blocks)",
+        )
+        .execute(&mut *connection)
+        .await?;
+
+        let r = sqlx::query("SELECT 1 FROM addresses LIMIT 1")
+            .fetch_optional(&mut *connection)
+            .await?;
+
+        Ok(r.is_some())
+    }
+
+    async fn get_metadata(&self) -> Result<Option<WalletMetadata>> {
+        let mut connection = self.pool.acquire().await?;
+        let metadata = sqlx::query("SELECT ufvk_fingerprint, network, birth_height FROM metadata")
+            .map(|row: PgRow| WalletMetadata {
+                ufvk_fingerprint: row.get(0),
+                network: row.get(1),
+                birth_height: row.get::<i64, _>(2) as u32,
+            })
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(metadata)
+    }
+
+    async fn set_metadata(&self, metadata: &WalletMetadata) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        Self::store_metadata(&mut connection, metadata).await
+    }
+
+    async fn reset(&self, metadata: &WalletMetadata) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        sqlx::query(
+            "TRUNCATE received_notes, transactions, blocks, receivers, \
+            addresses, account_tags, tags, wallets, memo_queue",
+        )
+        .execute(&mut *db_transaction)
+        .await?;
+        Self::store_metadata(&mut db_transaction, metadata).await?;
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_wallets(&self) -> Result<Vec<WalletKey>> {
+        let mut connection = self.pool.acquire().await?;
+        let wallets = sqlx::query(
+            "SELECT id_wallet, label, vk, birth_height, scan_height
+            FROM wallets ORDER BY id_wallet",
+        )
+        .map(|row: PgRow| WalletKey {
+            id: row.get::<i64, _>(0) as u32,
+            label: row.get(1),
+            vk: row.get(2),
+            birth_height: row.get::<i64, _>(3) as u32,
+            scan_height: row.get::<Option<i64>, _>(4).map(|h| h as u32),
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(wallets)
+    }
+
+    async fn store_wallet(&self, wallet: &WalletKey) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        sqlx::query(
+            "INSERT INTO wallets(id_wallet, label, vk, birth_height, scan_height)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(wallet.id as i64)
+        .bind(&wallet.label)
+        .bind(&wallet.vk)
+        .bind(wallet.birth_height as i64)
+        .bind(wallet.scan_height.map(|h| h as i64))
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_wallet_scan_height(&self, wallet: u32, height: Option<u32>) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        sqlx::query("UPDATE wallets SET scan_height = $2 WHERE id_wallet = $1")
+            .bind(wallet as i64)
+            .bind(height.map(|h| h as i64))
+            .execute(&mut *connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_wallet(&self, wallet: u32) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let wallet = wallet as i64;
+        sqlx::query(
+            "DELETE FROM receivers WHERE id_address IN
+            (SELECT id_address FROM addresses WHERE wallet = $1)",
+        )
+        .bind(wallet)
+        .execute(&mut *db_transaction)
+        .await?;
+        for table in ["addresses", "received_notes", "account_tags", "tags"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE wallet = $1"))
+                .bind(wallet)
+                .execute(&mut *db_transaction)
+                .await?;
+        }
+        sqlx::query("DELETE FROM wallets WHERE id_wallet = $1")
+            .bind(wallet)
+            .execute(&mut *db_transaction)
+            .await?;
+        // Transactions are shared, only those of the wallet go away
+        sqlx::query(
+            "DELETE FROM transactions t WHERE NOT EXISTS
+            (SELECT 1 FROM received_notes n WHERE n.id_tx = t.id_tx OR n.spent_tx = t.id_tx)",
+        )
+        .execute(&mut *db_transaction)
+        .await?;
+        // and so do the memos to fetch that no other wallet needs
+        sqlx::query(
+            "DELETE FROM memo_queue q WHERE NOT EXISTS
+            (SELECT 1 FROM received_notes n JOIN transactions t ON n.id_tx = t.id_tx
+            WHERE t.txid = q.txid)",
+        )
+        .execute(&mut *db_transaction)
+        .await?;
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn max_account(&self, wallet: u32) -> Result<Option<u32>> {
+        let mut connection = self.pool.acquire().await?;
+        let account = sqlx::query("SELECT MAX(account) FROM addresses WHERE wallet = $1")
+            .bind(wallet as i64)
+            .map(|r: PgRow| r.get::<Option<i64>, _>(0).map(|a| a as u32))
+            .fetch_one(&mut *connection)
+            .await?;
+        Ok(account)
+    }
+
+    async fn max_sub_account(&self, wallet: u32, account: u32) -> Result<Option<u32>> {
+        let mut connection = self.pool.acquire().await?;
+        let sub_account = sqlx::query(
+            "SELECT MAX(sub_account) FROM addresses WHERE wallet = $1 AND account = $2",
+        )
+        .bind(wallet as i64)
+        .bind(account as i64)
+        .map(|r: PgRow| r.get::<Option<i64>, _>(0).map(|a| a as u32))
+        .fetch_one(&mut *connection)
+        .await?;
+        Ok(sub_account)
+    }
+
+    async fn max_diversifier_index(&self, wallet: u32) -> Result<Option<u64>> {
+        let mut connection = self.pool.acquire().await?;
+        let di = sqlx::query("SELECT MAX(diversifier_index) FROM addresses WHERE wallet = $1")
+            .bind(wallet as i64)
+            .map(|r: PgRow| r.get::<Option<i64>, _>(0).map(|di| di as u64))
+            .fetch_one(&mut *connection)
+            .await?;
+        Ok(di)
+    }
+
+    async fn store_address(
+        &self,
+        wallet: u32,
+        label: &str,
+        account: u32,
+        sub_account: u32,
+        diversifier_index: u64,
+        address: &str,
+        receivers: &[(u8, String)],
+    ) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let id_address = sqlx::query(
+            "INSERT INTO addresses(label, account, sub_account, address, diversifier_index, wallet)
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING id_address",
+        )
+        .bind(label)
+        .bind(account as i64)
+        .bind(sub_account as i64)
+        .bind(address)
+        .bind(diversifier_index as i64)
+        .bind(wallet as i64)
+        .map(|r: PgRow| r.get::<i64, _>(0))
+        .fetch_one(&mut *db_transaction)
+        .await?;
+
+        for (pool, receiver) in receivers {
+            sqlx::query(
+                "INSERT INTO receivers(pool, id_address, receiver_address)
+                VALUES ($1, $2, $3)",
+            )
+            .bind(*pool as i64)
+            .bind(id_address)
+            .bind(receiver)
+            .execute(&mut *db_transaction)
+            .await?;
+        }
+        db_transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_addresses(
+        &self,
+        wallet: u32,
+        account: u32,
+        sub_accounts: &[u32],
+    ) -> Result<Vec<AddressInfo>> {
+        let mut connection = self.pool.acquire().await?;
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "SELECT a.sub_account, a.address, a.label, a.diversifier_index, \
+            MAX(CASE WHEN r.pool = 0 THEN r.receiver_address END), \
+            MAX(CASE WHEN r.pool = 1 THEN r.receiver_address END), \
+            MAX(CASE WHEN r.pool = 2 THEN r.receiver_address END), \
+            EXISTS(SELECT 1 FROM received_notes n \
+                WHERE n.wallet = a.wallet AND n.account = a.account \
+                AND n.sub_account = a.sub_account) \
+            FROM addresses a LEFT JOIN receivers r ON r.id_address = a.id_address \
+            WHERE a.wallet = ",
+        );
+        builder
+            .push_bind(wallet as i64)
+            .push(" AND a.account = ")
+            .push_bind(account as i64);
+        if !sub_accounts.is_empty() {
+            builder.push(" AND a.sub_account IN (");
+            let mut separated = builder.separated(", ");
+            for sub_account in sub_accounts.iter() {
+                separated.push_bind(*sub_account as i64);
+            }
+            separated.push_unseparated(")");
+        }
+        builder.push(" GROUP BY a.id_address ORDER BY a.sub_account");
+
+        let addresses = builder
+            .build()
+            .map(|row: PgRow| AddressInfo {
+                address_index: row.get::<i64, _>(0) as u32,
+                address: row.get(1),
+                label: row.get(2),
+                diversifier_index: row.get::<i64, _>(3) as u64,
+                receivers: Receivers {
+                    transparent: row.get(4),
+                    sapling: row.get(5),
+                    orchard: row.get(6),
+                },
+                used: row.get(7),
+            })
+            .fetch_all(&mut *connection)
+            .await?;
+        Ok(addresses)
+    }
+
+    async fn find_address(&self, wallet: u32, address: &str) -> Result<Option<SubAddress>> {
+        let mut connection = self.pool.acquire().await?;
+        let index = sqlx::query(
+            "SELECT a.account, a.sub_account FROM addresses a
+            LEFT JOIN receivers r ON a.id_address = r.id_address
+            WHERE a.wallet = $2 AND (a.address = $1 OR r.receiver_address = $1) LIMIT 1",
+        )
+        .bind(address)
+        .bind(wallet as i64)
+        .map(|r: PgRow| SubAddress {
+            major: r.get::<i64, _>(0) as u32,
+            minor: r.get::<i64, _>(1) as u32,
+        })
+        .fetch_optional(&mut *connection)
+        .await?;
+        Ok(index)
+    }
+
+    async fn get_accounts(
+        &self,
+        wallet: u32,
+        confirmed_height: u32,
+        tag: Option<&str>,
+    ) -> Result<Vec<AccountBalance>> {
+        let mut connection = self.pool.acquire().await?;
+        // SUM of BIGINT is a NUMERIC in PostgreSQL
+        let accounts = sqlx::query(
+            "SELECT a.account, a.label, COALESCE(SUM(n.value), 0)::BIGINT AS total, \
+                COALESCE(SUM(CASE WHEN n.height <= $1 THEN n.value ELSE 0 END), 0)::BIGINT AS unlocked, \
+                a.address AS base_address, COALESCE(t.tag, '') \
+                FROM addresses a LEFT JOIN received_notes n \
+                ON n.wallet = a.wallet AND n.account = a.account AND COALESCE(n.spent, 0) = 0 \
+                LEFT JOIN account_tags t ON t.wallet = a.wallet AND t.account = a.account \
+                WHERE a.wallet = $3 AND a.sub_account = 0 AND ($2::TEXT IS NULL OR t.tag = $2) \
+                GROUP BY a.account, a.label, a.address, t.tag ORDER BY a.account",
+        )
+        .bind(confirmed_height as i64)
+        .bind(tag)
+        .bind(wallet as i64)
+        .map(|row: PgRow| AccountBalance {
+            account_index: row.get::<i64, _>(0) as u32,
+            label: row.get(1),
+            balance: row.get::<i64, _>(2) as u64,
+            unlocked_balance: row.get::<i64, _>(3) as u64,
+            base_address: row.get(4),
+            tag: row.get(5),
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+
+        Ok(accounts)
+    }
+
+    async fn get_subaddress_balances(
+        &self,
+        wallet: u32,
+        height: u32,
+        account_index: u32,
+        sub_accounts: &[u32],
+        confirmations: u32,
+    ) -> Result<Vec<SubAccountBalance>> {
+        let mut connection = self.pool.acquire().await?;
+        let confirmed_height = height - confirmations + 1;
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "SELECT a.sub_account, a.address, a.label, COALESCE(SUM(n.value), 0)::BIGINT, \
+            COALESCE(SUM(CASE WHEN n.height <= ",
+        );
+        builder
+            .push_bind(confirmed_height as i64)
+            .push(
+                " THEN n.value ELSE 0 END), 0)::BIGINT, COUNT(n.id_note), MAX(n.height) \
+                FROM addresses a LEFT JOIN received_notes n \
+                ON n.wallet = a.wallet AND n.account = a.account \
+                AND n.sub_account = a.sub_account \
+                AND COALESCE(n.spent, 0) = 0 WHERE a.wallet = ",
+            )
+            .push_bind(wallet as i64)
+            .push(" AND a.account = ")
+            .push_bind(account_index as i64);
+        if !sub_accounts.is_empty() {
+            builder.push(" AND a.sub_account IN (");
+            let mut separated = builder.separated(", ");
+            for sub_account in sub_accounts.iter() {
+                separated.push_bind(*sub_account as i64);
+            }
+            separated.push_unseparated(")");
+        }
+        builder.push(" GROUP BY a.sub_account, a.address, a.label ORDER BY a.sub_account");
+
+        let balances = builder
+            .build()
+            .map(|row: PgRow| {
+                let last_height = row.get::<Option<i64>, _>(6).map(|h| h as u32);
+                SubAccountBalance {
+                    account_index,
+                    address_index: row.get::<i64, _>(0) as u32,
+                    address: row.get(1),
+                    label: row.get(2),
+                    balance: row.get::<i64, _>(3) as u64,
+                    unlocked_balance: row.get::<i64, _>(4) as u64,
+                    num_unspent_outputs: row.get::<i64, _>(5) as u32,
+                    blocks_to_unlock: blocks_to_unlock(last_height, height, confirmations),
+                }
+            })
+            .fetch_all(&mut *connection)
+            .await?;
+
+        Ok(balances)
+    }
+
+    async fn set_address_label(
+        &self,
+        wallet: u32,
+        account: u32,
+        sub_account: u32,
+        label: &str,
+    ) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let r = sqlx::query(
+            "UPDATE addresses SET label = $3 WHERE wallet = $4 AND account = $1 AND sub_account = $2",
+        )
+        .bind(account as i64)
+        .bind(sub_account as i64)
+        .bind(label)
+        .bind(wallet as i64)
+        .execute(&mut *connection)
+        .await?;
+        if r.rows_affected() == 0 {
+            bail!("Unknown address index {account}/{sub_account}");
+        }
+        Ok(())
+    }
+
+    async fn tag_accounts(&self, wallet: u32, tag: &str, accounts: &[u32]) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        for &account in accounts {
+            Self::check_account_exists(&mut db_transaction, wallet, account).await?;
+            sqlx::query(
+                "INSERT INTO account_tags(wallet, account, tag) VALUES ($1, $2, $3)
+                ON CONFLICT (wallet, account) DO UPDATE SET tag = excluded.tag",
+            )
+            .bind(wallet as i64)
+            .bind(account as i64)
+            .bind(tag)
+            .execute(&mut *db_transaction)
+            .await?;
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn untag_accounts(&self, wallet: u32, accounts: &[u32]) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        for &account in accounts {
+            Self::check_account_exists(&mut db_transaction, wallet, account).await?;
+            sqlx::query("DELETE FROM account_tags WHERE wallet = $1 AND account = $2")
+                .bind(wallet as i64)
+                .bind(account as i64)
+                .execute(&mut *db_transaction)
+                .await?;
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn set_tag_description(&self, wallet: u32, tag: &str, description: &str) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        sqlx::query(
+            "INSERT INTO tags(wallet, tag, description) VALUES ($1, $2, $3)
+            ON CONFLICT (wallet, tag) DO UPDATE SET description = excluded.description",
+        )
+        .bind(wallet as i64)
+        .bind(tag)
+        .bind(description)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_tagged_accounts(&self, wallet: u32) -> Result<Vec<(String, String, u32)>> {
+        let mut connection = self.pool.acquire().await?;
+        let rows = sqlx::query(
+            "SELECT t.tag, COALESCE(d.description, ''), t.account \
+            FROM account_tags t LEFT JOIN tags d ON d.wallet = t.wallet AND d.tag = t.tag \
+            WHERE t.wallet = $1 ORDER BY t.tag, t.account",
+        )
+        .bind(wallet as i64)
+        .map(|row: PgRow| (row.get(0), row.get(1), row.get::<i64, _>(2) as u32))
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_synced_height(&self) -> Result<Option<u32>> {
+        let mut connection = self.pool.acquire().await?;
+        let height = sqlx::query("SELECT MAX(height) FROM blocks")
+            .map(|row: PgRow| row.get::<Option<i64>, _>(0).map(|h| h as u32))
+            .fetch_one(&mut *connection)
+            .await?;
+        Ok(height)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<Option<Hash>> {
+        let mut connection = self.pool.acquire().await?;
+        let hash = sqlx::query("SELECT hash FROM blocks WHERE height = $1")
+            .bind(height as i64)
+            .map(|row: PgRow| {
+                let hash_vec: Vec<u8> = row.get(0);
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&hash_vec);
+                hash
+            })
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(hash)
+    }
+
+    async fn get_block_time(&self, height: u32) -> Result<Option<u32>> {
+        let mut connection = self.pool.acquire().await?;
+        let time = sqlx::query("SELECT time FROM blocks WHERE height = $1")
+            .bind(height as i64)
+            .map(|row: PgRow| row.get::<Option<i64>, _>(0).map(|t| t as u32))
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(time.flatten())
+    }
+
+    async fn store_block(&self, height: u32, hash: &Hash, time: Option<u32>) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        sqlx::query("INSERT INTO blocks(hash, height, time) VALUES ($1, $2, $3)")
+            .bind(hash.as_slice())
+            .bind(height as i64)
+            .bind(time.map(i64::from))
+            .execute(&mut *connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        sqlx::query("DELETE FROM blocks WHERE height < 0")
+            .execute(&mut *db_transaction)
+            .await?;
+        db_transaction.rollback().await?;
+        Ok(())
+    }
+
+    async fn get_transfers(&self, filter: &TransferFilter) -> Result<Vec<TransferRow>> {
+        let mut connection = self.pool.acquire().await?;
+
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "SELECT n.address, n.value, n.account, n.sub_account, txid, memo, n.height, 'in', \
+            COALESCE(a.label, ''), COALESCE(n.memo_type, ''), n.memo_bytes, n.payment_id, \
+            n.memo_status \
+            FROM received_notes n JOIN transactions t ON n.id_tx = t.id_tx \
+            LEFT JOIN addresses a ON a.wallet = n.wallet AND a.account = n.account \
+            AND a.sub_account = n.sub_account \
+            WHERE 1 = 1",
+        );
+        Self::push_transfer_filter(&mut builder, filter, "n.height");
+        builder.push(" ORDER BY n.height, n.id_note");
+        let transfers = builder
+            .build()
+            .map(Self::row_to_transfer)
+            .fetch_all(&mut *connection)
+            .await?;
+        Ok(transfers)
+    }
+
+    async fn get_outgoing_transfers(&self, filter: &TransferFilter) -> Result<Vec<TransferRow>> {
+        let mut connection = self.pool.acquire().await?;
+
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "SELECT a.address, SUM(n.value)::BIGINT, n.account, n.sub_account, t.txid, '', t.height, \
+            'out', a.label, '', NULL::BYTEA, NULL::TEXT, '' \
+            FROM received_notes n JOIN transactions t ON n.spent_tx = t.id_tx \
+            JOIN addresses a ON a.wallet = n.wallet AND a.account = n.account \
+            AND a.sub_account = n.sub_account \
+            WHERE 1 = 1",
+        );
+        Self::push_transfer_filter(&mut builder, filter, "t.height");
+        builder.push(
+            " GROUP BY t.id_tx, n.account, n.sub_account, a.address, a.label ORDER BY t.height",
+        );
+        let transfers = builder
+            .build()
+            .map(Self::row_to_transfer)
+            .fetch_all(&mut *connection)
+            .await?;
+        Ok(transfers)
+    }
+
+    async fn get_transfers_by_txid(
+        &self,
+        wallet: u32,
+        account: Option<u32>,
+        txid: &[u8],
+    ) -> Result<Vec<TransferRow>> {
+        let mut connection = self.pool.acquire().await?;
+
+        let transfers = sqlx::query(
+            "SELECT a.address, n.value, n.account, n.sub_account, txid, memo, n.height, 'in', a.label,
+            COALESCE(n.memo_type, ''), n.memo_bytes, n.payment_id, n.memo_status
+            FROM received_notes n
+            JOIN transactions t ON n.id_tx = t.id_tx
+            JOIN receivers r ON n.address = r.receiver_address
+            JOIN addresses a ON a.id_address = r.id_address
+            WHERE txid = $1 AND n.wallet = $2 AND ($3::BIGINT IS NULL OR n.account = $3)
+            ORDER BY n.height",
+        )
+        .bind(txid)
+        .bind(wallet as i64)
+        .bind(account.map(|a| a as i64))
+        .map(Self::row_to_transfer)
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(transfers)
+    }
+
+    async fn truncate_height(&self, height: u32) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let height = height as i64;
+
+        sqlx::query("DELETE FROM transactions WHERE height >= $1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("DELETE FROM received_notes WHERE height >= $1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("DELETE FROM blocks WHERE height >= $1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("UPDATE received_notes SET spent = 0, spent_tx = NULL WHERE spent >= $1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("UPDATE wallets SET scan_height = $1 - 1 WHERE scan_height >= $1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("DELETE FROM memo_queue WHERE height >= $1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        db_transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_memo_queue(&self, now: u64, limit: u32) -> Result<Vec<(WalletTx, u32)>> {
+        let mut connection = self.pool.acquire().await?;
+        let queue = sqlx::query(
+            "SELECT txid, height, sap_position, orc_position, attempts FROM memo_queue
+            WHERE next_attempt <= $1 ORDER BY height LIMIT $2",
+        )
+        .bind(now as i64)
+        .bind(limit as i64)
+        .map(|row: PgRow| {
+            let txid: Vec<u8> = row.get(0);
+            let wtx = WalletTx {
+                height: row.get::<i64, _>(1) as u32,
+                txid: txid.try_into().unwrap(),
+                sap_position: row.get::<i64, _>(2) as u32,
+                orc_position: row.get::<i64, _>(3) as u32,
+            };
+            (wtx, row.get::<i64, _>(4) as u32)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(queue)
+    }
+
+    async fn retry_memo_tx(
+        &self,
+        txid: &Hash,
+        attempts: u32,
+        next_attempt: Option<u64>,
+    ) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        match next_attempt {
+            Some(next_attempt) => {
+                sqlx::query(
+                    "UPDATE memo_queue SET attempts = $2, next_attempt = $3 WHERE txid = $1",
+                )
+                .bind(txid.as_slice())
+                .bind(attempts as i64)
+                .bind(next_attempt as i64)
+                .execute(&mut *db_transaction)
+                .await?;
+            }
+            None => Self::dequeue_memo_tx(txid, &mut db_transaction).await?,
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_nfs(&self, wallet: u32) -> Result<HashMap<Hash, u64>> {
+        let mut connection = self.pool.acquire().await?;
+
+        let nfs = sqlx::query(
+            "SELECT nf, value FROM received_notes WHERE wallet = $1 AND COALESCE(spent, 0) = 0",
+        )
+        .bind(wallet as i64)
+        .map(|row: PgRow| {
+            let nf: Vec<u8> = row.get(0);
+            let value = row.get::<i64, _>(1) as u64;
+            let nf: Hash = nf.try_into().unwrap();
+            (nf, value)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+
+        Ok(nfs.into_iter().collect())
+    }
+
+    async fn store_events(&self, events: &[ScanEvent]) -> Result<Vec<Hash>> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+        let mut notify_txids = vec![];
+
+        for event in events {
+            match event {
+                ScanEvent::Received(received_note) => {
+                    let (id_tx, is_new) = Self::create_tx_if_not_exists(
+                        received_note.height,
+                        received_note.txid.as_slice(),
+                        db_tx,
+                    )
+                    .await?;
+                    if is_new {
+                        notify_txids.push(received_note.txid);
+                    }
+
+                    let (account, sub_account) = match sqlx::query(
+                        "SELECT a.account, a.sub_account FROM addresses a
+                        JOIN receivers r ON a.id_address = r.id_address
+                        WHERE a.wallet = $2 AND r.receiver_address = $1",
+                    )
+                    .bind(&received_note.address)
+                    .bind(received_note.wallet as i64)
+                    .map(|r: PgRow| (r.get::<i64, _>(0), r.get::<i64, _>(1)))
+                    .fetch_optional(&mut *db_tx)
+                    .await?
+                    {
+                        Some(x) => x,
+                        None => {
+                            let account =
+                                sqlx::query("SELECT MAX(account) FROM addresses WHERE wallet = $1")
+                                    .bind(received_note.wallet as i64)
+                                    .map(|r: PgRow| r.get::<Option<i64>, _>(0).unwrap_or_default())
+                                    .fetch_one(&mut *db_tx)
+                                    .await?;
+                            let sub_account = sqlx::query(
+                                "SELECT MAX(sub_account) FROM addresses
+                                WHERE wallet = $2 AND account = $1",
+                            )
+                            .bind(account)
+                            .bind(received_note.wallet as i64)
+                            .map(|r: PgRow| {
+                                r.get::<Option<i64>, _>(0)
+                                    .map(|x| x + 1)
+                                    .unwrap_or_default()
+                            })
+                            .fetch_one(&mut *db_tx)
+                            .await?;
+
+                            let id_address = sqlx::query(
+                                "INSERT INTO addresses
+                                (label, account, sub_account, address, diversifier_index, wallet)
+                                VALUES ('', $1, $2, $3, $4, $5) RETURNING id_address",
+                            )
+                            .bind(account)
+                            .bind(sub_account)
+                            .bind(&received_note.address)
+                            .bind(received_note.diversifier_index.unwrap_or_default() as i64)
+                            .bind(received_note.wallet as i64)
+                            .map(|r: PgRow| r.get::<i64, _>(0))
+                            .fetch_one(&mut *db_tx)
+                            .await?;
+
+                            sqlx::query(
+                                "INSERT INTO receivers(pool, id_address, receiver_address)
+                                VALUES ($1, $2, $3)",
+                            )
+                            .bind(received_note.pool as i64)
+                            .bind(id_address)
+                            .bind(&received_note.address)
+                            .execute(&mut *db_tx)
+                            .await?;
+
+                            (account, sub_account)
+                        }
+                    };
+
+                    sqlx::query(
+                        "INSERT INTO received_notes
+                        (address, account, sub_account, id_tx, position, height,
+                        diversifier, value, rcm, nf, rho, memo, memo_status, spent, wallet, pool)
+                        VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,'','pending',0,$12,$13)",
+                    )
+                    .bind(&received_note.address)
+                    .bind(account)
+                    .bind(sub_account)
+                    .bind(id_tx)
+                    .bind(received_note.position as i64)
+                    .bind(received_note.height as i64)
+                    .bind(received_note.diversifier.as_slice())
+                    .bind(received_note.value as i64)
+                    .bind(received_note.rcm.as_slice())
+                    .bind(received_note.nf.as_slice())
+                    .bind(received_note.rho.map(|r| r.to_vec()))
+                    .bind(received_note.wallet as i64)
+                    .bind(received_note.pool as i64)
+                    .execute(&mut *db_tx)
+                    .await?;
+                    sqlx::query("UPDATE transactions SET value = value + $2 WHERE txid = $1")
+                        .bind(received_note.txid.as_slice())
+                        .bind(received_note.value as i64)
+                        .execute(&mut *db_tx)
+                        .await?;
+                }
+                ScanEvent::Spent(spent_note) => {
+                    let (id_tx, is_new) = Self::create_tx_if_not_exists(
+                        spent_note.height,
+                        spent_note.txid.as_slice(),
+                        db_tx,
+                    )
+                    .await?;
+                    if is_new {
+                        notify_txids.push(spent_note.txid);
+                    }
+                    sqlx::query(
+                        "UPDATE received_notes SET spent = $2, spent_tx = $3 WHERE nf = $1",
+                    )
+                    .bind(spent_note.nf.as_slice())
+                    .bind(spent_note.height as i64)
+                    .bind(id_tx)
+                    .execute(&mut *db_tx)
+                    .await?;
+                    sqlx::query("UPDATE transactions SET value = value - $2 WHERE txid = $1")
+                        .bind(spent_note.txid.as_slice())
+                        .bind(spent_note.value as i64)
+                        .execute(&mut *db_tx)
+                        .await?;
+                }
+                ScanEvent::Memo(memo_note) => {
+                    sqlx::query(
+                        "UPDATE received_notes SET memo = $2, memo_type = $3, memo_bytes = $4,
+                        payment_id = $5, memo_status = 'fetched'
+                        WHERE nf = $1",
+                    )
+                    .bind(memo_note.nf.as_slice())
+                    .bind(&memo_note.memo)
+                    .bind(memo_note.memo_type)
+                    .bind(&memo_note.memo_bytes)
+                    .bind(&memo_note.payment_id)
+                    .execute(&mut *db_tx)
+                    .await?;
+                }
+                ScanEvent::Block(height, hash, time) => {
+                    sqlx::query("INSERT INTO blocks(height, hash, time) VALUES ($1, $2, $3)")
+                        .bind(*height as i64)
+                        .bind(hash.as_slice())
+                        .bind(time.map(i64::from))
+                        .execute(&mut *db_tx)
+                        .await?;
+                }
+                ScanEvent::MemoTx(wtx) => {
+                    sqlx::query(
+                        "INSERT INTO memo_queue(txid, height, sap_position, orc_position)
+                        VALUES ($1, $2, $3, $4) ON CONFLICT (txid) DO NOTHING",
+                    )
+                    .bind(wtx.txid.as_slice())
+                    .bind(wtx.height as i64)
+                    .bind(wtx.sap_position as i64)
+                    .bind(wtx.orc_position as i64)
+                    .execute(&mut *db_tx)
+                    .await?;
+                }
+                ScanEvent::MemoFetched(txid) => {
+                    Self::dequeue_memo_tx(txid, &mut *db_tx).await?;
+                    // Notified again, now that its memos and payment ids are stored
+                    if !notify_txids.contains(txid) {
+                        notify_txids.push(*txid);
+                    }
+                }
+                ScanEvent::WalletHeight(wallet, from, height) => {
+                    // The wallet may have been removed or rewound meanwhile
+                    let updated = sqlx::query(
+                        "UPDATE wallets SET scan_height = $3
+                        WHERE id_wallet = $1 AND scan_height = $2",
+                    )
+                    .bind(*wallet as i64)
+                    .bind(*from as i64)
+                    .bind(*height as i64)
+                    .execute(&mut *db_tx)
+                    .await?;
+                    if updated.rows_affected() == 0 {
+                        anyhow::bail!("Wallet {wallet} is no longer at height {from}");
+                    }
+                }
+            }
+        }
+        db_transaction.commit().await?;
+
+        Ok(notify_txids)
+    }
+
+    async fn backup(&self, _path: &str, _snapshot: &SnapshotInfo) -> Result<()> {
+        bail!("Use pg_dump to back up a PostgreSQL database")
+    }
+
+    async fn read_snapshot_info(&self, _path: &str) -> Result<SnapshotInfo> {
+        bail!("Backups are only supported with SQLite")
+    }
+
+    async fn restore(&self, _path: &str) -> Result<()> {
+        bail!("Use pg_restore to restore a PostgreSQL database")
+    }
+}
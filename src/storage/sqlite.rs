@@ -0,0 +1,1264 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use rusqlite::backup::{Backup, StepResult};
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
+use sqlx::{Acquire, QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool};
+
+use super::migrations::migrate;
+use super::{blocks_to_unlock, SnapshotInfo, Storage, TransferRow, WalletKey, WalletMetadata};
+use crate::account::{AccountBalance, AddressInfo, Receivers, SubAccountBalance};
+use crate::db::fingerprint;
+use crate::network::Network;
+use crate::scan::{ScanEvent, WalletTx};
+use crate::transaction::{SubAddress, TransferFilter};
+use crate::Hash;
+
+pub struct SqliteStorage {
+    db_path: String,
+    /// Replaced by a new pool when a backup is restored
+    pool: RwLock<SqlitePool>,
+}
+
+impl SqliteStorage {
+    pub async fn open(db_path: &str) -> Result<Self> {
+        Ok(SqliteStorage {
+            db_path: db_path.to_string(),
+            pool: RwLock::new(Self::connect(db_path).await?),
+        })
+    }
+
+    async fn connect(db_path: &str) -> Result<SqlitePool> {
+        let options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .create_if_missing(true);
+        Ok(SqlitePool::connect_with(options).await?)
+    }
+
+    async fn acquire(&self) -> Result<PoolConnection<Sqlite>> {
+        let pool = self.pool.read().unwrap().clone();
+        Ok(pool.acquire().await?)
+    }
+
+    // Every connection to :memory: is a different database, so we
+    // must keep a single one open
+    #[cfg(test)]
+    pub async fn open_in_memory() -> Result<Self> {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(SqliteConnectOptions::new().filename(":memory:"))
+            .await?;
+        Ok(SqliteStorage {
+            db_path: ":memory:".to_string(),
+            pool: RwLock::new(pool),
+        })
+    }
+
+    async fn cleanup_stale_data(connection: &mut SqliteConnection) -> Result<()> {
+        sqlx::query(
+            "DELETE FROM received_notes WHERE height >=
+            (SELECT MAX(height) FROM blocks)",
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    // Notes whose output is not in the full transaction will never get their memo
+    async fn dequeue_memo_tx(txid: &Hash, connection: &mut SqliteConnection) -> Result<()> {
+        sqlx::query("DELETE FROM memo_queue WHERE txid = ?1")
+            .bind(txid.as_slice())
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query(
+            "UPDATE received_notes SET memo_status = 'failed'
+            WHERE memo_status = 'pending'
+            AND id_tx IN (SELECT id_tx FROM transactions WHERE txid = ?1)",
+        )
+        .bind(txid.as_slice())
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn store_metadata(
+        connection: &mut SqliteConnection,
+        metadata: &WalletMetadata,
+    ) -> Result<()> {
+        sqlx::query("DELETE FROM metadata")
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query(
+            "INSERT INTO metadata(ufvk_fingerprint, network, birth_height)
+            VALUES (?1, ?2, ?3)",
+        )
+        .bind(&metadata.ufvk_fingerprint)
+        .bind(&metadata.network)
+        .bind(metadata.birth_height)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn check_account_exists(
+        connection: &mut SqliteConnection,
+        wallet: u32,
+        account: u32,
+    ) -> Result<()> {
+        if sqlx::query(
+            "SELECT 1 FROM addresses WHERE wallet = ?1 AND account = ?2 AND sub_account = 0",
+        )
+        .bind(wallet)
+        .bind(account)
+        .fetch_optional(&mut *connection)
+        .await?
+        .is_none()
+        {
+            anyhow::bail!("Unknown account index {account}");
+        }
+        Ok(())
+    }
+
+    fn row_to_transfer(row: SqliteRow) -> TransferRow {
+        TransferRow {
+            address: row.get(0),
+            value: row.get(1),
+            account: row.get(2),
+            sub_account: row.get(3),
+            txid: row.get(4),
+            memo: row.get(5),
+            height: row.get(6),
+            r#type: row.get(7),
+            label: row.get(8),
+            memo_type: row.get(9),
+            memo_bytes: row.get::<Option<Vec<u8>>, _>(10).unwrap_or_default(),
+            payment_id: row.get::<Option<String>, _>(11).unwrap_or_default(),
+            memo_status: row.get(12),
+        }
+    }
+
+    fn push_transfer_filter(
+        builder: &mut QueryBuilder<'_, Sqlite>,
+        filter: &TransferFilter,
+        height_column: &str,
+    ) {
+        builder.push(" AND n.wallet = ").push_bind(filter.wallet);
+        if let Some(account_index) = filter.account_index {
+            builder.push(" AND n.account = ").push_bind(account_index);
+        }
+        // An empty list of sub accounts selects all of them
+        if !filter.sub_accounts.is_empty() {
+            builder.push(" AND n.sub_account IN (");
+            let mut separated = builder.separated(", ");
+            for sub_account in filter.sub_accounts.iter() {
+                separated.push_bind(*sub_account);
+            }
+            separated.push_unseparated(")");
+        }
+        if !filter.payment_ids.is_empty() {
+            builder.push(" AND n.payment_id IN (");
+            let mut separated = builder.separated(", ");
+            for payment_id in filter.payment_ids.iter() {
+                separated.push_bind(payment_id.clone());
+            }
+            separated.push_unseparated(")");
+        }
+        // Same semantics as monero: min_height is exclusive, max_height inclusive
+        if let Some((min_height, max_height)) = filter.height_range {
+            builder
+                .push(format!(" AND {height_column} > "))
+                .push_bind(min_height)
+                .push(format!(" AND {height_column} <= "))
+                .push_bind(max_height);
+        }
+    }
+
+    // Copy all the pages in a single step, so that the copy is consistent
+    // even if another connection writes to the source
+    fn copy_database(src: &rusqlite::Connection, dst: &mut rusqlite::Connection) -> Result<()> {
+        let backup = Backup::new(src, dst)?;
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => return Ok(()),
+                _ => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    // Backups of older versions have no wallet_fingerprints. The keys
+    // are then taken from their wallets table, if they have one
+    fn snapshot_wallets(
+        connection: &rusqlite::Connection,
+        snapshot: &SnapshotInfo,
+    ) -> Result<BTreeMap<u32, String>> {
+        if let Ok(fingerprints) =
+            connection.query_row("SELECT wallet_fingerprints FROM snapshot", [], |row| {
+                row.get::<_, String>(0)
+            })
+        {
+            return Ok(serde_json::from_str(&fingerprints)?);
+        }
+        let mut fingerprints = BTreeMap::from([(0, snapshot.ufvk_fingerprint.clone())]);
+        let has_wallets: bool = connection.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'wallets')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_wallets {
+            let mut statement = connection.prepare("SELECT id_wallet, vk FROM wallets")?;
+            let wallets = statement.query_map([], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+            })?;
+            for wallet in wallets {
+                let (id, vk) = wallet?;
+                fingerprints.insert(id, fingerprint(&vk));
+            }
+        }
+        Ok(fingerprints)
+    }
+
+    async fn create_tx_if_not_exists(
+        height: u32,
+        txid: &[u8],
+        db_tx: &mut SqliteConnection,
+    ) -> Result<(u32, bool)> {
+        let result = match sqlx::query("SELECT id_tx FROM transactions WHERE txid = ?1")
+            .bind(txid)
+            .map(|r: SqliteRow| r.get::<u32, _>(0))
+            .fetch_optional(&mut *db_tx)
+            .await?
+        {
+            Some(id_tx) => (id_tx, false),
+            None => {
+                let r =
+                    sqlx::query("INSERT INTO transactions(txid, height, value) VALUES (?1, ?2, 0)")
+                        .bind(txid)
+                        .bind(height)
+                        .execute(db_tx)
+                        .await?;
+                let id_tx = r.last_insert_rowid();
+
+                (id_tx as u32, true)
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for SqliteStorage {
+    async fn create(&self, network: &Network) -> Result<bool> {
+        let mut connection = self.acquire().await?;
+
+        migrate(network, &mut connection).await?;
+        Self::cleanup_stale_data(&mut connection).await?;
+
+        let r = sqlx::query("SELECT 1 FROM addresses")
+            .map(|r: SqliteRow| r.get::<u32, _>(0))
+            .fetch_optional(&mut *connection)
+            .await?;
+
+        Ok(r.is_some())
+    }
+
+    async fn get_metadata(&self) -> Result<Option<WalletMetadata>> {
+        let mut connection = self.acquire().await?;
+        let metadata = sqlx::query("SELECT ufvk_fingerprint, network, birth_height FROM metadata")
+            .map(|row: SqliteRow| WalletMetadata {
+                ufvk_fingerprint: row.get(0),
+                network: row.get(1),
+                birth_height: row.get(2),
+            })
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(metadata)
+    }
+
+    async fn set_metadata(&self, metadata: &WalletMetadata) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        Self::store_metadata(&mut connection, metadata).await
+    }
+
+    async fn reset(&self, metadata: &WalletMetadata) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        for table in [
+            "received_notes",
+            "transactions",
+            "blocks",
+            "receivers",
+            "addresses",
+            "account_tags",
+            "tags",
+            "wallets",
+            "memo_queue",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table}"))
+                .execute(&mut *db_transaction)
+                .await?;
+        }
+        Self::store_metadata(&mut db_transaction, metadata).await?;
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_wallets(&self) -> Result<Vec<WalletKey>> {
+        let mut connection = self.acquire().await?;
+        let wallets = sqlx::query(
+            "SELECT id_wallet, label, vk, birth_height, scan_height
+            FROM wallets ORDER BY id_wallet",
+        )
+        .map(|row: SqliteRow| WalletKey {
+            id: row.get(0),
+            label: row.get(1),
+            vk: row.get(2),
+            birth_height: row.get(3),
+            scan_height: row.get(4),
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(wallets)
+    }
+
+    async fn store_wallet(&self, wallet: &WalletKey) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        sqlx::query(
+            "INSERT INTO wallets(id_wallet, label, vk, birth_height, scan_height)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(wallet.id)
+        .bind(&wallet.label)
+        .bind(&wallet.vk)
+        .bind(wallet.birth_height)
+        .bind(wallet.scan_height)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_wallet_scan_height(&self, wallet: u32, height: Option<u32>) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        sqlx::query("UPDATE wallets SET scan_height = ?2 WHERE id_wallet = ?1")
+            .bind(wallet)
+            .bind(height)
+            .execute(&mut *connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_wallet(&self, wallet: u32) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        sqlx::query(
+            "DELETE FROM receivers WHERE id_address IN
+            (SELECT id_address FROM addresses WHERE wallet = ?1)",
+        )
+        .bind(wallet)
+        .execute(&mut *db_transaction)
+        .await?;
+        for table in ["addresses", "received_notes", "account_tags", "tags"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE wallet = ?1"))
+                .bind(wallet)
+                .execute(&mut *db_transaction)
+                .await?;
+        }
+        sqlx::query("DELETE FROM wallets WHERE id_wallet = ?1")
+            .bind(wallet)
+            .execute(&mut *db_transaction)
+            .await?;
+        // Transactions are shared, only those of the wallet go away
+        sqlx::query(
+            "DELETE FROM transactions WHERE NOT EXISTS
+            (SELECT 1 FROM received_notes n
+            WHERE n.id_tx = transactions.id_tx OR n.spent_tx = transactions.id_tx)",
+        )
+        .execute(&mut *db_transaction)
+        .await?;
+        // and so do the memos to fetch that no other wallet needs
+        sqlx::query(
+            "DELETE FROM memo_queue WHERE NOT EXISTS
+            (SELECT 1 FROM received_notes n JOIN transactions t ON n.id_tx = t.id_tx
+            WHERE t.txid = memo_queue.txid)",
+        )
+        .execute(&mut *db_transaction)
+        .await?;
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn max_account(&self, wallet: u32) -> Result<Option<u32>> {
+        let mut connection = self.acquire().await?;
+        let (id_account,): (Option<u32>,) =
+            sqlx::query_as("SELECT MAX(account) FROM addresses WHERE wallet = ?1")
+                .bind(wallet)
+                .fetch_one(&mut *connection)
+                .await?;
+        Ok(id_account)
+    }
+
+    async fn max_sub_account(&self, wallet: u32, account: u32) -> Result<Option<u32>> {
+        let mut connection = self.acquire().await?;
+        let (id_sub_account,): (Option<u32>,) = sqlx::query_as(
+            "SELECT MAX(sub_account) FROM addresses WHERE wallet = ?1 AND account = ?2",
+        )
+        .bind(wallet)
+        .bind(account)
+        .fetch_one(&mut *connection)
+        .await?;
+        Ok(id_sub_account)
+    }
+
+    async fn max_diversifier_index(&self, wallet: u32) -> Result<Option<u64>> {
+        let mut connection = self.acquire().await?;
+        let di = sqlx::query("SELECT MAX(diversifier_index) FROM addresses WHERE wallet = ?1")
+            .bind(wallet)
+            .map(|r: SqliteRow| r.get::<Option<u64>, _>(0))
+            .fetch_one(&mut *connection)
+            .await?;
+        Ok(di)
+    }
+
+    async fn store_address(
+        &self,
+        wallet: u32,
+        label: &str,
+        account: u32,
+        sub_account: u32,
+        diversifier_index: u64,
+        address: &str,
+        receivers: &[(u8, String)],
+    ) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let r = sqlx::query("INSERT INTO addresses(label, account, sub_account, address, diversifier_index, wallet) VALUES (?1,?2,?3,?4,?5,?6)")
+            .bind(label)
+            .bind(account)
+            .bind(sub_account)
+            .bind(address)
+            .bind(diversifier_index as i64)
+            .bind(wallet)
+            .execute(&mut *db_transaction)
+            .await?;
+        let id_address = r.last_insert_rowid() as u32;
+
+        for (pool, receiver) in receivers {
+            sqlx::query(
+                "INSERT INTO receivers(pool, id_address, receiver_address)
+                VALUES (?1, ?2, ?3)",
+            )
+            .bind(pool)
+            .bind(id_address)
+            .bind(receiver)
+            .execute(&mut *db_transaction)
+            .await?;
+        }
+        db_transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_addresses(
+        &self,
+        wallet: u32,
+        account: u32,
+        sub_accounts: &[u32],
+    ) -> Result<Vec<AddressInfo>> {
+        let mut connection = self.acquire().await?;
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT a.sub_account, a.address, a.label, a.diversifier_index, \
+            MAX(CASE WHEN r.pool = 0 THEN r.receiver_address END), \
+            MAX(CASE WHEN r.pool = 1 THEN r.receiver_address END), \
+            MAX(CASE WHEN r.pool = 2 THEN r.receiver_address END), \
+            EXISTS(SELECT 1 FROM received_notes n \
+                WHERE n.wallet = a.wallet AND n.account = a.account \
+                AND n.sub_account = a.sub_account) \
+            FROM addresses a LEFT JOIN receivers r ON r.id_address = a.id_address \
+            WHERE a.wallet = ",
+        );
+        builder
+            .push_bind(wallet)
+            .push(" AND a.account = ")
+            .push_bind(account);
+        if !sub_accounts.is_empty() {
+            builder.push(" AND a.sub_account IN (");
+            let mut separated = builder.separated(", ");
+            for sub_account in sub_accounts.iter() {
+                separated.push_bind(*sub_account);
+            }
+            separated.push_unseparated(")");
+        }
+        builder.push(" GROUP BY a.id_address ORDER BY a.sub_account");
+
+        let addresses = builder
+            .build()
+            .map(|row: SqliteRow| AddressInfo {
+                address_index: row.get(0),
+                address: row.get(1),
+                label: row.get(2),
+                diversifier_index: row.get::<i64, _>(3) as u64,
+                receivers: Receivers {
+                    transparent: row.get(4),
+                    sapling: row.get(5),
+                    orchard: row.get(6),
+                },
+                used: row.get(7),
+            })
+            .fetch_all(&mut *connection)
+            .await?;
+        Ok(addresses)
+    }
+
+    async fn find_address(&self, wallet: u32, address: &str) -> Result<Option<SubAddress>> {
+        let mut connection = self.acquire().await?;
+        let index = sqlx::query(
+            "SELECT a.account, a.sub_account FROM addresses a
+            LEFT JOIN receivers r ON a.id_address = r.id_address
+            WHERE a.wallet = ?2 AND (a.address = ?1 OR r.receiver_address = ?1) LIMIT 1",
+        )
+        .bind(address)
+        .bind(wallet)
+        .map(|r: SqliteRow| SubAddress {
+            major: r.get(0),
+            minor: r.get(1),
+        })
+        .fetch_optional(&mut *connection)
+        .await?;
+        Ok(index)
+    }
+
+    async fn get_accounts(
+        &self,
+        wallet: u32,
+        confirmed_height: u32,
+        tag: Option<&str>,
+    ) -> Result<Vec<AccountBalance>> {
+        let mut connection = self.acquire().await?;
+        // Start from the base addresses so that accounts without any
+        // unspent note are listed too
+        let accounts = sqlx::query(
+            "SELECT a.account, a.label, COALESCE(SUM(n.value), 0) AS total, \
+                COALESCE(SUM(CASE WHEN n.height <= ?1 THEN n.value ELSE 0 END), 0) AS unlocked, \
+                a.address AS base_address, COALESCE(t.tag, '') \
+                FROM addresses a LEFT JOIN received_notes n \
+                ON n.wallet = a.wallet AND n.account = a.account AND COALESCE(n.spent, 0) = 0 \
+                LEFT JOIN account_tags t ON t.wallet = a.wallet AND t.account = a.account \
+                WHERE a.wallet = ?3 AND a.sub_account = 0 AND (?2 IS NULL OR t.tag = ?2) \
+                GROUP BY a.account ORDER BY a.account",
+        )
+        .bind(confirmed_height)
+        .bind(tag)
+        .bind(wallet)
+        .map(|row: SqliteRow| {
+            let id_account: u32 = row.get(0);
+            let label: String = row.get(1);
+            let balance: u64 = row.get(2);
+            let unlocked: u64 = row.get(3);
+            let base_address: String = row.get(4);
+            let tag: String = row.get(5);
+            AccountBalance {
+                account_index: id_account,
+                label,
+                balance,
+                unlocked_balance: unlocked,
+                base_address,
+                tag,
+            }
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+
+        Ok(accounts)
+    }
+
+    async fn get_subaddress_balances(
+        &self,
+        wallet: u32,
+        height: u32,
+        account_index: u32,
+        sub_accounts: &[u32],
+        confirmations: u32,
+    ) -> Result<Vec<SubAccountBalance>> {
+        let mut connection = self.acquire().await?;
+        let confirmed_height = height - confirmations + 1;
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT a.sub_account, a.address, a.label, COALESCE(SUM(n.value), 0), \
+            COALESCE(SUM(CASE WHEN n.height <= ",
+        );
+        builder
+            .push_bind(confirmed_height)
+            .push(
+                " THEN n.value ELSE 0 END), 0), COUNT(n.id_note), MAX(n.height) \
+                FROM addresses a LEFT JOIN received_notes n \
+                ON n.wallet = a.wallet AND n.account = a.account \
+                AND n.sub_account = a.sub_account \
+                AND COALESCE(n.spent, 0) = 0 WHERE a.wallet = ",
+            )
+            .push_bind(wallet)
+            .push(" AND a.account = ")
+            .push_bind(account_index);
+        if !sub_accounts.is_empty() {
+            builder.push(" AND a.sub_account IN (");
+            let mut separated = builder.separated(", ");
+            for sub_account in sub_accounts.iter() {
+                separated.push_bind(*sub_account);
+            }
+            separated.push_unseparated(")");
+        }
+        builder.push(" GROUP BY a.sub_account ORDER BY a.sub_account");
+
+        let balances = builder
+            .build()
+            .map(|row: SqliteRow| {
+                let sub_account: u32 = row.get(0);
+                let address: String = row.get(1);
+                let label: String = row.get(2);
+                let balance: u64 = row.get(3);
+                let unlocked: u64 = row.get(4);
+                let num_unspent_outputs: u32 = row.get(5);
+                let last_height: Option<u32> = row.get(6);
+                SubAccountBalance {
+                    account_index,
+                    address_index: sub_account,
+                    address,
+                    balance,
+                    unlocked_balance: unlocked,
+                    label,
+                    num_unspent_outputs,
+                    blocks_to_unlock: blocks_to_unlock(last_height, height, confirmations),
+                }
+            })
+            .fetch_all(&mut *connection)
+            .await?;
+
+        Ok(balances)
+    }
+
+    async fn set_address_label(
+        &self,
+        wallet: u32,
+        account: u32,
+        sub_account: u32,
+        label: &str,
+    ) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let r = sqlx::query(
+            "UPDATE addresses SET label = ?3 WHERE wallet = ?4 AND account = ?1 AND sub_account = ?2",
+        )
+        .bind(account)
+        .bind(sub_account)
+        .bind(label)
+        .bind(wallet)
+        .execute(&mut *connection)
+        .await?;
+        if r.rows_affected() == 0 {
+            anyhow::bail!("Unknown address index {account}/{sub_account}");
+        }
+        Ok(())
+    }
+
+    async fn tag_accounts(&self, wallet: u32, tag: &str, accounts: &[u32]) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        for &account in accounts {
+            Self::check_account_exists(&mut db_transaction, wallet, account).await?;
+            sqlx::query(
+                "INSERT INTO account_tags(wallet, account, tag) VALUES (?1, ?2, ?3)
+                ON CONFLICT (wallet, account) DO UPDATE SET tag = excluded.tag",
+            )
+            .bind(wallet)
+            .bind(account)
+            .bind(tag)
+            .execute(&mut *db_transaction)
+            .await?;
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn untag_accounts(&self, wallet: u32, accounts: &[u32]) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        for &account in accounts {
+            Self::check_account_exists(&mut db_transaction, wallet, account).await?;
+            sqlx::query("DELETE FROM account_tags WHERE wallet = ?1 AND account = ?2")
+                .bind(wallet)
+                .bind(account)
+                .execute(&mut *db_transaction)
+                .await?;
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn set_tag_description(&self, wallet: u32, tag: &str, description: &str) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        sqlx::query(
+            "INSERT INTO tags(wallet, tag, description) VALUES (?1, ?2, ?3)
+            ON CONFLICT (wallet, tag) DO UPDATE SET description = excluded.description",
+        )
+        .bind(wallet)
+        .bind(tag)
+        .bind(description)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_tagged_accounts(&self, wallet: u32) -> Result<Vec<(String, String, u32)>> {
+        let mut connection = self.acquire().await?;
+        let rows = sqlx::query(
+            "SELECT t.tag, COALESCE(d.description, ''), t.account \
+            FROM account_tags t LEFT JOIN tags d ON d.wallet = t.wallet AND d.tag = t.tag \
+            WHERE t.wallet = ?1 ORDER BY t.tag, t.account",
+        )
+        .bind(wallet)
+        .map(|row: SqliteRow| {
+            let tag: String = row.get(0);
+            let description: String = row.get(1);
+            let account: u32 = row.get(2);
+            (tag, description, account)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_synced_height(&self) -> Result<Option<u32>> {
+        let mut connection = self.acquire().await?;
+        let height = sqlx::query("SELECT MAX(height) FROM blocks")
+            .map(|row: SqliteRow| row.get::<Option<u32>, _>(0))
+            .fetch_one(&mut *connection)
+            .await?;
+        Ok(height)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<Option<Hash>> {
+        let mut connection = self.acquire().await?;
+
+        let hash = sqlx::query("SELECT hash FROM blocks WHERE height = ?1")
+            .bind(height)
+            .map(|row: SqliteRow| {
+                let hash_vec: Vec<u8> = row.get(0);
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&hash_vec);
+                hash
+            })
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(hash)
+    }
+
+    async fn get_block_time(&self, height: u32) -> Result<Option<u32>> {
+        let mut connection = self.acquire().await?;
+        let time = sqlx::query("SELECT time FROM blocks WHERE height = ?1")
+            .bind(height)
+            .map(|row: SqliteRow| row.get::<Option<u32>, _>(0))
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(time.flatten())
+    }
+
+    async fn store_block(&self, height: u32, hash: &Hash, time: Option<u32>) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        sqlx::query(
+            "INSERT INTO blocks(hash, height, time)
+            VALUES (?1, ?2, ?3)",
+        )
+        .bind(hash.as_slice())
+        .bind(height)
+        .bind(time)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        sqlx::query("DELETE FROM blocks WHERE height < 0")
+            .execute(&mut *db_transaction)
+            .await?;
+        db_transaction.rollback().await?;
+        Ok(())
+    }
+
+    async fn get_transfers(&self, filter: &TransferFilter) -> Result<Vec<TransferRow>> {
+        let mut connection = self.acquire().await?;
+
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT n.address, n.value, n.account, n.sub_account, txid, memo, n.height, 'in', \
+            COALESCE(a.label, ''), COALESCE(n.memo_type, ''), n.memo_bytes, n.payment_id, \
+            n.memo_status \
+            FROM received_notes n JOIN transactions t ON n.id_tx = t.id_tx \
+            LEFT JOIN addresses a ON a.wallet = n.wallet AND a.account = n.account \
+            AND a.sub_account = n.sub_account \
+            WHERE 1 = 1",
+        );
+        Self::push_transfer_filter(&mut builder, filter, "n.height");
+        builder.push(" ORDER BY n.height, n.id_note");
+        let transfers = builder
+            .build()
+            .map(Self::row_to_transfer)
+            .fetch_all(&mut *connection)
+            .await?;
+        Ok(transfers)
+    }
+
+    async fn get_outgoing_transfers(&self, filter: &TransferFilter) -> Result<Vec<TransferRow>> {
+        let mut connection = self.acquire().await?;
+
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT a.address, SUM(n.value), n.account, n.sub_account, t.txid, '', t.height, 'out', \
+            a.label, '', NULL, NULL, '' \
+            FROM received_notes n JOIN transactions t ON n.spent_tx = t.id_tx \
+            JOIN addresses a ON a.wallet = n.wallet AND a.account = n.account \
+            AND a.sub_account = n.sub_account \
+            WHERE 1 = 1",
+        );
+        Self::push_transfer_filter(&mut builder, filter, "t.height");
+        builder.push(" GROUP BY t.id_tx, n.account, n.sub_account ORDER BY t.height");
+        let transfers = builder
+            .build()
+            .map(Self::row_to_transfer)
+            .fetch_all(&mut *connection)
+            .await?;
+        Ok(transfers)
+    }
+
+    async fn get_transfers_by_txid(
+        &self,
+        wallet: u32,
+        account: Option<u32>,
+        txid: &[u8],
+    ) -> Result<Vec<TransferRow>> {
+        let mut connection = self.acquire().await?;
+
+        let transfers = sqlx::query(
+            "SELECT a.address, n.value, n.account, n.sub_account, txid, memo, n.height, 'in', a.label,
+            COALESCE(n.memo_type, ''), n.memo_bytes, n.payment_id, n.memo_status
+            FROM received_notes n
+			JOIN transactions t ON n.id_tx = t.id_tx
+			JOIN receivers r ON n.address = r.receiver_address
+			JOIN addresses a ON a.id_address = r.id_address
+            WHERE txid = ?1 AND n.wallet = ?2 AND (?3 IS NULL OR n.account = ?3)
+			ORDER BY n.height",
+        )
+        .bind(txid)
+        .bind(wallet)
+        .bind(account)
+        .map(Self::row_to_transfer)
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(transfers)
+    }
+
+    async fn truncate_height(&self, height: u32) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+
+        sqlx::query("DELETE FROM transactions WHERE height >= ?1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("DELETE FROM received_notes WHERE height >= ?1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("DELETE FROM blocks WHERE height >= ?1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("UPDATE received_notes SET spent = 0, spent_tx = NULL WHERE spent >= ?1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("UPDATE wallets SET scan_height = ?1 - 1 WHERE scan_height >= ?1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        sqlx::query("DELETE FROM memo_queue WHERE height >= ?1")
+            .bind(height)
+            .execute(&mut *db_transaction)
+            .await?;
+        db_transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_memo_queue(&self, now: u64, limit: u32) -> Result<Vec<(WalletTx, u32)>> {
+        let mut connection = self.acquire().await?;
+        let queue = sqlx::query(
+            "SELECT txid, height, sap_position, orc_position, attempts FROM memo_queue
+            WHERE next_attempt <= ?1 ORDER BY height LIMIT ?2",
+        )
+        .bind(now as i64)
+        .bind(limit)
+        .map(|row: SqliteRow| {
+            let txid: Vec<u8> = row.get(0);
+            let wtx = WalletTx {
+                height: row.get(1),
+                txid: txid.try_into().unwrap(),
+                sap_position: row.get(2),
+                orc_position: row.get(3),
+            };
+            (wtx, row.get(4))
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(queue)
+    }
+
+    async fn retry_memo_tx(
+        &self,
+        txid: &Hash,
+        attempts: u32,
+        next_attempt: Option<u64>,
+    ) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        match next_attempt {
+            Some(next_attempt) => {
+                sqlx::query(
+                    "UPDATE memo_queue SET attempts = ?2, next_attempt = ?3 WHERE txid = ?1",
+                )
+                .bind(txid.as_slice())
+                .bind(attempts)
+                .bind(next_attempt as i64)
+                .execute(&mut *db_transaction)
+                .await?;
+            }
+            None => Self::dequeue_memo_tx(txid, &mut db_transaction).await?,
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_nfs(&self, wallet: u32) -> Result<HashMap<Hash, u64>> {
+        let mut connection = self.acquire().await?;
+
+        let nfs = sqlx::query(
+            "SELECT nf, value FROM received_notes WHERE wallet = ?1 AND COALESCE(spent, 0) = 0",
+        )
+        .bind(wallet)
+        .map(|row: SqliteRow| {
+            let nf: Vec<u8> = row.get(0);
+            let value: u64 = row.get(1);
+            let nf: Hash = nf.try_into().unwrap();
+            (nf, value)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+
+        let mut nf_map = HashMap::new();
+        for (nf, value) in nfs {
+            nf_map.insert(nf, value);
+        }
+        Ok(nf_map)
+    }
+
+    async fn store_events(&self, events: &[ScanEvent]) -> Result<Vec<Hash>> {
+        let mut connection = self.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+        let mut notify_txids = vec![];
+
+        for event in events {
+            match event {
+                ScanEvent::Received(received_note) => {
+                    let (id_tx, is_new) = Self::create_tx_if_not_exists(
+                        received_note.height,
+                        received_note.txid.as_slice(),
+                        db_tx,
+                    )
+                    .await?;
+                    if is_new {
+                        notify_txids.push(received_note.txid);
+                    }
+
+                    let (account, sub_account) = match sqlx::query(
+                        "SELECT a.account, a.sub_account FROM addresses a
+                        JOIN receivers r ON a.id_address = r.id_address
+                        WHERE a.wallet = ?2 AND r.receiver_address = ?1",
+                    )
+                    .bind(&received_note.address)
+                    .bind(received_note.wallet)
+                    .map(|r: SqliteRow| {
+                        let account: u32 = r.get(0);
+                        let sub_account: u32 = r.get(1);
+                        (account, sub_account)
+                    })
+                    .fetch_optional(&mut *db_tx)
+                    .await?
+                    {
+                        Some(x) => x,
+                        None => {
+                            let account =
+                                sqlx::query("SELECT MAX(account) FROM addresses WHERE wallet = ?1")
+                                    .bind(received_note.wallet)
+                                    .map(|r: SqliteRow| {
+                                        let account: Option<u32> = r.get(0);
+                                        account.unwrap_or_default()
+                                    })
+                                    .fetch_one(&mut *db_tx)
+                                    .await?;
+                            let sub_account = sqlx::query(
+                                "SELECT MAX(sub_account) FROM addresses
+                                WHERE wallet = ?2 AND account = ?1",
+                            )
+                            .bind(account)
+                            .bind(received_note.wallet)
+                            .map(|r: SqliteRow| {
+                                let sub_account: Option<u32> = r.get(0);
+                                sub_account.map(|x| x + 1).unwrap_or_default()
+                            })
+                            .fetch_optional(&mut *db_tx)
+                            .await?
+                            .unwrap_or_default();
+
+                            let r = sqlx::query(
+                                "INSERT INTO addresses
+                            (label, account, sub_account, address, diversifier_index, wallet)
+                            VALUES ('', ?1, ?2, ?3, ?4, ?5)",
+                            )
+                            .bind(account)
+                            .bind(sub_account)
+                            .bind(&received_note.address)
+                            .bind(received_note.diversifier_index.unwrap_or_default() as u32)
+                            .bind(received_note.wallet)
+                            .execute(&mut *db_tx)
+                            .await?;
+                            let id_address = r.last_insert_rowid() as u32;
+
+                            sqlx::query(
+                                "INSERT INTO receivers(pool, id_address, receiver_address)
+                                VALUES (?1, ?2, ?3)",
+                            )
+                            .bind(received_note.pool)
+                            .bind(id_address)
+                            .bind(&received_note.address)
+                            .execute(&mut *db_tx)
+                            .await?;
+
+                            (account, sub_account)
+                        }
+                    };
+
+                    sqlx::query(
+                        "INSERT INTO received_notes
+                        (address, account, sub_account, id_tx, position, height,
+                        diversifier, value, rcm, nf, rho, memo, memo_status, spent, wallet, pool)
+                        VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,'','pending',0,?12,?13)",
+                    )
+                    .bind(&received_note.address)
+                    .bind(account)
+                    .bind(sub_account)
+                    .bind(id_tx)
+                    .bind(received_note.position)
+                    .bind(received_note.height)
+                    .bind(received_note.diversifier.as_slice())
+                    .bind(received_note.value as i64)
+                    .bind(received_note.rcm.as_slice())
+                    .bind(received_note.nf.as_slice())
+                    .bind(received_note.rho.map(|r| r.to_vec()))
+                    .bind(received_note.wallet)
+                    .bind(received_note.pool)
+                    .execute(&mut *db_tx)
+                    .await?;
+                    sqlx::query("UPDATE transactions SET value = value + ?2 WHERE txid = ?1")
+                        .bind(received_note.txid.as_slice())
+                        .bind(received_note.value as i64)
+                        .execute(&mut *db_tx)
+                        .await?;
+                }
+                ScanEvent::Spent(spent_note) => {
+                    let (id_tx, is_new) = Self::create_tx_if_not_exists(
+                        spent_note.height,
+                        spent_note.txid.as_slice(),
+                        db_tx,
+                    )
+                    .await?;
+                    if is_new {
+                        notify_txids.push(spent_note.txid);
+                    }
+                    sqlx::query(
+                        "UPDATE received_notes SET spent = ?2, spent_tx = ?3 WHERE nf = ?1",
+                    )
+                    .bind(spent_note.nf.as_slice())
+                    .bind(spent_note.height)
+                    .bind(id_tx)
+                    .execute(&mut *db_tx)
+                    .await?;
+                    sqlx::query("UPDATE transactions SET value = value - ?2 WHERE txid = ?1")
+                        .bind(spent_note.txid.as_slice())
+                        .bind(spent_note.value as i64)
+                        .execute(&mut *db_tx)
+                        .await?;
+                }
+                ScanEvent::Memo(memo_note) => {
+                    sqlx::query(
+                        "UPDATE received_notes SET memo = ?2, memo_type = ?3, memo_bytes = ?4,
+                        payment_id = ?5, memo_status = 'fetched'
+                        WHERE nf = ?1",
+                    )
+                    .bind(memo_note.nf.as_slice())
+                    .bind(&memo_note.memo)
+                    .bind(memo_note.memo_type)
+                    .bind(&memo_note.memo_bytes)
+                    .bind(&memo_note.payment_id)
+                    .execute(&mut *db_tx)
+                    .await?;
+                }
+                ScanEvent::Block(height, hash, time) => {
+                    sqlx::query(
+                        "INSERT INTO blocks(height, hash, time)
+                        VALUES (?1, ?2, ?3)",
+                    )
+                    .bind(*height)
+                    .bind(hash.as_slice())
+                    .bind(*time)
+                    .execute(&mut *db_tx)
+                    .await?;
+                }
+                ScanEvent::MemoTx(wtx) => {
+                    sqlx::query(
+                        "INSERT INTO memo_queue(txid, height, sap_position, orc_position)
+                        VALUES (?1, ?2, ?3, ?4) ON CONFLICT (txid) DO NOTHING",
+                    )
+                    .bind(wtx.txid.as_slice())
+                    .bind(wtx.height)
+                    .bind(wtx.sap_position)
+                    .bind(wtx.orc_position)
+                    .execute(&mut *db_tx)
+                    .await?;
+                }
+                ScanEvent::MemoFetched(txid) => {
+                    Self::dequeue_memo_tx(txid, &mut *db_tx).await?;
+                    // Notified again, now that its memos and payment ids are stored
+                    if !notify_txids.contains(txid) {
+                        notify_txids.push(*txid);
+                    }
+                }
+                ScanEvent::WalletHeight(wallet, from, height) => {
+                    // The wallet may have been removed or rewound meanwhile
+                    let updated = sqlx::query(
+                        "UPDATE wallets SET scan_height = ?3
+                        WHERE id_wallet = ?1 AND scan_height = ?2",
+                    )
+                    .bind(*wallet)
+                    .bind(*from)
+                    .bind(*height)
+                    .execute(&mut *db_tx)
+                    .await?;
+                    if updated.rows_affected() == 0 {
+                        anyhow::bail!("Wallet {wallet} is no longer at height {from}");
+                    }
+                }
+            }
+        }
+        db_transaction.commit().await?;
+
+        Ok(notify_txids)
+    }
+
+    async fn backup(&self, path: &str, snapshot: &SnapshotInfo) -> Result<()> {
+        if Path::new(path).exists() {
+            anyhow::bail!("Backup file {path} already exists");
+        }
+        let db_path = self.db_path.clone();
+        let backup_path = path.to_string();
+        let snapshot = snapshot.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let src = rusqlite::Connection::open(&db_path)?;
+            let mut dst = rusqlite::Connection::open(&backup_path)?;
+            Self::copy_database(&src, &mut dst)?;
+            dst.execute_batch(
+                "CREATE TABLE snapshot (
+                schema_version INTEGER NOT NULL,
+                synced_height INTEGER NOT NULL,
+                ufvk_fingerprint TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                wallet_fingerprints TEXT NOT NULL)",
+            )?;
+            dst.execute(
+                "INSERT INTO snapshot(schema_version, synced_height, ufvk_fingerprint, created_at,
+                wallet_fingerprints) VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![
+                    snapshot.schema_version,
+                    snapshot.synced_height,
+                    snapshot.ufvk_fingerprint,
+                    snapshot.created_at as i64,
+                    serde_json::to_string(&snapshot.wallet_fingerprints)?,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+        // A partial copy must not pass for a backup
+        if result.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    async fn read_snapshot_info(&self, path: &str) -> Result<SnapshotInfo> {
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            let connection = rusqlite::Connection::open_with_flags(
+                &path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            let mut snapshot = connection
+                .query_row(
+                    "SELECT schema_version, synced_height, ufvk_fingerprint, created_at
+                    FROM snapshot",
+                    [],
+                    |row| {
+                        Ok(SnapshotInfo {
+                            schema_version: row.get(0)?,
+                            synced_height: row.get(1)?,
+                            ufvk_fingerprint: row.get(2)?,
+                            created_at: row.get::<_, i64>(3)? as u64,
+                            wallet_fingerprints: BTreeMap::new(),
+                        })
+                    },
+                )
+                .map_err(|_| anyhow::anyhow!("{path} is not a wallet backup"))?;
+            snapshot.wallet_fingerprints = Self::snapshot_wallets(&connection, &snapshot)?;
+            Ok(snapshot)
+        })
+        .await?
+    }
+
+    async fn restore(&self, path: &str) -> Result<()> {
+        // No connection of the pool may use the file while it is overwritten.
+        // Closing the pool waits for those in use, and fails the new ones
+        // until it is replaced
+        let pool = self.pool.read().unwrap().clone();
+        pool.close().await;
+        let db_path = self.db_path.clone();
+        let path = path.to_string();
+        let result = tokio::task::spawn_blocking(move || {
+            let src = rusqlite::Connection::open_with_flags(
+                &path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            let mut dst = rusqlite::Connection::open(&db_path)?;
+            Self::copy_database(&src, &mut dst)?;
+            dst.execute_batch("DROP TABLE snapshot")?;
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+        let pool = Self::connect(&self.db_path).await?;
+        *self.pool.write().unwrap() = pool;
+        result
+    }
+}
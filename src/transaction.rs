@@ -1,5 +1,8 @@
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use rocket::serde::{Deserialize, Serialize};
 
+use crate::memo::MemoFields;
+
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
 pub struct SubAddress {
     pub major: u32,
@@ -21,5 +24,42 @@ pub struct Transfer {
     pub txid: String,
     pub r#type: String,
     pub unlock_time: u32,
+    pub label: String,
+    // Type of the memo: empty, text, future or arbitrary (ZIP-302)
+    pub memo_type: String,
+    // Raw 512 bytes of the memo, empty if it is unknown
+    pub memo_bytes: String,
+    // pending until the full transaction is fetched, then fetched or failed
+    pub memo_status: String,
+    // Refund address and JSON fields of the memo, only in get_transfer_by_txid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo_fields: Option<MemoFields>,
+}
+
+/// Encoding of the raw memos in transfers
+#[derive(Default, Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoEncoding {
+    #[default]
+    Hex,
+    Base64,
 }
 
+impl MemoEncoding {
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            MemoEncoding::Hex => hex::encode(bytes),
+            MemoEncoding::Base64 => BASE64_STANDARD.encode(bytes),
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct TransferFilter {
+    pub wallet: u32,
+    pub account_index: Option<u32>,
+    pub sub_accounts: Vec<u32>,
+    /// Only the notes with one of these payment ids, if not empty
+    pub payment_ids: Vec<String>,
+    pub height_range: Option<(u32, u32)>,
+}
@@ -92,5 +92,244 @@ describe('POST /request_scan', function () {
     expect(incoming[1].amount).to.equal(120000000);
     expect(incoming[1].subaddr_index.major).to.equal(0);
     expect(incoming[1].subaddr_index.minor).to.equal(0);
+    expect(incoming[0].memo_type).to.be.oneOf(["empty", "text", "future", "arbitrary"]);
+    expect(incoming[0].memo_bytes).to.match(/^[0-9a-f]{1024}$/);
+    expect(incoming[0].memo_status).to.equal("fetched");
+  });
+});
+
+describe('POST /get_transfers - filters', function () {
+  it('should treat empty subaddr_indices as all sub accounts', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_transfers')
+      .send({ "account_index": 0, "in": true, "subaddr_indices": [] });
+
+    expect(res.status).to.equal(200);
+    expect(res.body.in).to.have.lengthOf(2);
+    expect(res.body.out).to.have.lengthOf(0);
+    expect(res.body.pool).to.have.lengthOf(0);
+  });
+
+  it('should refuse the mempool and outgoing options', async function () {
+    for (const option of ["pool", "pending", "failed"]) {
+      let status;
+      try {
+        await request
+          .post('http://localhost:8000/get_transfers')
+          .send({ "account_index": 0, [option]: true });
+      } catch (err) {
+        status = err.status;
+      }
+      expect(status).to.equal(500);
+    }
+  });
+
+  it('should filter by height', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_transfers')
+      .send({ "in": true, "all_accounts": true, "filter_by_height": true, "min_height": 180 });
+
+    expect(res.status).to.equal(200);
+    expect(res.body.in).to.have.lengthOf(0);
+  });
+});
+
+describe('Payment IDs', function () {
+  it('should have no payments without a payment_id convention', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_bulk_payments')
+      .send({ "min_block_height": 0 });
+
+    expect(res.status).to.equal(200);
+    expect(res.body.payments).to.have.lengthOf(0);
+  });
+
+  it('should require a payment id', async function () {
+    let status;
+    try {
+      await request
+        .post('http://localhost:8000/get_payments')
+        .send({ "payment_id": "" });
+    } catch (err) {
+      status = err.status;
+    }
+    expect(status).to.equal(500);
+  });
+});
+
+describe('POST /get_balance', function () {
+  it('should return per sub account balances', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_balance')
+      .send({ "account_index": 1, "address_indices": [1, 2] });
+
+    expect(res.status).to.equal(200);
+    expect(res.body.per_subaddress).to.have.lengthOf(2);
+    expect(res.body.per_subaddress[0]).to.have.property('address_index', 1);
+    expect(res.body.per_subaddress[0]).to.have.property('num_unspent_outputs');
+    expect(res.body.per_subaddress[1]).to.have.property('address_index', 2);
+  });
+});
+
+describe('POST /get_accounts', function () {
+  it('should list accounts without balance', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_accounts')
+      .send({});
+
+    expect(res.status).to.equal(200);
+    const indices = res.body.subaddress_accounts.map((a) => a.account_index);
+    expect(indices).to.include.members([0, 1]);
+  });
+});
+
+describe('Labels and tags', function () {
+  it('should relabel an address and return it in transfers', async function () {
+    const res = await request
+      .post('http://localhost:8000/label_address')
+      .send({ "index": { "major": 0, "minor": 0 }, "label": "main" });
+    expect(res.status).to.equal(200);
+
+    const res2 = await request
+      .post('http://localhost:8000/get_transfers')
+      .send({ "account_index": 0, "in": true });
+    expect(res2.body.in[0]).to.have.property('label', 'main');
+  });
+
+  it('should filter accounts by tag', async function () {
+    await request
+      .post('http://localhost:8000/tag_accounts')
+      .send({ "tag": "store", "accounts": [1] });
+    await request
+      .post('http://localhost:8000/set_account_tag_description')
+      .send({ "tag": "store", "description": "Merchant store" });
+
+    const res = await request
+      .post('http://localhost:8000/get_accounts')
+      .send({ "tag": "store" });
+    expect(res.status).to.equal(200);
+    expect(res.body.subaddress_accounts).to.have.lengthOf(1);
+    expect(res.body.subaddress_accounts[0]).to.have.property('tag', 'store');
+
+    const res2 = await request
+      .post('http://localhost:8000/get_account_tags')
+      .send({});
+    expect(res2.body.account_tags[0]).to.deep.equal({ tag: "store", label: "Merchant store", accounts: [1] });
+  });
+});
+
+describe('Address lookup', function () {
+  const address2 = "uregtest1se78asch326c8czsa2wyzzfuytrvlezzjw42rest6nkqu3dzuvf4ua3lxjzf8gc5ygwca5sjdsqnpzcs087hdpgz4msfazfwfsjtr0lrln7dg0729rzp7y2acm2wrjyr5qjc8mj7x03dqh4a6frku9ue8gv3z54xgxev3dg895hepwej";
+
+  it('should list the addresses of an account', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_address')
+      .send({ "account_index": 1, "address_indices": [2] });
+
+    expect(res.status).to.equal(200);
+    expect(res.body.addresses).to.have.lengthOf(1);
+    expect(res.body.addresses[0]).to.have.property('address', address2);
+    expect(res.body.addresses[0]).to.have.property('address_index', 2);
+    expect(res.body.addresses[0].receivers.orchard).to.match(/^uregtest1[0-9a-z]+$/);
+    expect(res.body.addresses[0].receivers.sapling).to.match(/^zregtestsapling1[0-9a-z]+$/);
+  });
+
+  it('should resolve an address and its receivers', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_address')
+      .send({ "account_index": 1, "address_indices": [2] });
+    const receivers = res.body.addresses[0].receivers;
+
+    for (const address of [address2, receivers.sapling, receivers.orchard]) {
+      const res2 = await request
+        .post('http://localhost:8000/get_address_index')
+        .send({ address });
+      expect(res2.status).to.equal(200);
+      expect(res2.body.index).to.deep.equal({ major: 1, minor: 2 });
+    }
+  });
+});
+
+describe('Health', function () {
+  it('should report the sync status', async function () {
+    const res = await request
+      .get('http://localhost:8000/health');
+
+    expect(res.status).to.equal(200);
+    expect(res.body).to.have.property('synced_height');
+    expect(res.body).to.have.property('db_writable', true);
+    expect(res.body.lwd_error).to.be.null;
+  });
+
+  it('should be ready once synced', async function () {
+    await request
+      .post('http://localhost:8000/request_scan')
+      .send({});
+    const res = await request
+      .get('http://localhost:8000/ready');
+
+    expect(res.status).to.equal(200);
+    expect(res.body).to.have.property('ready', true);
+    expect(res.body.lag_blocks).to.be.at.most(10);
+  });
+});
+
+describe('Wallets', function () {
+  it('should use wallet 0 by default', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_accounts')
+      .send({});
+    const res0 = await request
+      .post('http://localhost:8000/get_accounts')
+      .send({ "wallet_id": 0 });
+
+    expect(res0.status).to.equal(200);
+    expect(res0.body).to.deep.equal(res.body);
+  });
+
+  it('should reject an unknown wallet', async function () {
+    let status;
+    try {
+      await request
+        .post('http://localhost:8000/create_account')
+        .send({ "wallet_id": 99 });
+    } catch (err) {
+      status = err.status;
+    }
+    expect(status).to.equal(500);
+  });
+
+  it('should list wallet 0', async function () {
+    const res = await request
+      .post('http://localhost:8000/get_wallets')
+      .send({});
+
+    expect(res.status).to.equal(200);
+    expect(res.body.wallets[0]).to.include({ wallet_id: 0, scan_height: null });
+    expect(res.body.wallets[0].fingerprint).to.match(/^[0-9a-f]{32}$/);
+  });
+
+  it('should not remove wallet 0', async function () {
+    let status;
+    try {
+      await request
+        .post('http://localhost:8000/remove_wallet')
+        .send({ "wallet_id": 0 });
+    } catch (err) {
+      status = err.status;
+    }
+    expect(status).to.equal(500);
+  });
+
+  it('should reject an invalid viewing key', async function () {
+    let status;
+    try {
+      await request
+        .post('http://localhost:8000/import_wallet')
+        .send({ "vk": "uviewregtest1invalid", "birth_height": 1 });
+    } catch (err) {
+      status = err.status;
+    }
+    expect(status).to.equal(500);
   });
 });